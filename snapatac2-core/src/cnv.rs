@@ -0,0 +1,317 @@
+//! # Copy Number Variation
+//!
+//! Copy numbers are inferred from tile matrices as follows:
+//!
+//! 1. Bins are aggregated into large windows, e.g., 1 Mb.
+//! 2. The expected counts of each window are derived from a reference group of
+//!    (normal) cells, which corrects for differences in accessibility and
+//!    mappability between windows. The expected counts of each profile are further
+//!    corrected for GC content, by rescaling windows in the same GC stratum
+//!    to match the observed counts.
+//! 3. Each profile, either a cell or a group of cells, is segmented with a hidden Markov model
+//!    whose states are copy numbers and whose emissions are Poisson distributions with
+//!    means proportional to the copy numbers.
+//! 4. The copy-number state of a chromosome arm is the most frequent state of its windows.
+use crate::preprocessing::{count_data::{ChromSizes, GenomeBaseIndex}, SnapData};
+
+use anyhow::{ensure, Result};
+use bed_utils::bed::BEDLike;
+use nalgebra_sparse::CsrMatrix;
+use ndarray::{Array2, ArrayView1, Axis};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use std::{collections::HashMap, ops::Range};
+
+#[derive(Debug, Clone)]
+pub struct CnvOptions {
+    /// Size of the windows into which bins are aggregated.
+    pub window_size: usize,
+    /// Copy numbers of the hidden states. Must contain the neutral state 2.
+    pub states: Vec<u8>,
+    /// Probability of switching to a different state between adjacent windows.
+    pub transition_prob: f64,
+    /// Number of GC strata used for GC correction.
+    pub gc_bins: usize,
+    pub chunk_size: usize,
+}
+
+impl Default for CnvOptions {
+    fn default() -> Self {
+        Self {
+            window_size: 1_000_000,
+            states: vec![1, 2, 3],
+            transition_prob: 1e-4,
+            gc_bins: 10,
+            chunk_size: 2000,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cnv {
+    pub windows: GenomeBaseIndex,
+    /// Names of chromosome arms, e.g., "chr1p" and "chr1q". Chromosomes without
+    /// centromere positions are not split.
+    pub arms: Vec<String>,
+    /// Copy-number states of each cell (rows) in each chromosome arm (columns).
+    pub arm_states: Array2<u8>,
+    /// Differences between the copy-number states and the neutral state of each
+    /// cell (rows) in each window (columns). Windows without data are zeros.
+    pub window_states: CsrMatrix<i8>,
+    /// Fraction of windows with altered copy numbers in each cell.
+    pub scores: Vec<f64>,
+}
+
+/// Infer copy-number states from the tile matrix stored in `.X`.
+///
+/// # Arguments
+///
+/// * `data` - Cells with tile matrices.
+/// * `reference` - Whether each cell belongs to the reference group of normal cells.
+/// * `groups` - Group labels of cells. If provided, the summed profiles of groups are
+///   segmented and cells inherit the states of their groups. Otherwise,
+///   every cell is segmented separately.
+/// * `gc` - GC content of windows, i.e., bins of the genome index with step `opts.window_size`.
+///   Windows with non-finite GC content are ignored.
+/// * `centromeres` - Centromere positions used to split chromosomes into arms.
+/// * `opts` - Parameters.
+pub fn infer_cnv<D: SnapData>(
+    data: &D,
+    reference: &[bool],
+    groups: Option<&[usize]>,
+    gc: Option<&[f64]>,
+    centromeres: &HashMap<String, u64>,
+    opts: &CnvOptions,
+) -> Result<Cnv> {
+    let n_obs = data.n_obs();
+    ensure!(reference.len() == n_obs, "the length of `reference` must equal the number of cells");
+    ensure!(reference.iter().any(|x| *x), "the reference group is empty");
+    ensure!(groups.map_or(true, |x| x.len() == n_obs), "the length of `groups` must equal the number of cells");
+    ensure!(opts.states.contains(&2), "the neutral state 2 is missing");
+    let chrom_sizes = data.read_chrom_sizes()?;
+    let windows = GenomeBaseIndex::new(&chrom_sizes).with_step(opts.window_size);
+    ensure!(gc.map_or(true, |x| x.len() == windows.len()), "the length of `gc` must equal the number of windows");
+    let chroms: Vec<Range<usize>> = windows.chrom_sizes().map(|(chr, _)| windows.get_range(chr).unwrap()).collect();
+    let arms = chromosome_arms(&windows, centromeres);
+
+    let n_groups = groups.map_or(0, |x| x.iter().max().map_or(0, |m| m + 1));
+    let mut reference_counts = vec![0.0; windows.len()];
+    let mut group_counts = Array2::<f64>::zeros((n_groups, windows.len()));
+    window_counts(data, &chrom_sizes, &windows, opts.chunk_size)?.for_each(|(mat, start)| {
+        mat.axis_iter(Axis(0)).enumerate().for_each(|(k, row)| {
+            if reference[start + k] {
+                reference_counts.iter_mut().zip(row).for_each(|(a, b)| *a += b);
+            }
+            if let Some(groups) = groups {
+                let mut x = group_counts.row_mut(groups[start + k]);
+                x += &row;
+            }
+        })
+    });
+    let total: f64 = reference_counts.iter().enumerate()
+        .filter(|(i, _)| gc.map_or(true, |g| g[*i].is_finite()))
+        .map(|(_, x)| x).sum();
+    let expected: Vec<f64> = reference_counts.iter().enumerate().map(|(i, x)|
+        if gc.map_or(true, |g| g[i].is_finite()) { x / total } else { 0.0 }
+    ).collect();
+    let strata = gc.map(|x| gc_strata(x, &expected, opts.gc_bins));
+
+    let segment = |counts: ArrayView1<f64>|
+        segment_profile(counts, &expected, strata.as_deref(), &chroms, opts);
+    let states: Vec<Vec<Option<u8>>> = if let Some(groups) = groups {
+        let group_states: Vec<_> = group_counts.axis_iter(Axis(0)).into_par_iter().map(&segment).collect();
+        groups.iter().map(|g| group_states[*g].clone()).collect()
+    } else {
+        window_counts(data, &chrom_sizes, &windows, opts.chunk_size)?.flat_map(|(mat, _)|
+            mat.axis_iter(Axis(0)).into_par_iter().map(&segment).collect::<Vec<_>>()
+        ).collect()
+    };
+
+    let mut arm_states = Array2::zeros((n_obs, arms.len()));
+    arm_states.axis_iter_mut(Axis(0)).into_par_iter().zip(states.par_iter()).for_each(|(mut row, x)|
+        row.iter_mut().zip(arms.iter()).for_each(|(s, (_, range))| *s = majority_state(&x[range.clone()]))
+    );
+    let scores = states.iter().map(|x| {
+        let (altered, valid) = x.iter().flatten().fold((0, 0), |(a, v), s| (a + (*s != 2) as usize, v + 1));
+        if valid > 0 { altered as f64 / valid as f64 } else { 0.0 }
+    }).collect();
+    let window_states: Vec<Vec<(usize, i8)>> = states.into_iter().map(|x| x.into_iter().enumerate().filter_map(|(i, s)|
+        s.filter(|s| *s != 2).map(|s| (i, s as i8 - 2))
+    ).collect()).collect();
+    let (r, c, offset, ind, values) = anndata::data::utils::to_csr_data(window_states, windows.len());
+    Ok(Cnv {
+        windows,
+        arms: arms.into_iter().map(|x| x.0).collect(),
+        arm_states,
+        window_states: CsrMatrix::try_from_csr_data(r, c, offset, ind, values).unwrap(),
+        scores,
+    })
+}
+
+/// Aggregate the tile matrix into dense window counts.
+fn window_counts<'a, D: SnapData>(
+    data: &'a D,
+    chrom_sizes: &ChromSizes,
+    windows: &'a GenomeBaseIndex,
+    chunk_size: usize,
+) -> Result<impl Iterator<Item = (Array2<f64>, usize)> + 'a> {
+    let values = data.read_chrom_values(chunk_size)?;
+    let bin_to_window: Vec<Option<usize>> = values.regions.iter().map(|x|
+        chrom_sizes.get(x.chrom()).filter(|size| x.start() < *size)
+            .map(|_| windows.get_position_rev(x.chrom(), x.start()))
+    ).collect();
+    Ok(values.iter.map(move |(mat, start, end)| {
+        let mut counts = Array2::zeros((end - start, windows.len()));
+        counts.axis_iter_mut(Axis(0)).into_par_iter().zip(mat.row_iter().collect::<Vec<_>>()).for_each(|(mut out, row)|
+            row.col_indices().iter().zip(row.values()).for_each(|(j, v)|
+                if let Some(w) = bin_to_window[*j] {
+                    out[w] += *v as f64;
+                }
+            )
+        );
+        (counts, start)
+    }))
+}
+
+/// Split chromosomes into arms at centromeres.
+fn chromosome_arms(windows: &GenomeBaseIndex, centromeres: &HashMap<String, u64>) -> Vec<(String, Range<usize>)> {
+    windows.chrom_sizes().flat_map(|(chr, _)| {
+        let range = windows.get_range(chr).unwrap();
+        if let Some(pos) = centromeres.get(chr) {
+            let split = range.start + ((*pos as f64 / windows.step as f64).round() as usize).min(range.len());
+            [(format!("{}p", chr), range.start..split), (format!("{}q", chr), split..range.end)]
+                .into_iter().filter(|x| !x.1.is_empty()).collect()
+        } else {
+            vec![(chr.clone(), range)]
+        }
+    }).collect()
+}
+
+/// Assign windows with positive expected counts to GC strata of equal sizes.
+fn gc_strata(gc: &[f64], expected: &[f64], n: usize) -> Vec<usize> {
+    let mut valid: Vec<usize> = (0..gc.len()).filter(|i| expected[*i] > 0.0).collect();
+    valid.sort_by(|a, b| gc[*a].total_cmp(&gc[*b]));
+    let mut strata = vec![0; gc.len()];
+    let size = num::integer::div_ceil(valid.len(), n.max(1)).max(1);
+    valid.into_iter().enumerate().for_each(|(rank, i)| strata[i] = rank / size);
+    strata
+}
+
+/// Segment a profile of window counts into copy-number states.
+/// Windows with zero expected counts have `None` states.
+fn segment_profile(
+    counts: ArrayView1<f64>,
+    expected: &[f64],
+    strata: Option<&[usize]>,
+    chroms: &[Range<usize>],
+    opts: &CnvOptions,
+) -> Vec<Option<u8>> {
+    let valid = |i: usize| expected[i] > 0.0;
+    let total: f64 = (0..counts.len()).filter(|i| valid(*i)).map(|i| counts[i]).sum();
+    let mut lambda: Vec<f64> = expected.iter().map(|x| x * total).collect();
+    if let Some(strata) = strata {
+        let n = strata.iter().max().map_or(0, |x| x + 1);
+        let mut observed = vec![0.0; n];
+        let mut exp = vec![0.0; n];
+        (0..counts.len()).filter(|i| valid(*i)).for_each(|i| {
+            observed[strata[i]] += counts[i];
+            exp[strata[i]] += lambda[i];
+        });
+        lambda.iter_mut().enumerate().filter(|(i, _)| valid(*i)).for_each(|(i, x)| {
+            let s = strata[i];
+            if observed[s] > 0.0 && exp[s] > 0.0 {
+                *x *= observed[s] / exp[s];
+            }
+        });
+        let sum: f64 = lambda.iter().sum();
+        if sum > 0.0 {
+            lambda.iter_mut().for_each(|x| *x *= total / sum);
+        }
+    }
+
+    let mut states = vec![None; counts.len()];
+    if total == 0.0 {
+        (0..counts.len()).filter(|i| valid(*i)).for_each(|i| states[i] = Some(2));
+        return states;
+    }
+    chroms.iter().for_each(|range| {
+        let idx: Vec<usize> = range.clone().filter(|i| valid(*i)).collect();
+        let emission: Vec<Vec<f64>> = idx.iter().map(|i| opts.states.iter().map(|cn| {
+            let mu = lambda[*i] * *cn as f64 / 2.0;
+            counts[*i] * mu.ln() - mu
+        }).collect()).collect();
+        let neutral = opts.states.iter().position(|x| *x == 2).unwrap();
+        viterbi(&emission, neutral, opts.transition_prob).into_iter().zip(idx).for_each(|(s, i)|
+            states[i] = Some(opts.states[s])
+        );
+    });
+    states
+}
+
+/// Most likely state sequence of an HMM in which all states have the same probability
+/// of switching to any other state. The sequence starts from the `init` state.
+fn viterbi(emission: &[Vec<f64>], init: usize, transition_prob: f64) -> Vec<usize> {
+    if emission.is_empty() {
+        return Vec::new();
+    }
+    let n = emission[0].len();
+    let stay = (1.0 - transition_prob).ln();
+    let switch = (transition_prob / (n - 1).max(1) as f64).ln();
+    let mut score: Vec<f64> = (0..n).map(|s| if s == init { stay } else { switch } + emission[0][s]).collect();
+    let mut backtrack = vec![vec![0; n]];
+    emission[1..].iter().for_each(|e| {
+        let best = (0..n).max_by(|a, b| score[*a].total_cmp(&score[*b])).unwrap();
+        let mut ptr = vec![0; n];
+        score = (0..n).map(|s| {
+            let (prev, x) = if s == best || score[s] + stay >= score[best] + switch {
+                (s, score[s] + stay)
+            } else {
+                (best, score[best] + switch)
+            };
+            ptr[s] = prev;
+            x + e[s]
+        }).collect();
+        backtrack.push(ptr);
+    });
+    let mut s = (0..n).max_by(|a, b| score[*a].total_cmp(&score[*b])).unwrap();
+    let mut path = vec![0; emission.len()];
+    (0..emission.len()).rev().for_each(|t| {
+        path[t] = s;
+        s = backtrack[t][s];
+    });
+    path
+}
+
+/// The most frequent state, with ties resolved in favour of the neutral state 2.
+fn majority_state(states: &[Option<u8>]) -> u8 {
+    let mut counts: HashMap<u8, usize> = HashMap::new();
+    states.iter().flatten().for_each(|s| *counts.entry(*s).or_insert(0) += 1);
+    let neutral = counts.get(&2).copied().unwrap_or(0);
+    counts.into_iter().filter(|x| x.1 > neutral).max_by_key(|x| (x.1, x.0)).map_or(2, |x| x.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_profile() {
+        // Two chromosomes of 100 windows. The second half of the first chromosome is
+        // gained and the second chromosome is lost.
+        let n = 200;
+        let expected: Vec<f64> = (0..n).map(|i| if i == 10 { 0.0 } else { (1 + i % 3) as f64 }).collect();
+        let total: f64 = expected.iter().sum();
+        let expected: Vec<f64> = expected.into_iter().map(|x| x / total).collect();
+        let counts = ndarray::Array1::from_shape_fn(n, |i| {
+            let cn = if i < 50 { 2.0 } else if i < 100 { 3.0 } else { 1.0 };
+            (expected[i] * 20000.0 * cn / 2.0).round()
+        });
+        let chroms = [0..100, 100..200];
+        let states = segment_profile(counts.view(), &expected, None, &chroms, &CnvOptions::default());
+        assert_eq!(states[10], None);
+        assert!(states[..50].iter().flatten().all(|x| *x == 2));
+        assert!(states[50..100].iter().all(|x| *x == Some(3)));
+        assert!(states[100..].iter().all(|x| *x == Some(1)));
+        assert_eq!(majority_state(&states[..90]), 2);
+        assert_eq!(majority_state(&states[50..]), 1);
+    }
+}
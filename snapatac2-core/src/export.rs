@@ -1,5 +1,10 @@
-use crate::{preprocessing::{count_data::{SnapData, GenomeCoverage, CoverageType, ChromSizes}, Fragment}, utils::open_file_for_write};
+use crate::{
+    preprocessing::{count_data::{SnapData, GenomeCoverage, CoverageType, ChromSizes}, Fragment},
+    hic::{aggregate_contacts, balance, call_compartments, call_loops, write_bedpe, LoopOptions, write_cooler, init_mcool, BalanceMethod, LowCoverageFilter},
+    utils::open_file_for_write,
+};
 
+use anndata::data::{utils::to_csr_data, DataFrameIndex};
 use anyhow::{Context, Result, ensure};
 use itertools::Itertools;
 use log::info;
@@ -11,6 +16,7 @@ use rayon::iter::{ParallelBridge, ParallelIterator};
 use bed_utils::bed::{BEDLike, BedGraph, merge_sorted_bed_with, GenomicRange, tree::{GenomeRegions, SparseBinnedCoverage}};
 use bigtools::{bbi::bigwigwrite::BigWigWrite, bed::bedparser::BedParser};
 use futures::executor::ThreadPool;
+use nalgebra_sparse::CsrMatrix;
 use indicatif::{ProgressIterator, style::ProgressStyle};
 
 impl<T> Exporter for T where T: SnapData {}
@@ -26,6 +32,7 @@ pub trait Exporter: SnapData {
         suffix:&str,
         compression: Option<&str>,
         compression_level: Option<u32>,
+        fragment_size_range: Option<(u64, u64)>,
     ) -> Result<HashMap<String, PathBuf>> {
         ensure!(self.n_obs() == group_by.len(), "lengths differ");
         let mut groups: HashSet<&str> = group_by.iter().map(|x| *x).unique().collect();
@@ -41,7 +48,11 @@ pub trait Exporter: SnapData {
         }).collect::<Result<HashMap<_, _>>>()?;
 
         let style = ProgressStyle::with_template("[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})")?;
-        self.get_count_iter(1000)?.into_raw()
+        let mut coverage = self.get_count_iter(1000)?;
+        if let Some((min, max)) = fragment_size_range {
+            coverage = coverage.with_fragment_size_range(min, max)?;
+        }
+        coverage.into_raw()
             .progress_with_style(style)
             .map(move |(vals, start, _)| {
                 let mut ordered = HashMap::new();
@@ -80,21 +91,255 @@ pub trait Exporter: SnapData {
         dir: P,
         prefix: &str,
         suffix:&str,
+        fragment_size_range: Option<(u64, u64)>,
     ) -> Result<HashMap<String, PathBuf>> {
-        export_insertions_as_bigwig(
-            self.get_count_iter(500)?, group_by, selections, resolution, dir, prefix, suffix,
-        )
+        let mut coverage = self.get_count_iter(500)?;
+        if let Some((min, max)) = fragment_size_range {
+            coverage = coverage.with_fragment_size_range(min, max)?;
+        }
+        export_insertions_as_bigwig(coverage, group_by, selections, resolution, dir, prefix, suffix)
+    }
+
+    /// Export pseudobulk contact maps as cooler files. If more than one resolution
+    /// is provided, a multi-resolution cooler (`.mcool`) is written for each group.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_by` - Group labels of cells.
+    /// * `selections` - Groups to export. If `None`, all groups are exported.
+    /// * `resolutions` - Bin sizes.
+    /// * `balance_method` - If provided, balancing weights are computed and stored as
+    ///   `bins/weight`, using the default `LowCoverageFilter`.
+    fn export_cool<P: AsRef<Path>>(
+        &self,
+        group_by: &Vec<&str>,
+        selections: Option<HashSet<&str>>,
+        resolutions: &[usize],
+        balance_method: Option<BalanceMethod>,
+        dir: P,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<HashMap<String, PathBuf>> {
+        ensure!(!resolutions.is_empty(), "at least one resolution must be provided");
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
+
+        let mut files: HashMap<String, (PathBuf, hdf5::File)> = HashMap::new();
+        for resolution in resolutions {
+            info!("Aggregate contacts at resolution {}...", resolution);
+            let contacts = self.contact_count_iter(500)?.with_resolution(*resolution);
+            let matrices = aggregate_contacts(contacts, group_by, selections.clone())?;
+            for (grp, mat) in matrices {
+                let (_, file) = match files.entry(grp.clone()) {
+                    std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        let filename = dir.as_ref().join(
+                            prefix.to_string() + grp.replace("/", "+").as_str() + suffix
+                        );
+                        let file = hdf5::File::create(&filename)
+                            .with_context(|| format!("cannot create file: {}", filename.display()))?;
+                        if resolutions.len() > 1 {
+                            init_mcool(&file)?;
+                        }
+                        e.insert((filename, file))
+                    },
+                };
+                let weight = balance_method.map(|method|
+                    balance(&mat.matrix, method, &LowCoverageFilter::default(), 1e-5, 200)
+                ).transpose()?.map(|x| x.bias);
+                if resolutions.len() > 1 {
+                    let group = file.group("resolutions")?.create_group(&resolution.to_string())?;
+                    write_cooler(&group, &mat, weight.as_deref())?;
+                } else {
+                    write_cooler(file, &mat, weight.as_deref())?;
+                }
+            }
+        }
+        Ok(files.into_iter().map(|(k, (v, _))| (k, v)).collect())
+    }
+
+    /// Call A/B compartments from pseudobulk contact maps and export the compartment
+    /// scores as bedGraph or bigwig files, depending on whether `suffix` ends
+    /// with ".bw" or ".bigwig".
+    ///
+    /// # Arguments
+    ///
+    /// * `group_by` - Group labels of cells.
+    /// * `selections` - Groups to export. If `None`, all groups are exported.
+    /// * `resolution` - Bin size.
+    /// * `balance_method` - If provided, matrices are balanced before calling compartments.
+    /// * `track` - Reference track used to orient compartment scores, binned at `resolution`.
+    ///   See `hic::gc_content` and `hic::coverage_track`.
+    fn export_compartments<P: AsRef<Path>>(
+        &self,
+        group_by: &Vec<&str>,
+        selections: Option<HashSet<&str>>,
+        resolution: usize,
+        balance_method: Option<BalanceMethod>,
+        track: Option<&[f64]>,
+        dir: P,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<HashMap<String, PathBuf>> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
+        let contacts = self.contact_count_iter(500)?.with_resolution(resolution);
+        if let Some(track) = track {
+            ensure!(track.len() == contacts.get_gindex().len(), "the length of track does not match the number of bins");
+        }
+        let matrices = aggregate_contacts(contacts, group_by, selections)?;
+
+        info!("Calling compartments for {} groups...", matrices.len());
+        let bigwig = suffix.ends_with(".bw") || suffix.ends_with(".bigwig");
+        matrices.into_iter().map(|(grp, mat)| {
+            let filename = dir.as_ref().join(
+                prefix.to_string() + grp.replace("/", "+").as_str() + suffix
+            );
+            let weight = balance_method.map(|method|
+                balance(&mat.matrix, method, &LowCoverageFilter::default(), 1e-5, 200)
+            ).transpose()?.map(|x| x.bias);
+            let compartments = call_compartments(&mat, weight.as_deref(), track, 2);
+            if bigwig {
+                compartments.write_bigwig(&filename)?;
+            } else {
+                compartments.write_bedgraph(&filename)?;
+            }
+            Ok((grp, filename))
+        }).collect()
+    }
+
+    /// Call chromatin loops from pseudobulk contact maps and export them as BEDPE files.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_by` - Group labels of cells.
+    /// * `selections` - Groups to export. If `None`, all groups are exported.
+    /// * `resolution` - Bin size.
+    /// * `balance_method` - If provided, matrices are balanced before calling loops.
+    /// * `opts` - Loop calling parameters.
+    fn export_loops<P: AsRef<Path>>(
+        &self,
+        group_by: &Vec<&str>,
+        selections: Option<HashSet<&str>>,
+        resolution: usize,
+        balance_method: Option<BalanceMethod>,
+        opts: &LoopOptions,
+        dir: P,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<HashMap<String, PathBuf>> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("cannot create directory: {}", dir.as_ref().display()))?;
+        let contacts = self.contact_count_iter(500)?.with_resolution(resolution);
+        let matrices = aggregate_contacts(contacts, group_by, selections)?;
+
+        info!("Calling loops for {} groups...", matrices.len());
+        matrices.into_iter().map(|(grp, mat)| {
+            let filename = dir.as_ref().join(
+                prefix.to_string() + grp.replace("/", "+").as_str() + suffix
+            );
+            let weight = balance_method.map(|method|
+                balance(&mat.matrix, method, &LowCoverageFilter::default(), 1e-5, 200)
+            ).transpose()?.map(|x| x.bias);
+            let loops = call_loops(&mat, weight.as_deref(), opts);
+            write_bedpe(&loops, &mat.index, &filename)?;
+            Ok((grp, filename))
+        }).collect()
     }
 
+    /// Sum the counts of cells in each group, i.e., compute pseudobulk profiles,
+    /// streaming the data once.
+    ///
+    /// # Arguments
+    ///
+    /// * `group_by` - Group labels of cells.
+    /// * `replicates` - If provided, cells are further split by replicate labels,
+    ///   resulting in one profile for each pair of group and replicate.
+    /// * `selections` - Groups to aggregate. If `None`, all groups are aggregated.
+    /// * `source` - The counts to aggregate.
     fn get_counts(
         &self,
         group_by: &Vec<&str>,
+        replicates: Option<&Vec<&str>>,
         selections: Option<HashSet<&str>>,
-    ) -> Result<()> {
-        todo!()
+        source: CountSource,
+    ) -> Result<Pseudobulk> {
+        ensure!(self.n_obs() == group_by.len(), "lengths differ");
+        if let Some(reps) = replicates {
+            ensure!(self.n_obs() == reps.len(), "the length of replicates does not match the number of cells");
+        }
+        let keys: Vec<Option<(&str, Option<&str>)>> = group_by.iter().enumerate().map(|(i, grp)|
+            if selections.as_ref().map_or(true, |x| x.contains(grp)) {
+                Some((*grp, replicates.map(|x| x[i])))
+            } else {
+                None
+            }
+        ).collect();
+        let ordered: Vec<(&str, Option<&str>)> = keys.iter().flatten().copied().unique().sorted().collect();
+        let key_index: HashMap<_, usize> = ordered.iter().enumerate().map(|(i, x)| (*x, i)).collect();
+        let rows: Vec<Option<usize>> = keys.iter().map(|x| x.map(|k| key_index[&k])).collect();
+
+        let mut num_cells = vec![0; ordered.len()];
+        rows.iter().flatten().for_each(|i| num_cells[*i] += 1);
+        let mut sums: Vec<HashMap<usize, f64>> = vec![HashMap::new(); ordered.len()];
+        let style = ProgressStyle::with_template("[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})")?;
+        let features = match source {
+            CountSource::X => {
+                self.x().iter(500).progress_with_style(style).for_each(|(mat, start, _): (CsrMatrix<f64>, _, _)|
+                    add_rows(&mut sums, &rows[start..], &mat)
+                );
+                self.var_names().into_vec()
+            },
+            CountSource::Fragments(resolution) => {
+                let coverage = self.get_count_iter(500)?.with_resolution(resolution);
+                let index: DataFrameIndex = coverage.get_gindex().to_index().into();
+                coverage.into_values::<u32>().progress_with_style(style).for_each(|(mat, start, _)|
+                    add_rows(&mut sums, &rows[start..], &mat)
+                );
+                index.into_vec()
+            },
+        };
+
+        let counts = sums.into_iter().map(|x| x.into_iter().sorted_by_key(|x| x.0).collect()).collect();
+        let (r, c, offset, ind, data) = to_csr_data(counts, features.len());
+        Ok(Pseudobulk {
+            groups: ordered.iter().map(|x| x.0.to_string()).collect(),
+            replicates: replicates.map(|_| ordered.iter().map(|x| x.1.unwrap().to_string()).collect()),
+            num_cells,
+            features,
+            counts: CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap(),
+        })
     }
 }
 
+/// The counts aggregated by `Exporter::get_counts`.
+#[derive(Debug, Clone, Copy)]
+pub enum CountSource {
+    /// The `.X` matrix.
+    X,
+    /// Insertion counts computed from fragments, binned at the given resolution.
+    Fragments(usize),
+}
+
+/// Pseudobulk profiles, one row for each group or each pair of group and replicate.
+pub struct Pseudobulk {
+    pub groups: Vec<String>,
+    pub replicates: Option<Vec<String>>,
+    /// Number of cells in each row.
+    pub num_cells: Vec<usize>,
+    pub features: Vec<String>,
+    pub counts: CsrMatrix<f64>,
+}
+
+fn add_rows<T: Copy + Into<f64>>(sums: &mut [HashMap<usize, f64>], rows: &[Option<usize>], mat: &CsrMatrix<T>) {
+    mat.row_iter().zip(rows).for_each(|(row, i)| if let Some(i) = i {
+        let sum = &mut sums[*i];
+        row.col_indices().iter().zip(row.values()).for_each(|(j, v)|
+            *sum.entry(*j).or_insert(0.0) += (*v).into()
+        );
+    });
+}
+
 /// Export TN5 insertions as bigwig files
 /// 
 /// # Arguments
@@ -195,7 +440,7 @@ where
 }
 
 /// Create a bigwig file from BedGraph records.
-fn create_bigwig_from_bedgraph<P: AsRef<Path>>(
+pub(crate) fn create_bigwig_from_bedgraph<P: AsRef<Path>>(
     mut bedgraph: Vec<BedGraph<f32>>,
     chrom_sizes: &HashMap<String, u32>,
     filename: P,
@@ -227,4 +472,47 @@ fn create_bigwig_from_bedgraph<P: AsRef<Path>>(
         ThreadPool::new().unwrap(),
     ).unwrap();
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anndata::{AnnData, AnnDataOp};
+    use anndata_hdf5::H5;
+
+    fn to_rows(mat: &CsrMatrix<f64>) -> Vec<Vec<(usize, f64)>> {
+        mat.row_iter().map(|row|
+            row.col_indices().iter().copied().zip(row.values().iter().copied()).collect()
+        ).collect()
+    }
+
+    #[test]
+    fn test_get_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let adata = AnnData::<H5>::new(dir.path().join("data.h5ad")).unwrap();
+        // Rows: [1, 0, 2], [0, 3, 0], [4, 0, 0], [1, 1, 0].
+        let x = CsrMatrix::try_from_csr_data(
+            4, 3, vec![0, 2, 3, 4, 6], vec![0, 2, 1, 0, 0, 1], vec![1.0, 2.0, 3.0, 4.0, 1.0, 1.0],
+        ).unwrap();
+        adata.set_x(x).unwrap();
+        adata.set_var_names(vec!["f1".to_string(), "f2".to_string(), "f3".to_string()].into()).unwrap();
+
+        let group_by = vec!["b", "a", "b", "a"];
+        let replicates = vec!["r1", "r1", "r2", "r1"];
+        let res = adata.get_counts(&group_by, Some(&replicates), None, CountSource::X).unwrap();
+        assert_eq!(res.groups, vec!["a", "b", "b"]);
+        assert_eq!(res.replicates, Some(vec!["r1".to_string(), "r1".to_string(), "r2".to_string()]));
+        assert_eq!(res.num_cells, vec![2, 1, 1]);
+        assert_eq!(res.features, vec!["f1", "f2", "f3"]);
+        assert_eq!(
+            to_rows(&res.counts),
+            vec![vec![(0, 1.0), (1, 4.0)], vec![(0, 1.0), (2, 2.0)], vec![(0, 4.0)]],
+        );
+
+        let res = adata.get_counts(&group_by, None, Some(HashSet::from(["a"])), CountSource::X).unwrap();
+        assert_eq!(res.groups, vec!["a"]);
+        assert!(res.replicates.is_none());
+        assert_eq!(res.num_cells, vec![2]);
+        assert_eq!(to_rows(&res.counts), vec![vec![(0, 1.0), (1, 4.0)]]);
+    }
+}
@@ -0,0 +1,102 @@
+//! # Hi-C Analysis
+//!
+//! Analyses of pseudobulk contact matrices aggregated from single-cell contact maps.
+//! `ContactMatrix` stores a symmetric bin-by-bin matrix at a fixed resolution,
+//! where bins are indexed by a binned `GenomeBaseIndex`.
+mod balance;
+mod compartment;
+mod cooler;
+mod impute;
+mod loops;
+
+pub use balance::{balance, BalanceMethod, Balanced, LowCoverageFilter};
+pub use compartment::{call_compartments, coverage_track, gc_content, Compartments};
+pub use cooler::{write_cooler, init_mcool};
+pub use impute::{impute_contacts, ImputeOptions};
+pub use loops::{call_loops, write_bedpe, Loop, LoopOptions};
+
+use crate::preprocessing::count_data::{ContactMap, GenomeBaseIndex};
+
+use anyhow::{ensure, Result};
+use indicatif::{ProgressIterator, style::ProgressStyle};
+use itertools::Itertools;
+use nalgebra_sparse::{CooMatrix, CsrMatrix};
+use std::collections::{HashMap, HashSet};
+
+/// A symmetric pseudobulk contact matrix.
+#[derive(Debug, Clone)]
+pub struct ContactMatrix {
+    pub index: GenomeBaseIndex,
+    pub matrix: CsrMatrix<f64>,
+}
+
+impl ContactMatrix {
+    /// Build a symmetric matrix from contacts `(i, j, count)`. Contacts are
+    /// canonicalized so that `(i, j)` and `(j, i)` are counted together.
+    pub fn from_contacts<I>(index: GenomeBaseIndex, contacts: I) -> Self
+    where
+        I: IntoIterator<Item = (usize, usize, f64)>,
+    {
+        let n = index.len();
+        let mut coo = CooMatrix::new(n, n);
+        contacts.into_iter().for_each(|(i, j, v)| {
+            coo.push(i, j, v);
+            if i != j {
+                coo.push(j, i, v);
+            }
+        });
+        Self { index, matrix: CsrMatrix::from(&coo) }
+    }
+
+    pub fn n_bins(&self) -> usize {
+        self.matrix.nrows()
+    }
+}
+
+/// Aggregate single-cell contact maps into pseudobulk contact matrices, one for each group.
+///
+/// # Arguments
+///
+/// * `contacts` - Single-cell contact maps. The resolution of the output is
+///   determined by `ContactMap::with_resolution`.
+/// * `group_by` - Group labels of cells.
+/// * `selections` - Groups to keep. If `None`, all groups are kept.
+pub fn aggregate_contacts<I>(
+    contacts: ContactMap<I>,
+    group_by: &Vec<&str>,
+    selections: Option<HashSet<&str>>,
+) -> Result<HashMap<String, ContactMatrix>>
+where
+    I: ExactSizeIterator<Item = (CsrMatrix<u8>, usize, usize)>,
+{
+    let index = contacts.get_gindex();
+    let n = index.len();
+    let mut groups: HashSet<&str> = group_by.iter().map(|x| *x).unique().collect();
+    if let Some(select) = selections { groups.retain(|x| select.contains(x)); }
+    let mut counts: HashMap<&str, HashMap<(usize, usize), f64>> =
+        groups.into_iter().map(|grp| (grp, HashMap::new())).collect();
+
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})"
+    ).unwrap();
+    contacts.into_values::<f64>().progress_with_style(style).try_for_each(|(csr, start, end)| {
+        ensure!(end <= group_by.len(), "the length of group_by is smaller than the number of cells");
+        (start..end).zip(csr.row_iter()).for_each(|(row_idx, row)|
+            if let Some(count) = counts.get_mut(group_by[row_idx]) {
+                row.col_indices().iter().zip(row.values()).for_each(|(idx, val)| {
+                    let (i, j) = (idx / n, idx % n);
+                    let key = if i <= j { (i, j) } else { (j, i) };
+                    *count.entry(key).or_insert(0.0) += *val;
+                });
+            }
+        );
+        Ok(())
+    })?;
+
+    Ok(counts.into_iter().map(|(grp, count)| {
+        let mat = ContactMatrix::from_contacts(
+            index.clone(), count.into_iter().map(|((i, j), v)| (i, j, v))
+        );
+        (grp.to_string(), mat)
+    }).collect())
+}
@@ -0,0 +1,283 @@
+//! # Matrix Balancing
+//!
+//! Iterative correction (ICE; Imakaev et al., 2012) and Knight-Ruiz balancing
+//! (KR; Knight and Ruiz, 2013) of symmetric contact matrices. Both methods find a
+//! bias vector `b` such that the balanced matrix `b_i * m_ij * b_j` has equal
+//! marginals. Biases are scaled so that the marginals of the balanced matrix are 1.
+//! Following the convention of cooler, masked bins have `NaN` biases.
+use crate::utils::median;
+
+use anyhow::{bail, ensure, Result};
+use nalgebra_sparse::{CooMatrix, CsrMatrix};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceMethod {
+    ICE,
+    KR,
+}
+
+/// Filters used to mask low-coverage bins before balancing.
+#[derive(Debug, Clone)]
+pub struct LowCoverageFilter {
+    /// Number of diagonals to ignore. `1` ignores the main diagonal, `2` also ignores
+    /// contacts between adjacent bins, etc.
+    pub ignore_diags: usize,
+    /// Minimum number of non-zero entries of a bin.
+    pub min_nnz: usize,
+    /// Minimum total count of a bin.
+    pub min_count: f64,
+    /// Mask bins whose log-marginals are more than `mad_max` median absolute deviations
+    /// below the median.
+    pub mad_max: Option<f64>,
+}
+
+impl Default for LowCoverageFilter {
+    fn default() -> Self {
+        Self { ignore_diags: 2, min_nnz: 10, min_count: 0.0, mad_max: Some(5.0) }
+    }
+}
+
+impl LowCoverageFilter {
+    /// Return `true` for bins that pass the filters.
+    pub fn mask(&self, mat: &CsrMatrix<f64>) -> Vec<bool> {
+        let marg: Vec<f64> = mat.row_iter().map(|row| row.values().iter().sum()).collect();
+        let mut mask: Vec<bool> = mat.row_iter().zip(marg.iter()).map(|(row, m)|
+            *m > 0.0 && *m >= self.min_count && row.nnz() >= self.min_nnz
+        ).collect();
+        if let Some(mad_max) = self.mad_max {
+            let log_marg: Vec<f64> = marg.iter().zip(mask.iter())
+                .filter(|(_, k)| **k).map(|(m, _)| m.ln()).collect();
+            if !log_marg.is_empty() {
+                let med = median(log_marg.clone());
+                let mad = median(log_marg.iter().map(|x| (x - med).abs()).collect());
+                mask.iter_mut().zip(marg.iter()).for_each(|(k, m)|
+                    if *k && m.ln() < med - mad_max * mad { *k = false; }
+                );
+            }
+        }
+        mask
+    }
+
+    fn remove_diags(&self, mat: &CsrMatrix<f64>) -> CsrMatrix<f64> {
+        filter_entries(mat, |i, j| i.abs_diff(j) >= self.ignore_diags)
+    }
+}
+
+/// The result of matrix balancing.
+#[derive(Debug, Clone)]
+pub struct Balanced {
+    /// Bias vector. Masked bins have `NaN` biases.
+    pub bias: Vec<f64>,
+    /// The balanced matrix, `bias_i * m_ij * bias_j`. Masked bins and ignored diagonals are removed.
+    pub matrix: CsrMatrix<f64>,
+    pub converged: bool,
+    pub n_iter: usize,
+}
+
+/// Balance a symmetric contact matrix.
+///
+/// # Arguments
+///
+/// * `mat` - A symmetric contact matrix.
+/// * `method` - The balancing algorithm.
+/// * `filter` - Filters used to mask low-coverage bins.
+/// * `tol` - Convergence tolerance. For ICE, this is the variance of the marginals.
+///   For KR, this is the norm of the residual.
+/// * `max_iter` - Maximum number of iterations.
+pub fn balance(
+    mat: &CsrMatrix<f64>,
+    method: BalanceMethod,
+    filter: &LowCoverageFilter,
+    tol: f64,
+    max_iter: usize,
+) -> Result<Balanced> {
+    ensure!(mat.nrows() == mat.ncols(), "the contact matrix must be square");
+    let mat = filter.remove_diags(mat);
+    let mask = filter.mask(&mat);
+    let keep: Vec<usize> = mask.iter().enumerate().filter(|(_, k)| **k).map(|(i, _)| i).collect();
+    if keep.is_empty() {
+        bail!("all bins are masked");
+    }
+    let mut new_index = vec![None; mat.nrows()];
+    keep.iter().enumerate().for_each(|(k, i)| new_index[*i] = Some(k));
+    let sub = submatrix(&mat, &new_index, keep.len());
+
+    let (mut b, converged, n_iter) = match method {
+        BalanceMethod::ICE => ice(&sub, tol, max_iter),
+        BalanceMethod::KR => knight_ruiz(&sub, tol, max_iter),
+    };
+    let marg = marginals(&sub, &b);
+    let nonzero: Vec<f64> = marg.into_iter().filter(|x| *x > 0.0).collect();
+    if !nonzero.is_empty() {
+        let scale = (nonzero.iter().sum::<f64>() / nonzero.len() as f64).sqrt();
+        b.iter_mut().for_each(|x| *x /= scale);
+    }
+
+    let mut bias = vec![f64::NAN; mat.nrows()];
+    keep.iter().zip(b).for_each(|(i, x)| bias[*i] = x);
+    let matrix = {
+        let mut coo = CooMatrix::new(mat.nrows(), mat.ncols());
+        mat.triplet_iter().for_each(|(i, j, v)| if mask[i] && mask[j] {
+            coo.push(i, j, v * bias[i] * bias[j]);
+        });
+        CsrMatrix::from(&coo)
+    };
+    Ok(Balanced { bias, matrix, converged, n_iter })
+}
+
+/// Iterative correction. Return the bias vector, whether the algorithm converged,
+/// and the number of iterations.
+fn ice(mat: &CsrMatrix<f64>, tol: f64, max_iter: usize) -> (Vec<f64>, bool, usize) {
+    let mut b = vec![1.0; mat.nrows()];
+    for iter in 1..=max_iter {
+        let marg = marginals(mat, &b);
+        let nonzero: Vec<f64> = marg.iter().copied().filter(|x| *x > 0.0).collect();
+        let mean = nonzero.iter().sum::<f64>() / nonzero.len() as f64;
+        b.iter_mut().zip(marg.iter()).for_each(|(x, m)| if *m > 0.0 { *x /= m / mean; });
+        let var = nonzero.iter().map(|m| (m / mean - 1.0).powi(2)).sum::<f64>() / nonzero.len() as f64;
+        if var < tol {
+            return (b, true, iter);
+        }
+    }
+    (b, false, max_iter)
+}
+
+/// Knight-Ruiz balancing using the inexact Newton method ("BNEWT" in Knight and Ruiz, 2013).
+/// Return the bias vector, whether the algorithm converged, and the number of outer iterations.
+fn knight_ruiz(mat: &CsrMatrix<f64>, tol: f64, max_iter: usize) -> (Vec<f64>, bool, usize) {
+    const DELTA_MIN: f64 = 0.1;
+    const DELTA_MAX: f64 = 3.0;
+    const G: f64 = 0.9;
+    const ETA_MAX: f64 = 0.1;
+
+    let n = mat.nrows();
+    let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+    let rt = tol * tol;
+    let stop_tol = tol * 0.5;
+    let mut eta = ETA_MAX;
+
+    let mut x = vec![1.0; n];
+    let mut v: Vec<f64> = x.iter().zip(matvec(mat, &x)).map(|(a, b)| a * b).collect();
+    let mut rk: Vec<f64> = v.iter().map(|a| 1.0 - a).collect();
+    let mut rho_km1 = dot(&rk, &rk);
+    let mut rout = rho_km1;
+    let mut rold = rout;
+
+    let mut iter = 0;
+    while rout > rt {
+        if iter >= max_iter {
+            return (x, false, iter);
+        }
+        iter += 1;
+        let mut k = 0;
+        let mut y = vec![1.0; n];
+        let mut z: Vec<f64> = Vec::new();
+        let mut p: Vec<f64> = Vec::new();
+        let mut rho_km2 = 0.0;
+        let inner_tol = (eta * eta * rout).max(rt);
+        while rho_km1 > inner_tol {
+            k += 1;
+            if k == 1 {
+                z = rk.iter().zip(v.iter()).map(|(a, b)| a / b).collect();
+                p = z.clone();
+                rho_km1 = dot(&rk, &z);
+            } else {
+                let beta = rho_km1 / rho_km2;
+                p.iter_mut().zip(z.iter()).for_each(|(a, b)| *a = b + beta * *a);
+            }
+            let xp: Vec<f64> = x.iter().zip(p.iter()).map(|(a, b)| a * b).collect();
+            let w: Vec<f64> = matvec(mat, &xp).into_iter().zip(x.iter()).zip(v.iter().zip(p.iter()))
+                .map(|((a, xi), (vi, pi))| xi * a + vi * pi).collect();
+            let alpha = rho_km1 / dot(&p, &w);
+            let ap: Vec<f64> = p.iter().map(|a| alpha * a).collect();
+            let y_new: Vec<f64> = y.iter().zip(ap.iter()).map(|(a, b)| a + b).collect();
+            if y_new.iter().any(|a| *a <= DELTA_MIN) {
+                let gamma = y.iter().zip(ap.iter()).filter(|(_, a)| **a < 0.0)
+                    .map(|(yi, a)| (DELTA_MIN - yi) / a).fold(f64::INFINITY, f64::min);
+                y.iter_mut().zip(ap.iter()).for_each(|(a, b)| *a += gamma * b);
+                break;
+            }
+            if y_new.iter().any(|a| *a >= DELTA_MAX) {
+                let gamma = y.iter().zip(ap.iter()).zip(y_new.iter()).filter(|(_, a)| **a >= DELTA_MAX)
+                    .map(|((yi, a), _)| (DELTA_MAX - yi) / a).fold(f64::INFINITY, f64::min);
+                y.iter_mut().zip(ap.iter()).for_each(|(a, b)| *a += gamma * b);
+                break;
+            }
+            y = y_new;
+            rk.iter_mut().zip(w.iter()).for_each(|(a, b)| *a -= alpha * b);
+            rho_km2 = rho_km1;
+            z = rk.iter().zip(v.iter()).map(|(a, b)| a / b).collect();
+            rho_km1 = dot(&rk, &z);
+        }
+        x.iter_mut().zip(y.iter()).for_each(|(a, b)| *a *= b);
+        v = x.iter().zip(matvec(mat, &x)).map(|(a, b)| a * b).collect();
+        rk = v.iter().map(|a| 1.0 - a).collect();
+        rho_km1 = dot(&rk, &rk);
+        rout = rho_km1;
+        let rat = rout / rold;
+        rold = rout;
+        let eta_old = eta;
+        eta = G * rat;
+        if G * eta_old * eta_old > 0.1 {
+            eta = eta.max(G * eta_old * eta_old);
+        }
+        eta = eta.min(ETA_MAX).max(stop_tol / rout.sqrt());
+    }
+    (x, true, iter)
+}
+
+/// Marginals of the balanced matrix, i.e., `b_i * sum_j m_ij * b_j`.
+fn marginals(mat: &CsrMatrix<f64>, b: &[f64]) -> Vec<f64> {
+    matvec(mat, b).into_iter().zip(b).map(|(m, x)| m * x).collect()
+}
+
+fn matvec(mat: &CsrMatrix<f64>, x: &[f64]) -> Vec<f64> {
+    (0..mat.nrows()).into_par_iter().map(|i| {
+        let row = mat.row(i);
+        row.col_indices().iter().zip(row.values()).map(|(j, v)| v * x[*j]).sum()
+    }).collect()
+}
+
+fn submatrix(mat: &CsrMatrix<f64>, new_index: &[Option<usize>], n: usize) -> CsrMatrix<f64> {
+    let mut coo = CooMatrix::new(n, n);
+    mat.triplet_iter().for_each(|(i, j, v)|
+        if let (Some(i), Some(j)) = (new_index[i], new_index[j]) {
+            coo.push(i, j, *v);
+        }
+    );
+    CsrMatrix::from(&coo)
+}
+
+fn filter_entries<F: Fn(usize, usize) -> bool>(mat: &CsrMatrix<f64>, f: F) -> CsrMatrix<f64> {
+    let mut coo = CooMatrix::new(mat.nrows(), mat.ncols());
+    mat.triplet_iter().for_each(|(i, j, v)| if f(i, j) { coo.push(i, j, *v); });
+    CsrMatrix::from(&coo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balance() {
+        let n = 20;
+        let mut coo = CooMatrix::new(n, n);
+        for i in 0..n {
+            for j in 0..n {
+                let v = 100.0 / (1.0 + i.abs_diff(j) as f64) * (1.0 + (i % 3) as f64) * (1.0 + (j % 3) as f64);
+                coo.push(i, j, v);
+            }
+        }
+        let mat = CsrMatrix::from(&coo);
+        let filter = LowCoverageFilter { ignore_diags: 1, min_nnz: 0, min_count: 0.0, mad_max: None };
+        for method in [BalanceMethod::ICE, BalanceMethod::KR] {
+            let res = balance(&mat, method, &filter, 1e-8, 1000).unwrap();
+            assert!(res.converged);
+            res.matrix.row_iter().for_each(|row| {
+                let s: f64 = row.values().iter().sum();
+                assert!((s - 1.0).abs() < 1e-3, "{:?}: {}", method, s);
+            });
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! # A/B Compartments
+//!
+//! Compartment scores are computed for each chromosome separately, following the
+//! procedure of Lieberman-Aiden et al. (2009):
+//!
+//! 1. The (balanced) contact matrix is normalized by the expected contacts at each
+//!    distance, i.e., the mean of each diagonal.
+//! 2. The Pearson correlation matrix of the observed/expected matrix is computed.
+//! 3. The leading eigenvector of the correlation matrix is used as the compartment score.
+//!    As the sign of the eigenvector is arbitrary, it is oriented to be positively
+//!    correlated with a reference track, e.g., GC content or chromatin accessibility,
+//!    so that positive values correspond to the A compartment.
+use crate::{
+    hic::ContactMatrix,
+    preprocessing::count_data::{CoverageType, GenomeBaseIndex, GenomeCoverage},
+    utils::{open_file_for_write, similarity::pearson},
+};
+
+use anyhow::Result;
+use bed_utils::bed::{BEDLike, BedGraph};
+use ndarray::{Array1, Array2, Axis};
+use std::{io::{BufRead, Write}, path::Path};
+
+/// Compartment scores of a contact matrix. Bins without scores have `NaN` values.
+#[derive(Debug, Clone)]
+pub struct Compartments {
+    pub index: GenomeBaseIndex,
+    pub scores: Vec<f64>,
+    /// Leading eigenvalues of chromosomes.
+    pub eigenvalues: Vec<(String, f64)>,
+}
+
+impl Compartments {
+    pub fn to_bedgraph(&self) -> Vec<BedGraph<f32>> {
+        self.scores.iter().enumerate().filter(|(_, x)| x.is_finite()).map(|(i, x)|
+            BedGraph::from_bed(&self.index.get_region(i), *x as f32)
+        ).collect()
+    }
+
+    pub fn write_bedgraph<P: AsRef<Path>>(&self, file: P) -> Result<()> {
+        let mut writer = open_file_for_write(file, None, None)?;
+        self.to_bedgraph().into_iter().try_for_each(|x|
+            writeln!(writer, "{}\t{}\t{}\t{}", x.chrom(), x.start(), x.end(), x.value)
+        )?;
+        Ok(())
+    }
+
+    pub fn write_bigwig<P: AsRef<Path>>(&self, file: P) -> Result<()> {
+        let chrom_sizes = self.index.chrom_sizes().map(|(k, v)| (k.to_string(), v as u32)).collect();
+        crate::export::create_bigwig_from_bedgraph(self.to_bedgraph(), &chrom_sizes, file)
+    }
+}
+
+/// Call compartments from a symmetric contact matrix.
+///
+/// # Arguments
+///
+/// * `mat` - The contact matrix.
+/// * `weight` - Balancing weights. Bins with non-finite weights are masked.
+/// * `track` - A reference track used to orient the eigenvectors, e.g., GC content or
+///   chromatin accessibility of each bin. Bins with non-finite values are ignored.
+/// * `ignore_diags` - Number of diagonals to ignore.
+pub fn call_compartments(
+    mat: &ContactMatrix,
+    weight: Option<&[f64]>,
+    track: Option<&[f64]>,
+    ignore_diags: usize,
+) -> Compartments {
+    let index = &mat.index;
+    let mut scores = vec![f64::NAN; mat.n_bins()];
+    let mut eigenvalues = Vec::new();
+    index.chrom_sizes().for_each(|(chr, _)| {
+        let range = index.get_range(chr).unwrap();
+        let n = range.len();
+        let mut cis = Array2::<f64>::zeros((n, n));
+        range.clone().for_each(|i| {
+            let row = mat.matrix.row(i);
+            row.col_indices().iter().zip(row.values()).filter(|(j, _)| range.contains(j)).for_each(|(j, v)| {
+                let w = weight.map_or(1.0, |w| w[i] * w[*j]);
+                cis[[i - range.start, j - range.start]] = v * w;
+            });
+        });
+        let valid: Vec<usize> = (0..n).filter(|i| {
+            weight.map_or(true, |w| w[range.start + i].is_finite()) && cis.row(*i).sum() > 0.0
+        }).collect();
+        if valid.len() < 3 {
+            return;
+        }
+
+        let corr = correlation(&observed_over_expected(&cis, &valid, ignore_diags));
+        let (lambda, mut eig) = leading_eigenvector(&corr);
+        if let Some(track) = track {
+            let (x, y): (Vec<f64>, Vec<f64>) = valid.iter().zip(eig.iter())
+                .map(|(i, e)| (*e, track[range.start + i]))
+                .filter(|(_, t)| t.is_finite())
+                .unzip();
+            if pearson(&x, &y) < 0.0 {
+                eig.mapv_inplace(|x| -x);
+            }
+        }
+        valid.iter().zip(eig.iter()).for_each(|(i, e)| scores[range.start + i] = *e);
+        eigenvalues.push((chr.clone(), lambda));
+    });
+    Compartments { index: index.clone(), scores, eigenvalues }
+}
+
+/// Observed/expected matrix of valid bins. The expected value at each distance
+/// is the mean of the diagonal over valid bins. Ignored diagonals are set to 1.
+fn observed_over_expected(mat: &Array2<f64>, valid: &[usize], ignore_diags: usize) -> Array2<f64> {
+    let n = mat.nrows();
+    let mut is_valid = vec![false; n];
+    valid.iter().for_each(|i| is_valid[*i] = true);
+    let expected: Vec<f64> = (0..n).map(|d| {
+        let (sum, count) = (0..n - d).filter(|i| is_valid[*i] && is_valid[i + d])
+            .fold((0.0, 0), |(s, c), i| (s + mat[[i, i + d]], c + 1));
+        if count > 0 { sum / count as f64 } else { 0.0 }
+    }).collect();
+    Array2::from_shape_fn((valid.len(), valid.len()), |(a, b)| {
+        let (i, j) = (valid[a], valid[b]);
+        let d = i.abs_diff(j);
+        if d < ignore_diags {
+            1.0
+        } else if expected[d] > 0.0 {
+            mat[[i, j]] / expected[d]
+        } else {
+            0.0
+        }
+    })
+}
+
+/// Pearson correlation between rows.
+fn correlation(mat: &Array2<f64>) -> Array2<f64> {
+    let mut z = mat.clone();
+    z.axis_iter_mut(Axis(0)).for_each(|mut row| {
+        let mean = row.mean().unwrap();
+        row.mapv_inplace(|x| x - mean);
+        let norm = row.dot(&row).sqrt();
+        if norm > 0.0 {
+            row.mapv_inplace(|x| x / norm);
+        }
+    });
+    z.dot(&z.t())
+}
+
+/// Eigenvector of the eigenvalue with the largest magnitude, computed by power iteration.
+/// The eigenvector is scaled by the square root of the absolute eigenvalue.
+fn leading_eigenvector(mat: &Array2<f64>) -> (f64, Array1<f64>) {
+    let n = mat.nrows();
+    let mut v = Array1::from_shape_fn(n, |i| 1.0 + ((i * 7919) % 101) as f64 / 101.0);
+    v /= v.dot(&v).sqrt();
+    for _ in 0..1000 {
+        let mut v_new = mat.dot(&v);
+        let norm = v_new.dot(&v_new).sqrt();
+        if norm == 0.0 {
+            break;
+        }
+        v_new /= norm;
+        let delta = (&v_new - &v).mapv(|x| x.abs()).sum().min((&v_new + &v).mapv(|x| x.abs()).sum());
+        v = v_new;
+        if delta < 1e-10 {
+            break;
+        }
+    }
+    let lambda = v.dot(&mat.dot(&v));
+    (lambda, v * lambda.abs().sqrt())
+}
+
+/// Compute the GC content of each bin from a FASTA file.
+/// Bins without A, C, G or T bases, or in chromosomes absent from the FASTA file, have `NaN` values.
+pub fn gc_content<R: BufRead>(fasta: R, index: &GenomeBaseIndex) -> Result<Vec<f64>> {
+    let step = index.step;
+    let mut gc = vec![0u64; index.len()];
+    let mut total = vec![0u64; index.len()];
+    let mut cur: Option<(std::ops::Range<usize>, usize)> = None; // (bins, position)
+    for line in fasta.lines() {
+        let line = line?;
+        if let Some(name) = line.strip_prefix('>') {
+            let name = name.split_whitespace().next().unwrap_or("");
+            cur = index.get_range(name).map(|r| (r, 0));
+        } else if let Some((range, pos)) = cur.as_mut() {
+            line.trim_end().bytes().for_each(|b| {
+                let i = range.start + *pos / step;
+                *pos += 1;
+                if i >= range.end {
+                    return;
+                }
+                match b {
+                    b'G' | b'C' | b'g' | b'c' => { gc[i] += 1; total[i] += 1; },
+                    b'A' | b'T' | b'a' | b't' => { total[i] += 1; },
+                    _ => {},
+                }
+            });
+        }
+    }
+    Ok(gc.into_iter().zip(total).map(|(g, t)| if t > 0 { g as f64 / t as f64 } else { f64::NAN }).collect())
+}
+
+/// Aggregate the coverage of all cells into a track binned by `index`.
+/// Bins in chromosomes absent from the coverage have `NaN` values.
+pub fn coverage_track<I>(coverage: GenomeCoverage<I>, index: &GenomeBaseIndex) -> Vec<f64>
+where
+    I: ExactSizeIterator<Item = (CoverageType, usize, usize)>,
+{
+    let coverage = coverage.with_resolution(index.step);
+    let cov_index = coverage.get_gindex();
+    let mut total = vec![0.0; cov_index.len()];
+    coverage.into_values::<u32>().for_each(|(csr, _, _)|
+        csr.col_indices().iter().zip(csr.values()).for_each(|(i, v)| total[*i] += *v as f64)
+    );
+    let mut track = vec![f64::NAN; index.len()];
+    cov_index.chrom_sizes().for_each(|(chr, _)| {
+        if let (Some(src), Some(dst)) = (cov_index.get_range(chr), index.get_range(chr)) {
+            src.zip(dst).for_each(|(i, j)| track[j] = total[i]);
+        }
+    });
+    track
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compartment_eigenvector() {
+        // Checkerboard pattern with decay: bins of the same compartment interact more.
+        let label = [1.0, 1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, -1.0];
+        let n = label.len();
+        let mat = Array2::from_shape_fn((n, n), |(i, j)| {
+            let d = i.abs_diff(j) as f64 + 1.0;
+            (if label[i] == label[j] { 3.0 } else { 1.0 }) / d
+        });
+        let valid: Vec<usize> = (0..n).collect();
+        let corr = correlation(&observed_over_expected(&mat, &valid, 1));
+        let (lambda, eig) = leading_eigenvector(&corr);
+        assert!(lambda > 0.0);
+        let eig = eig.to_vec();
+        let r = pearson(&eig, &label);
+        assert!(r.abs() > 0.99, "{}", r);
+    }
+}
@@ -0,0 +1,109 @@
+//! # Cooler Writer
+//!
+//! Write contact matrices in the cooler format, version 3
+//! (<https://cooler.readthedocs.io/en/latest/schema.html>). Pixels are stored in
+//! the "symmetric-upper" mode, i.e., only contacts with `bin1_id <= bin2_id` are written.
+use crate::hic::ContactMatrix;
+
+use anyhow::{Context, Result};
+use hdf5::{types::{FixedAscii, VarLenUnicode}, Group, H5Type};
+
+const MAX_CHROM_NAME_LEN: usize = 64;
+
+/// Write a contact matrix as a cooler into the given HDF5 group.
+/// If provided, `weight` is stored as the `bins/weight` column. Masked bins should
+/// have `NaN` weights.
+pub fn write_cooler(group: &Group, mat: &ContactMatrix, weight: Option<&[f64]>) -> Result<()> {
+    let index = &mat.index;
+    let bin_size = index.step as u64;
+    let n_bins = mat.n_bins();
+
+    // Chromosomes
+    let chroms = group.create_group("chroms")?;
+    let names = index.chrom_sizes().map(|(chr, _)|
+        FixedAscii::<MAX_CHROM_NAME_LEN>::from_ascii(chr.as_bytes())
+            .with_context(|| format!("invalid chromosome name: {}", chr))
+    ).collect::<Result<Vec<_>>>()?;
+    let lengths: Vec<i64> = index.chrom_sizes().map(|(_, s)| s as i64).collect();
+    chroms.new_dataset_builder().with_data(names.as_slice()).create("name")?;
+    chroms.new_dataset_builder().with_data(lengths.as_slice()).create("length")?;
+
+    // Bins
+    let mut bin_chrom = Vec::with_capacity(n_bins);
+    let mut bin_start = Vec::with_capacity(n_bins);
+    let mut bin_end = Vec::with_capacity(n_bins);
+    index.chrom_sizes().enumerate().for_each(|(i, (_, size))| {
+        (0..size).step_by(bin_size as usize).for_each(|start| {
+            bin_chrom.push(i as i32);
+            bin_start.push(start as i64);
+            bin_end.push((start + bin_size).min(size) as i64);
+        });
+    });
+    let bins = group.create_group("bins")?;
+    bins.new_dataset_builder().with_data(bin_chrom.as_slice()).create("chrom")?;
+    bins.new_dataset_builder().with_data(bin_start.as_slice()).create("start")?;
+    bins.new_dataset_builder().with_data(bin_end.as_slice()).create("end")?;
+    if let Some(weight) = weight {
+        bins.new_dataset_builder().with_data(weight).create("weight")?;
+    }
+
+    // Pixels
+    let mut bin1_id = Vec::new();
+    let mut bin2_id = Vec::new();
+    let mut count = Vec::new();
+    let mut bin1_offset = Vec::with_capacity(n_bins + 1);
+    bin1_offset.push(0i64);
+    mat.matrix.row_iter().enumerate().for_each(|(i, row)| {
+        row.col_indices().iter().zip(row.values()).filter(|(j, _)| **j >= i).for_each(|(j, v)| {
+            bin1_id.push(i as i64);
+            bin2_id.push(*j as i64);
+            count.push(v.round() as i32);
+        });
+        bin1_offset.push(bin1_id.len() as i64);
+    });
+    let nnz = count.len();
+    let pixels = group.create_group("pixels")?;
+    pixels.new_dataset_builder().with_data(bin1_id.as_slice()).create("bin1_id")?;
+    pixels.new_dataset_builder().with_data(bin2_id.as_slice()).create("bin2_id")?;
+    pixels.new_dataset_builder().with_data(count.as_slice()).create("count")?;
+
+    // Indexes
+    let mut chrom_offset = vec![0i64];
+    index.chrom_sizes().for_each(|(chr, _)|
+        chrom_offset.push(index.get_range(chr).unwrap().end as i64)
+    );
+    let indexes = group.create_group("indexes")?;
+    indexes.new_dataset_builder().with_data(chrom_offset.as_slice()).create("chrom_offset")?;
+    indexes.new_dataset_builder().with_data(bin1_offset.as_slice()).create("bin1_offset")?;
+
+    // Metadata
+    write_str_attr(group, "format", "HDF5::Cooler")?;
+    write_attr(group, "format-version", 3i64)?;
+    write_str_attr(group, "bin-type", "fixed")?;
+    write_attr(group, "bin-size", bin_size as i64)?;
+    write_str_attr(group, "storage-mode", "symmetric-upper")?;
+    write_attr(group, "nbins", n_bins as i64)?;
+    write_attr(group, "nchroms", lengths.len() as i64)?;
+    write_attr(group, "nnz", nnz as i64)?;
+    write_str_attr(group, "generated-by", concat!("snapatac2-", env!("CARGO_PKG_VERSION")))?;
+    write_str_attr(group, "metadata", "{}")?;
+    Ok(())
+}
+
+/// Write the root attributes of a multi-resolution cooler (`.mcool`) and return
+/// the `resolutions` group, under which coolers are stored by resolution.
+pub fn init_mcool(group: &Group) -> Result<Group> {
+    write_str_attr(group, "format", "HDF5::MCOOL")?;
+    write_attr(group, "format-version", 2i64)?;
+    Ok(group.create_group("resolutions")?)
+}
+
+fn write_attr<T: H5Type>(group: &Group, name: &str, value: T) -> Result<()> {
+    group.new_attr_builder().with_data(&ndarray::arr0(value)).create(name)?;
+    Ok(())
+}
+
+fn write_str_attr(group: &Group, name: &str, value: &str) -> Result<()> {
+    let value: VarLenUnicode = value.parse()?;
+    write_attr(group, name, value)
+}
@@ -0,0 +1,278 @@
+//! # Contact Map Imputation
+//!
+//! scHiCluster-style imputation of single-cell contact maps (Zhou et al., 2019).
+//! For each cell and each chromosome, the binned contact matrix is smoothed by
+//! a linear convolution, followed by a random walk with restart on the resulting
+//! contact graph. Imputed values within a distance band are then binarized by
+//! keeping the top percentile of each chromosome.
+use crate::preprocessing::count_data::{ContactMap, GenomeBaseIndex, SnapData};
+use crate::utils::percentile;
+
+use anndata::{data::utils::to_csr_data, AnnDataOp, AxisArraysOp};
+use anyhow::Result;
+use indicatif::{ProgressIterator, style::ProgressStyle};
+use nalgebra_sparse::CsrMatrix;
+use ndarray::Array2;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::ops::Range;
+
+#[derive(Debug, Clone)]
+pub struct ImputeOptions {
+    /// Size of the convolution window is `2 * pad + 1`.
+    pub pad: usize,
+    /// Restart probability of the random walk.
+    pub restart_prob: f64,
+    /// Convergence tolerance of the random walk, measured by the Frobenius norm.
+    pub tol: f64,
+    pub max_iter: usize,
+    /// Only contacts within this distance (in bp) are kept as features.
+    /// If `None`, the entire upper triangle of each chromosome is used.
+    pub max_distance: Option<u64>,
+    /// Binarize imputed values by keeping the top `top_percent` percent of each
+    /// chromosome. If `None`, imputed values are returned.
+    pub top_percent: Option<f64>,
+}
+
+impl Default for ImputeOptions {
+    fn default() -> Self {
+        Self {
+            pad: 1,
+            restart_prob: 0.5,
+            tol: 1e-6,
+            max_iter: 100,
+            max_distance: None,
+            top_percent: Some(20.0),
+        }
+    }
+}
+
+/// Layout of the imputed band features. For each chromosome, features are the
+/// entries `(i, j)` with `i <= j <= i + band` in row-major order.
+struct BandIndex {
+    chroms: Vec<(String, Range<usize>)>,
+    band: usize,
+    /// Feature offsets of each chromosome.
+    offsets: Vec<usize>,
+    /// Feature offsets of each row, relative to the chromosome.
+    row_offsets: Vec<Vec<usize>>,
+}
+
+impl BandIndex {
+    fn new(index: &GenomeBaseIndex, band: usize) -> Self {
+        let chroms: Vec<_> = index.chrom_sizes()
+            .map(|(chr, _)| (chr.clone(), index.get_range(chr).unwrap()))
+            .collect();
+        let mut offsets = vec![0];
+        let row_offsets = chroms.iter().map(|(_, r)| {
+            let n = r.len();
+            let mut acc = 0;
+            let rows: Vec<usize> = (0..n).map(|i| {
+                let cur = acc;
+                acc += (i + band + 1).min(n) - i;
+                cur
+            }).collect();
+            offsets.push(offsets.last().unwrap() + acc);
+            rows
+        }).collect();
+        Self { chroms, band, offsets, row_offsets }
+    }
+
+    fn len(&self) -> usize {
+        *self.offsets.last().unwrap()
+    }
+
+    fn feature_names(&self, index: &GenomeBaseIndex) -> Vec<String> {
+        self.chroms.iter().flat_map(|(_, r)| {
+            let n = r.len();
+            let start = r.start;
+            (0..n).flat_map(move |i| (i..(i + self.band + 1).min(n)).map(move |j|
+                format!("{}|{}", index.get_region(start + i).pretty_show(), index.get_region(start + j).pretty_show())
+            ))
+        }).collect()
+    }
+
+    /// Find the chromosome of a bin.
+    fn chrom_of(&self, bin: usize) -> usize {
+        self.chroms.partition_point(|(_, r)| r.end <= bin)
+    }
+}
+
+/// Impute single-cell contact maps and write the band features to `.obsm[key]`,
+/// or to `.X` if `key` is `None`.
+///
+/// # Arguments
+///
+/// * `adata` - The input data containing `.obsm["contact"]`.
+/// * `resolution` - Bin size used to build the contact matrices.
+/// * `opts` - Imputation parameters.
+/// * `key` - If provided, the result is stored in `.obsm[key]`. Otherwise, it is stored in `.X`.
+/// * `chunk_size` - The number of cells to process at a time.
+/// * `out` - If provided, the result is written to `out` instead of `adata`.
+pub fn impute_contacts<A, B>(
+    adata: &A,
+    resolution: usize,
+    opts: &ImputeOptions,
+    key: Option<&str>,
+    chunk_size: usize,
+    out: Option<&B>,
+) -> Result<()>
+where
+    A: SnapData,
+    B: AnnDataOp,
+{
+    let contacts = adata.contact_count_iter(chunk_size)?.with_resolution(resolution);
+    let index = contacts.get_gindex();
+    let band = opts.max_distance.map_or(usize::MAX / 2, |d| num::integer::div_ceil(d as usize, resolution));
+    let band_index = BandIndex::new(&index, band);
+
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})"
+    ).unwrap();
+    let data_iter = impute(contacts, &band_index, opts).progress_with_style(style);
+
+    macro_rules! run {
+        ($adata:expr) => {
+            if let Some(key) = key {
+                $adata.obsm().add_iter(key, data_iter)?;
+            } else {
+                $adata.set_x_from_iter(data_iter)?;
+                $adata.set_var_names(band_index.feature_names(&index).into_iter().collect())?;
+            }
+        };
+    }
+    if let Some(out) = out {
+        run!(out);
+        out.set_obs_names(adata.obs_names())?;
+    } else {
+        run!(adata);
+    }
+    Ok(())
+}
+
+fn impute<'a, I>(
+    contacts: ContactMap<I>,
+    band_index: &'a BandIndex,
+    opts: &'a ImputeOptions,
+) -> impl ExactSizeIterator<Item = CsrMatrix<f32>> + 'a
+where
+    I: ExactSizeIterator<Item = (CsrMatrix<u8>, usize, usize)> + 'a,
+{
+    let n = contacts.get_gindex().len();
+    contacts.into_values::<f64>().map(move |(mat, _, _)| {
+        let rows: Vec<Vec<(usize, f64)>> = mat.row_iter().map(|row|
+            row.col_indices().iter().copied().zip(row.values().iter().copied()).collect()
+        ).collect();
+        let vec: Vec<_> = rows.into_par_iter().map(|row| impute_cell(row, n, band_index, opts)).collect();
+        let (r, c, offset, ind, data) = to_csr_data(vec, band_index.len());
+        CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()
+    })
+}
+
+fn impute_cell(
+    contacts: Vec<(usize, f64)>,
+    n: usize,
+    band_index: &BandIndex,
+    opts: &ImputeOptions,
+) -> Vec<(usize, f32)> {
+    let mut per_chrom: Vec<Vec<(usize, usize, f64)>> = vec![Vec::new(); band_index.chroms.len()];
+    contacts.into_iter().for_each(|(idx, v)| {
+        let (i, j) = (idx / n, idx % n);
+        let c = band_index.chrom_of(i);
+        if c == band_index.chrom_of(j) {
+            let start = band_index.chroms[c].1.start;
+            per_chrom[c].push((i - start, j - start, v));
+        }
+    });
+
+    per_chrom.into_iter().enumerate().filter(|(_, x)| !x.is_empty()).flat_map(|(c, x)| {
+        let size = band_index.chroms[c].1.len();
+        let mut mat = Array2::<f64>::zeros((size, size));
+        x.into_iter().for_each(|(i, j, v)| {
+            mat[[i, j]] += v;
+            if i != j {
+                mat[[j, i]] += v;
+            }
+        });
+        let q = random_walk(convolve(&mat, opts.pad), opts.restart_prob, opts.tol, opts.max_iter);
+
+        let offset = band_index.offsets[c];
+        let row_offsets = &band_index.row_offsets[c];
+        let values: Vec<(usize, f64)> = (0..size).flat_map(|i|
+            (i..(i + band_index.band + 1).min(size)).map(move |j| (i, j))
+        ).map(|(i, j)| (offset + row_offsets[i] + j - i, (q[[i, j]] + q[[j, i]]) / 2.0)).collect();
+        match opts.top_percent {
+            None => values.into_iter().map(|(k, v)| (k, v as f32)).filter(|(_, v)| *v != 0.0).collect::<Vec<_>>(),
+            Some(p) => {
+                let thres = percentile(values.iter().map(|x| x.1).collect(), 100.0 - p);
+                values.into_iter().filter(|(_, v)| *v > thres).map(|(k, _)| (k, 1.0)).collect()
+            },
+        }
+    }).collect()
+}
+
+/// Average over a `(2 * pad + 1) x (2 * pad + 1)` window, with zero padding.
+fn convolve(mat: &Array2<f64>, pad: usize) -> Array2<f64> {
+    if pad == 0 {
+        return mat.clone();
+    }
+    let n = mat.nrows();
+    // 2D prefix sums
+    let mut acc = Array2::<f64>::zeros((n + 1, n + 1));
+    for i in 0..n {
+        for j in 0..n {
+            acc[[i + 1, j + 1]] = mat[[i, j]] + acc[[i, j + 1]] + acc[[i + 1, j]] - acc[[i, j]];
+        }
+    }
+    let w = ((2 * pad + 1) * (2 * pad + 1)) as f64;
+    Array2::from_shape_fn((n, n), |(i, j)| {
+        let (r0, r1) = (i.saturating_sub(pad), (i + pad + 1).min(n));
+        let (c0, c1) = (j.saturating_sub(pad), (j + pad + 1).min(n));
+        (acc[[r1, c1]] - acc[[r0, c1]] - acc[[r1, c0]] + acc[[r0, c0]]) / w
+    })
+}
+
+/// Random walk with restart. Columns of the transition matrix are normalized,
+/// and bins without contacts are given self-loops.
+fn random_walk(mut mat: Array2<f64>, restart_prob: f64, tol: f64, max_iter: usize) -> Array2<f64> {
+    let n = mat.nrows();
+    for j in 0..n {
+        let s = mat.column(j).sum();
+        if s > 0.0 {
+            mat.column_mut(j).mapv_inplace(|x| x / s);
+        } else {
+            mat[[j, j]] = 1.0;
+        }
+    }
+    let restart = Array2::<f64>::eye(n) * restart_prob;
+    let mut q = &restart + &(&mat * (1.0 - restart_prob));
+    for _ in 0..max_iter {
+        let q_new = &restart + &(q.dot(&mat) * (1.0 - restart_prob));
+        let delta = (&q - &q_new).mapv(|x| x * x).sum().sqrt();
+        q = q_new;
+        if delta < tol {
+            break;
+        }
+    }
+    q
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convolve() {
+        let mat = Array2::from_shape_fn((4, 4), |(i, j)| (i * 4 + j) as f64);
+        let res = convolve(&mat, 1);
+        assert!((res[[0, 0]] - (0.0 + 1.0 + 4.0 + 5.0) / 9.0).abs() < 1e-12);
+        assert!((res[[1, 1]] - (0..3).flat_map(|i| (0..3).map(move |j| (i * 4 + j) as f64)).sum::<f64>() / 9.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_random_walk() {
+        let mat = Array2::from_shape_fn((5, 5), |(i, j)| if i.abs_diff(j) == 1 { 1.0 } else { 0.0 });
+        let q = random_walk(mat, 0.5, 1e-10, 1000);
+        // Columns of the stationary distribution sum to 1.
+        q.columns().into_iter().for_each(|c| assert!((c.sum() - 1.0).abs() < 1e-8));
+    }
+}
@@ -0,0 +1,381 @@
+//! # Loop Calling
+//!
+//! HiCCUPS-like detection of chromatin loops (Rao et al., 2014). For each pixel
+//! within a distance band, the local expected count is estimated from four
+//! neighbourhoods ("donut", "lower-left", "horizontal" and "vertical"), by scaling
+//! the distance-dependent expected count with the observed/expected ratio of the
+//! neighbourhood. Observed counts are tested against Poisson distributions, where
+//! pixels are grouped into logarithmically spaced "lambda chunks" by their local
+//! expected counts, and FDR is controlled within each chunk. Enriched pixels
+//! are then clustered and the summit of each cluster is reported.
+use crate::{
+    hic::ContactMatrix,
+    preprocessing::count_data::GenomeBaseIndex,
+    utils::{adjust_pvalues_bh, open_file_for_write},
+};
+
+use anyhow::Result;
+use bed_utils::bed::BEDLike;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use statrs::function::gamma::gamma_lr;
+use std::{collections::{BTreeMap, HashMap}, io::Write, path::Path};
+
+const KERNEL_NAMES: [&str; 4] = ["donut", "lowerleft", "horizontal", "vertical"];
+
+/// Maximum fraction of masked pixels allowed in a neighbourhood.
+const MAX_MASKED_FRAC: f64 = 0.25;
+
+#[derive(Debug, Clone)]
+pub struct LoopOptions {
+    /// Half width of the peak, in bins. Pixels within this distance from the
+    /// center are excluded from the neighbourhoods.
+    pub peak_width: usize,
+    /// Half width of the neighbourhoods, in bins.
+    pub window_width: usize,
+    /// Minimal distance (in bp) between the two anchors of a loop.
+    pub min_distance: u64,
+    /// Maximal distance (in bp) between the two anchors of a loop.
+    pub max_distance: u64,
+    /// FDR threshold applied to each neighbourhood.
+    pub fdr: f64,
+    /// Lambda chunks are bounded by the powers of this base.
+    pub lambda_base: f64,
+    /// Minimal enrichment over the donut and lower-left neighbourhoods.
+    pub min_enrichment: f64,
+    /// Minimal enrichment over the horizontal and vertical neighbourhoods.
+    pub min_enrichment_hv: f64,
+    /// Enriched pixels within this distance (in bins) are merged into one loop.
+    pub cluster_radius: usize,
+}
+
+impl Default for LoopOptions {
+    fn default() -> Self {
+        Self {
+            peak_width: 1,
+            window_width: 3,
+            min_distance: 20_000,
+            max_distance: 2_000_000,
+            fdr: 0.1,
+            lambda_base: 2f64.powf(1.0 / 3.0),
+            min_enrichment: 1.75,
+            min_enrichment_hv: 1.5,
+            cluster_radius: 2,
+        }
+    }
+}
+
+/// A chromatin loop. `bin1` and `bin2` are the summit of the loop, with `bin1 < bin2`.
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub bin1: usize,
+    pub bin2: usize,
+    /// Observed count, before balancing.
+    pub observed: f64,
+    /// Local expected counts of the donut, lower-left, horizontal and vertical
+    /// neighbourhoods, before balancing.
+    pub expected: [f64; 4],
+    /// The largest q-value of the four neighbourhoods.
+    pub qvalue: f64,
+    /// Number of enriched pixels in the cluster.
+    pub num_pixels: usize,
+}
+
+/// A candidate pixel.
+struct Pixel {
+    bin1: usize,
+    bin2: usize,
+    observed: f64,
+    /// Local expected counts, before balancing.
+    expected: [f64; 4],
+    /// Observed/expected ratios of the balanced matrix.
+    enrichment: [f64; 4],
+}
+
+/// Call loops from a symmetric contact matrix.
+///
+/// # Arguments
+///
+/// * `mat` - The contact matrix containing raw counts.
+/// * `weight` - Balancing weights. Bins with non-finite weights are masked.
+///   If `None`, the raw matrix is used.
+/// * `opts` - Loop calling parameters.
+pub fn call_loops(mat: &ContactMatrix, weight: Option<&[f64]>, opts: &LoopOptions) -> Vec<Loop> {
+    let index = &mat.index;
+    let step = index.step as u64;
+    let kernels = kernels(opts.peak_width, opts.window_width);
+    let min_d = (num::integer::div_ceil(opts.min_distance, step) as usize).max(opts.peak_width + 1);
+    let max_d = (opts.max_distance / step) as usize;
+
+    // Collect candidate pixels, and count pixels with zero observation in each lambda chunk.
+    let mut pixels = Vec::new();
+    let mut zeros: HashMap<(usize, i32), usize> = HashMap::new();
+    index.chrom_sizes().for_each(|(chr, _)| {
+        let range = index.get_range(chr).unwrap();
+        let band = Band::new(mat, weight, range, max_d + 2 * opts.window_width);
+        let (p, z) = band.pixels(&kernels, min_d, max_d, opts.lambda_base);
+        pixels.extend(p);
+        z.into_iter().for_each(|(k, v)| *zeros.entry(k).or_insert(0) += v);
+    });
+
+    // FDR within lambda chunks
+    let mut qvalues = vec![[1.0; 4]; pixels.len()];
+    for k in 0..kernels.len() {
+        let mut chunks: BTreeMap<i32, Vec<usize>> = BTreeMap::new();
+        pixels.iter().enumerate().for_each(|(i, p)|
+            chunks.entry(lambda_chunk(p.expected[k], opts.lambda_base)).or_default().push(i)
+        );
+        chunks.into_iter().for_each(|(chunk, idx)| {
+            let lambda = opts.lambda_base.powi(chunk);
+            let pvalues: Vec<f64> = idx.iter().map(|i| poisson_sf(pixels[*i].observed, lambda)).collect();
+            // Pixels with zero observations have p-values of 1. They take the largest
+            // ranks and only rescale the adjusted p-values of other pixels.
+            let total = idx.len() + zeros.get(&(k, chunk)).copied().unwrap_or(0);
+            let scale = total as f64 / idx.len() as f64;
+            adjust_pvalues_bh(&pvalues).into_iter().zip(idx).for_each(|(q, i)|
+                qvalues[i][k] = (q * scale).min(1.0)
+            );
+        });
+    }
+
+    let enriched: Vec<(Pixel, f64)> = pixels.into_iter().zip(qvalues).filter(|(p, q)|
+        q.iter().all(|x| *x <= opts.fdr) &&
+            p.enrichment[0] >= opts.min_enrichment && p.enrichment[1] >= opts.min_enrichment &&
+            p.enrichment[2] >= opts.min_enrichment_hv && p.enrichment[3] >= opts.min_enrichment_hv
+    ).map(|(p, q)| (p, q.into_iter().fold(0.0, f64::max))).collect();
+    cluster(enriched, opts.cluster_radius)
+}
+
+/// Write loops in the BEDPE format.
+pub fn write_bedpe<P: AsRef<Path>>(loops: &[Loop], index: &GenomeBaseIndex, file: P) -> Result<()> {
+    let mut writer = open_file_for_write(file, None, None)?;
+    writeln!(
+        writer, "#chr1\tx1\tx2\tchr2\ty1\ty2\tobserved\t{}\tqvalue\tnum_pixels",
+        KERNEL_NAMES.map(|x| format!("expected_{}", x)).join("\t"),
+    )?;
+    loops.iter().try_for_each(|l| {
+        let (r1, r2) = (index.get_region(l.bin1), index.get_region(l.bin2));
+        writeln!(
+            writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            r1.chrom(), r1.start(), r1.end(), r2.chrom(), r2.start(), r2.end(),
+            l.observed, l.expected.map(|x| x.to_string()).join("\t"), l.qvalue, l.num_pixels,
+        )
+    })?;
+    Ok(())
+}
+
+/// Offsets `(row, column)` of the donut, lower-left, horizontal and vertical neighbourhoods.
+/// Rows increase towards the diagonal for pixels in the upper triangle.
+fn kernels(p: usize, w: usize) -> Vec<Vec<(isize, isize)>> {
+    let (p, w) = (p as isize, w as isize);
+    let square = || (-w..=w).flat_map(|a| (-w..=w).map(move |b| (a, b)));
+    let in_peak = move |a: isize, b: isize| a.abs() <= p && b.abs() <= p;
+    vec![
+        square().filter(|(a, b)| !in_peak(*a, *b) && *a != 0 && *b != 0).collect(),
+        square().filter(|(a, b)| *a > 0 && *b < 0 && !in_peak(*a, *b)).collect(),
+        square().filter(|(a, b)| a.abs() <= 1 && b.abs() > p).collect(),
+        square().filter(|(a, b)| b.abs() <= 1 && a.abs() > p).collect(),
+    ]
+}
+
+/// Index of the lambda chunk `(base^(k-1), base^k]`. Lambdas below 1 belong to chunk 0.
+fn lambda_chunk(lambda: f64, base: f64) -> i32 {
+    if lambda <= 1.0 {
+        0
+    } else {
+        // Tolerance for lambdas at the boundaries.
+        (lambda.ln() / base.ln() - 1e-9).ceil() as i32
+    }
+}
+
+/// P(X >= k) for X ~ Poisson(lambda).
+fn poisson_sf(k: f64, lambda: f64) -> f64 {
+    if k <= 0.0 { 1.0 } else { gamma_lr(k, lambda) }
+}
+
+/// Dense band of the cis contact matrix of a chromosome.
+struct Band<'a> {
+    start: usize,
+    n: usize,
+    width: usize,
+    raw: Vec<f64>,
+    weight: Option<&'a [f64]>,
+    /// Expected balanced counts at each distance.
+    expected: Vec<f64>,
+}
+
+impl<'a> Band<'a> {
+    fn new(mat: &ContactMatrix, weight: Option<&'a [f64]>, range: std::ops::Range<usize>, width: usize) -> Self {
+        let (start, n) = (range.start, range.len());
+        let mut raw = vec![0.0; n * (width + 1)];
+        range.clone().for_each(|i| {
+            let row = mat.matrix.row(i);
+            row.col_indices().iter().zip(row.values())
+                .filter(|(j, _)| **j >= i && **j < range.end && **j - i <= width)
+                .for_each(|(j, v)| raw[(i - start) * (width + 1) + j - i] = *v);
+        });
+        let mut band = Self { start, n, width, raw, weight, expected: Vec::new() };
+        band.expected = (0..=width).map(|d| {
+            let (sum, count) = (0..n.saturating_sub(d)).filter_map(|i| band.balanced(i, i + d))
+                .fold((0.0, 0), |(s, c), x| (s + x, c + 1));
+            if count > 0 { sum / count as f64 } else { 0.0 }
+        }).collect();
+        band
+    }
+
+    fn weight(&self, i: usize) -> f64 {
+        self.weight.map_or(1.0, |w| w[self.start + i])
+    }
+
+    fn raw(&self, i: usize, j: usize) -> f64 {
+        let (i, j) = if i <= j { (i, j) } else { (j, i) };
+        self.raw[i * (self.width + 1) + j - i]
+    }
+
+    /// Balanced count, or `None` if the pixel is masked or outside the band.
+    fn balanced(&self, i: usize, j: usize) -> Option<f64> {
+        let (wi, wj) = (self.weight(i), self.weight(j));
+        if i.abs_diff(j) > self.width || !wi.is_finite() || !wj.is_finite() {
+            None
+        } else {
+            Some(self.raw(i, j) * wi * wj)
+        }
+    }
+
+    /// Compute candidate pixels with non-zero observations, and the number of
+    /// pixels with zero observations in each (kernel, lambda chunk).
+    fn pixels(
+        &self,
+        kernels: &[Vec<(isize, isize)>],
+        min_d: usize,
+        max_d: usize,
+        lambda_base: f64,
+    ) -> (Vec<Pixel>, HashMap<(usize, i32), usize>) {
+        (0..self.n).into_par_iter().fold(
+            || (Vec::new(), HashMap::new()),
+            |(mut pixels, mut zeros), i| {
+                (min_d..=max_d).map(|d| i + d).take_while(|j| *j < self.n).for_each(|j| {
+                    if let Some(pixel) = self.test_pixel(i, j, kernels) {
+                        if pixel.observed > 0.0 {
+                            pixels.push(pixel);
+                        } else {
+                            pixel.expected.iter().enumerate().for_each(|(k, e)|
+                                *zeros.entry((k, lambda_chunk(*e, lambda_base))).or_insert(0) += 1
+                            );
+                        }
+                    }
+                });
+                (pixels, zeros)
+            },
+        ).reduce(
+            || (Vec::new(), HashMap::new()),
+            |(mut p1, mut z1), (p2, z2)| {
+                p1.extend(p2);
+                z2.into_iter().for_each(|(k, v)| *z1.entry(k).or_insert(0) += v);
+                (p1, z1)
+            },
+        )
+    }
+
+    fn test_pixel(&self, i: usize, j: usize, kernels: &[Vec<(isize, isize)>]) -> Option<Pixel> {
+        let balanced = self.balanced(i, j)?;
+        let d = j - i;
+        let mut expected = [0.0; 4];
+        let mut enrichment = [0.0; 4];
+        for (k, kernel) in kernels.iter().enumerate() {
+            let (mut obs, mut exp, mut masked) = (0.0, 0.0, 0);
+            kernel.iter().for_each(|(a, b)| {
+                let (x, y) = (i as isize + a, j as isize + b);
+                let cell = if x < 0 || y < 0 || x as usize >= self.n || y as usize >= self.n {
+                    None
+                } else {
+                    self.balanced(x as usize, y as usize)
+                };
+                match cell {
+                    Some(v) => {
+                        obs += v;
+                        exp += self.expected[(x - y).unsigned_abs()];
+                    },
+                    None => masked += 1,
+                }
+            });
+            if masked as f64 > MAX_MASKED_FRAC * kernel.len() as f64 || obs <= 0.0 || exp <= 0.0 {
+                return None;
+            }
+            let local = self.expected[d] * obs / exp;
+            expected[k] = local / (self.weight(i) * self.weight(j));
+            enrichment[k] = balanced / local;
+        }
+        Some(Pixel {
+            bin1: self.start + i,
+            bin2: self.start + j,
+            observed: self.raw(i, j),
+            expected,
+            enrichment,
+        })
+    }
+}
+
+/// Merge enriched pixels within `radius` and report the pixel with the highest
+/// enrichment over the donut as the summit of each cluster.
+fn cluster(pixels: Vec<(Pixel, f64)>, radius: usize) -> Vec<Loop> {
+    let lookup: HashMap<(usize, usize), usize> = pixels.iter().enumerate()
+        .map(|(k, (p, _))| ((p.bin1, p.bin2), k)).collect();
+    let mut parent: Vec<usize> = (0..pixels.len()).collect();
+    fn find(parent: &mut [usize], mut x: usize) -> usize {
+        while parent[x] != x {
+            parent[x] = parent[parent[x]];
+            x = parent[x];
+        }
+        x
+    }
+    pixels.iter().enumerate().for_each(|(k, (p, _))| {
+        let rows = p.bin1.saturating_sub(radius)..=p.bin1 + radius;
+        rows.for_each(|x| (p.bin2.saturating_sub(radius)..=p.bin2 + radius).for_each(|y| {
+            if let Some(l) = lookup.get(&(x, y)) {
+                let (a, b) = (find(&mut parent, k), find(&mut parent, *l));
+                parent[a] = b;
+            }
+        }));
+    });
+
+    let mut clusters: HashMap<usize, Vec<usize>> = HashMap::new();
+    (0..pixels.len()).for_each(|k| clusters.entry(find(&mut parent, k)).or_default().push(k));
+    let mut loops: Vec<Loop> = clusters.into_values().map(|members| {
+        let summit = *members.iter().max_by(|a, b|
+            pixels[**a].0.enrichment[0].partial_cmp(&pixels[**b].0.enrichment[0]).unwrap()
+        ).unwrap();
+        let (p, q) = &pixels[summit];
+        Loop {
+            bin1: p.bin1,
+            bin2: p.bin2,
+            observed: p.observed,
+            expected: p.expected,
+            qvalue: *q,
+            num_pixels: members.len(),
+        }
+    }).collect();
+    loops.sort_by_key(|l| (l.bin1, l.bin2));
+    loops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernels() {
+        let k = kernels(1, 3);
+        assert_eq!(k[0].len(), 49 - 9 - 4 * 2);
+        assert_eq!(k[1].len(), 9 - 1);
+        assert_eq!(k[2].len(), 3 * 4);
+        assert_eq!(k[3].len(), 3 * 4);
+    }
+
+    #[test]
+    fn test_lambda_chunk() {
+        let base = 2f64.powf(1.0 / 3.0);
+        assert_eq!(lambda_chunk(0.5, base), 0);
+        assert_eq!(lambda_chunk(2.0, base), 3);
+        assert_eq!(lambda_chunk(2.1, base), 4);
+    }
+}
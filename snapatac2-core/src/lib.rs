@@ -4,4 +4,7 @@ pub mod knn;
 pub mod network;
 pub mod motif;
 pub mod export;
-pub mod embedding;
\ No newline at end of file
+pub mod embedding;
+pub mod liftover;
+pub mod hic;
+pub mod cnv;
@@ -0,0 +1,437 @@
+//! # Liftover
+//!
+//! Convert genomic coordinates between assemblies using UCSC chain files.
+//! A record is lifted by projecting it onto the ungapped blocks of the chains
+//! overlapping it. Following the UCSC `liftOver` tool, the mapped pieces from the
+//! best chain are merged into a single region, and the record is considered
+//! unmapped if less than `min_match` of its bases can be mapped.
+//! Records whose bases are mapped by more than one chain are reported as split.
+use crate::preprocessing::{
+    count_data::{make_arraydata, update_fragment_qc, SnapData, ChromSizes, GenomeBaseIndex},
+    qc::Fragment,
+};
+
+use anndata::{AnnDataOp, AxisArraysOp, ElemCollectionOp};
+use anyhow::{bail, Context, Result};
+use bed_utils::bed::{tree::BedTree, BEDLike, GenomicRange, NarrowPeak, Strand};
+use indicatif::{ProgressIterator, ProgressStyle};
+use log::info;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{collections::{HashMap, HashSet}, io::BufRead};
+
+/// The result of lifting a record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LiftResult<T> {
+    Mapped(T),
+    /// The record is mapped by multiple chains. Each piece is returned separately.
+    Split(Vec<T>),
+    Unmapped,
+}
+
+impl<T> LiftResult<T> {
+    pub fn mapped(self) -> Option<T> {
+        match self {
+            LiftResult::Mapped(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Summary of a liftover run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LiftOverStat {
+    pub mapped: u64,
+    pub split: u64,
+    pub unmapped: u64,
+}
+
+/// An ungapped alignment block.
+#[derive(Debug, Clone)]
+struct Block {
+    chain: usize,
+    source_start: u64,
+    target_chrom: usize,
+    /// Start position on the target strand of the chain.
+    target_start: u64,
+    target_size: u64,
+    is_reverse: bool,
+}
+
+/// A mapped interval on the target assembly.
+#[derive(Debug, Clone, PartialEq)]
+struct Mapped {
+    chrom: String,
+    start: u64,
+    end: u64,
+    is_reverse: bool,
+}
+
+/// Coordinate converter built from a UCSC chain file.
+pub struct LiftOver {
+    blocks: BedTree<usize>,
+    block_data: Vec<Block>,
+    target_chroms: Vec<String>,
+    target_sizes: ChromSizes,
+    min_match: f64,
+}
+
+impl LiftOver {
+    /// Read a chain file.
+    pub fn read_chain<R: BufRead>(reader: R) -> Result<Self> {
+        let mut block_data = Vec::new();
+        let mut regions = Vec::new();
+        let mut target_chroms: Vec<String> = Vec::new();
+        let mut target_sizes: Vec<(String, u64)> = Vec::new();
+
+        let mut n_chain = 0;
+        let mut current: Option<(String, u64, u64, usize, u64, bool)> = None;
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            if fields[0] == "chain" {
+                if fields.len() < 12 {
+                    bail!("invalid chain header: {}", line);
+                }
+                let parse = |i: usize| -> Result<u64> {
+                    fields[i].parse().with_context(|| format!("invalid chain header: {}", line))
+                };
+                if fields[4] != "+" {
+                    bail!("the reference strand must be '+': {}", line);
+                }
+                let target_chrom = fields[7].to_string();
+                let target_idx = match target_chroms.iter().position(|x| *x == target_chrom) {
+                    Some(i) => i,
+                    None => {
+                        target_chroms.push(target_chrom.clone());
+                        target_sizes.push((target_chrom, parse(8)?));
+                        target_chroms.len() - 1
+                    }
+                };
+                current = Some((
+                    fields[2].to_string(), parse(5)?, parse(10)?,
+                    target_idx, parse(8)?, fields[9] == "-",
+                ));
+                n_chain += 1;
+            } else {
+                let (source_chrom, source_pos, target_pos, target_chrom, target_size, is_reverse) =
+                    current.as_mut().with_context(|| format!("alignment data without chain header: {}", line))?;
+                let size: u64 = fields[0].parse()?;
+                regions.push((
+                    GenomicRange::new(source_chrom.as_str(), *source_pos, *source_pos + size),
+                    block_data.len(),
+                ));
+                block_data.push(Block {
+                    chain: n_chain - 1,
+                    source_start: *source_pos,
+                    target_chrom: *target_chrom,
+                    target_start: *target_pos,
+                    target_size: *target_size,
+                    is_reverse: *is_reverse,
+                });
+                if fields.len() >= 3 {
+                    *source_pos += size + fields[1].parse::<u64>()?;
+                    *target_pos += size + fields[2].parse::<u64>()?;
+                } else {
+                    current = None;
+                }
+            }
+        }
+
+        Ok(Self {
+            blocks: regions.into_iter().collect(),
+            block_data,
+            target_chroms,
+            target_sizes: target_sizes.into_iter().collect(),
+            min_match: 0.95,
+        })
+    }
+
+    /// Set the minimum fraction of bases that must be mapped. Default: 0.95.
+    pub fn with_min_match(mut self, min_match: f64) -> Self {
+        self.min_match = min_match;
+        self
+    }
+
+    /// Chromosome sizes of the target assembly, as declared in the chain file.
+    pub fn target_chrom_sizes(&self) -> &ChromSizes {
+        &self.target_sizes
+    }
+
+    fn map_interval<B: BEDLike>(&self, bed: &B) -> LiftResult<Mapped> {
+        let start = bed.start();
+        let end = bed.end();
+        let len = end - start;
+        if len == 0 {
+            return LiftResult::Unmapped;
+        }
+
+        let mut by_chain: HashMap<usize, (Mapped, u64)> = HashMap::new();
+        let query = GenomicRange::new(bed.chrom(), start, end);
+        self.blocks.find(&query).for_each(|(region, i)| {
+            let block = &self.block_data[*i];
+            let s = start.max(region.start());
+            let e = end.min(region.end());
+            let offset = s - block.source_start;
+            let (ts, te) = if block.is_reverse {
+                let te = block.target_size - (block.target_start + offset);
+                (te - (e - s), te)
+            } else {
+                let ts = block.target_start + offset;
+                (ts, ts + (e - s))
+            };
+            by_chain.entry(block.chain)
+                .and_modify(|(m, n)| {
+                    m.start = m.start.min(ts);
+                    m.end = m.end.max(te);
+                    *n += e - s;
+                })
+                .or_insert_with(|| (
+                    Mapped {
+                        chrom: self.target_chroms[block.target_chrom].clone(),
+                        start: ts,
+                        end: te,
+                        is_reverse: block.is_reverse,
+                    },
+                    e - s,
+                ));
+        });
+
+        let mut pieces: Vec<_> = by_chain.into_iter().map(|(_, x)| x).collect();
+        let total: u64 = pieces.iter().map(|x| x.1).sum();
+        if pieces.is_empty() || (total as f64) < self.min_match * len as f64 {
+            LiftResult::Unmapped
+        } else if pieces.len() == 1 {
+            LiftResult::Mapped(pieces.pop().unwrap().0)
+        } else {
+            pieces.sort_by(|a, b| b.1.cmp(&a.1));
+            LiftResult::Split(pieces.into_iter().map(|x| x.0).collect())
+        }
+    }
+
+    /// Lift a genomic range.
+    pub fn lift_range<B: BEDLike>(&self, bed: &B) -> LiftResult<GenomicRange> {
+        let to_range = |x: Mapped| GenomicRange::new(x.chrom, x.start, x.end);
+        match self.map_interval(bed) {
+            LiftResult::Mapped(x) => LiftResult::Mapped(to_range(x)),
+            LiftResult::Split(xs) => LiftResult::Split(xs.into_iter().map(to_range).collect()),
+            LiftResult::Unmapped => LiftResult::Unmapped,
+        }
+    }
+
+    /// Lift a fragment. The strand of single-end reads is flipped if they are
+    /// mapped to the reverse strand.
+    pub fn lift_fragment(&self, fragment: &Fragment) -> LiftResult<Fragment> {
+        let to_fragment = |x: Mapped| Fragment {
+            chrom: x.chrom,
+            start: x.start,
+            end: x.end,
+            barcode: fragment.barcode.clone(),
+            count: fragment.count,
+            strand: fragment.strand.map(|s| flip_strand(s, x.is_reverse)),
+        };
+        match self.map_interval(fragment) {
+            LiftResult::Mapped(x) => LiftResult::Mapped(to_fragment(x)),
+            LiftResult::Split(xs) => LiftResult::Split(xs.into_iter().map(to_fragment).collect()),
+            LiftResult::Unmapped => LiftResult::Unmapped,
+        }
+    }
+
+    /// Lift a peak. The summit is lifted separately, and is placed at the
+    /// center of the new peak if it cannot be mapped.
+    pub fn lift_narrow_peak(&self, peak: &NarrowPeak) -> LiftResult<NarrowPeak> {
+        let summit = peak.start() + peak.peak;
+        let lifted_summit = self.map_interval(
+            &GenomicRange::new(peak.chrom(), summit, summit + 1)
+        ).mapped();
+        let to_peak = |x: Mapped| {
+            let mut new_peak = peak.clone();
+            new_peak.chrom = x.chrom.clone();
+            new_peak.start = x.start;
+            new_peak.end = x.end;
+            new_peak.strand = peak.strand.map(|s| flip_strand(s, x.is_reverse));
+            new_peak.peak = match &lifted_summit {
+                Some(s) if s.chrom == x.chrom && s.start >= x.start && s.start < x.end =>
+                    s.start - x.start,
+                _ => (x.end - x.start) / 2,
+            };
+            new_peak
+        };
+        match self.map_interval(peak) {
+            LiftResult::Mapped(x) => LiftResult::Mapped(to_peak(x)),
+            LiftResult::Split(xs) => LiftResult::Split(xs.into_iter().map(to_peak).collect()),
+            LiftResult::Unmapped => LiftResult::Unmapped,
+        }
+    }
+}
+
+fn flip_strand(strand: Strand, is_reverse: bool) -> Strand {
+    match (strand, is_reverse) {
+        (Strand::Forward, true) => Strand::Reverse,
+        (Strand::Reverse, true) => Strand::Forward,
+        (s, _) => s,
+    }
+}
+
+/// Lift the fragments stored in `.obsm` to a new assembly and save them in `out`.
+///
+/// Fragments that are unmapped, split, or fall outside of `chrom_sizes` are discarded.
+/// `.obs_names` are copied, and `.uns["reference_sequences"]` is set to `chrom_sizes`.
+/// If `chrom_sizes` is not provided, the target chromosome sizes declared in the chain file are used.
+/// As in `subset_fragments`, the `n_fragment` and `frac_mito` columns of `.obs` are
+/// recomputed on the lifted fragments, other fragment-derived QC metrics are dropped,
+/// and the remaining columns are copied unchanged.
+pub fn liftover_fragments<A, O>(
+    adata: &A,
+    out: &O,
+    liftover: &LiftOver,
+    chrom_sizes: Option<&ChromSizes>,
+    mitochrondrial_dna: &HashSet<String>,
+    chunk_size: usize,
+) -> Result<LiftOverStat>
+where
+    A: SnapData,
+    O: AnnDataOp,
+{
+    let chrom_sizes = chrom_sizes.unwrap_or(liftover.target_chrom_sizes());
+    let genome_index = GenomeBaseIndex::new(chrom_sizes);
+
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})"
+    ).unwrap();
+    let is_paired = adata.fragment_is_paired()?;
+    let raw = adata.get_count_iter(chunk_size)?.into_raw();
+    let obsm_key = if is_paired { "fragment_paired" } else { "fragment_single" };
+
+    let mut stat = LiftOverStat::default();
+    let mut qc = Vec::new();
+    let arrays = raw.progress_with_style(style).map(|(cells, _, _)| {
+        let lifted: Vec<_> = cells.into_par_iter().map(|fragments| {
+            let mut s = LiftOverStat::default();
+            let fragments: Vec<_> = fragments.iter().filter_map(|f| match liftover.lift_fragment(f) {
+                LiftResult::Mapped(x) => if chrom_sizes.get(x.chrom()).map_or(false, |n| x.end() <= n) {
+                    s.mapped += 1;
+                    Some(x)
+                } else {
+                    s.unmapped += 1;
+                    None
+                },
+                LiftResult::Split(_) => {
+                    s.split += 1;
+                    None
+                },
+                LiftResult::Unmapped => {
+                    s.unmapped += 1;
+                    None
+                },
+            }).collect();
+            (fragments, s)
+        }).collect();
+        let cells = lifted.into_iter().map(|(x, s)| {
+            stat.mapped += s.mapped;
+            stat.split += s.split;
+            stat.unmapped += s.unmapped;
+            x
+        }).collect::<Vec<_>>();
+
+        if is_paired {
+            make_arraydata::<u32>(cells, mitochrondrial_dna, &genome_index, &mut qc)
+        } else {
+            make_arraydata::<i32>(cells, mitochrondrial_dna, &genome_index, &mut qc)
+        }
+    });
+    out.obsm().add_iter(obsm_key, arrays)?;
+
+    let mut obs = adata.read_obs()?;
+    update_fragment_qc(&mut obs, &qc)?;
+    out.set_obs_names(adata.obs_names())?;
+    out.set_obs(obs)?;
+    out.uns().add("reference_sequences", chrom_sizes.to_dataframe())?;
+    info!(
+        "Lifted {} fragments; {} fragments were split and {} fragments were unmapped.",
+        stat.mapped, stat.split, stat.unmapped,
+    );
+    Ok(stat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anndata::{AnnData, data::{array::utils::{from_csr_data, to_csr_data}, CsrNonCanonical}};
+    use anndata_hdf5::H5;
+    use polars::prelude::{DataFrame, NamedFrom, Series};
+    use std::str::FromStr;
+
+    const CHAIN: &str = "chain 1000 chr1 1000 + 100 300 chrA 500 + 0 210 1\n\
+                         100 10 20\n\
+                         90\n\
+                         \n\
+                         chain 500 chr1 1000 + 400 500 chrB 300 - 50 150 2\n\
+                         100\n";
+
+    #[test]
+    fn test_liftover() {
+        let liftover = LiftOver::read_chain(CHAIN.as_bytes()).unwrap();
+
+        let lift = |x: &str| liftover.lift_range(&GenomicRange::from_str(x).unwrap());
+        assert_eq!(lift("chr1:110-150"), LiftResult::Mapped(GenomicRange::from_str("chrA:10-50").unwrap()));
+        assert_eq!(lift("chr1:220-250"), LiftResult::Mapped(GenomicRange::from_str("chrA:130-160").unwrap()));
+        assert_eq!(lift("chr1:195-215"), LiftResult::Unmapped);
+        assert_eq!(lift("chr1:410-420"), LiftResult::Mapped(GenomicRange::from_str("chrB:230-240").unwrap()));
+        assert_eq!(lift("chr1:0-50"), LiftResult::Unmapped);
+
+        let fragment = Fragment {
+            chrom: "chr1".to_string(),
+            start: 410,
+            end: 420,
+            barcode: None,
+            count: 1,
+            strand: Some(Strand::Forward),
+        };
+        let lifted = liftover.lift_fragment(&fragment).mapped().unwrap();
+        assert_eq!(lifted.strand, Some(Strand::Reverse));
+    }
+
+    #[test]
+    fn test_liftover_fragments() {
+        let liftover = LiftOver::read_chain(CHAIN.as_bytes()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let adata = AnnData::<H5>::new(dir.path().join("input.h5ad")).unwrap();
+        let chrom_sizes: ChromSizes = [("chr1", 1000)].into_iter().collect();
+        let genome_index = GenomeBaseIndex::new(&chrom_sizes);
+        // The second cell has an unmapped fragment.
+        let counts = vec![
+            vec![(genome_index.get_position_rev("chr1", 110), 40u32)],
+            vec![
+                (genome_index.get_position_rev("chr1", 195), 20u32),
+                (genome_index.get_position_rev("chr1", 410), 10u32),
+            ],
+        ];
+        let (r, c, offset, ind, data) = to_csr_data(counts, genome_index.len());
+        let mat: CsrNonCanonical<u32> = from_csr_data(r, c, offset, ind, data).unwrap().try_into().unwrap();
+        adata.set_obs_names(vec!["a".to_string(), "b".to_string()].into()).unwrap();
+        adata.obsm().add("fragment_paired", mat).unwrap();
+        adata.uns().add("reference_sequences", chrom_sizes.to_dataframe()).unwrap();
+        adata.set_obs(DataFrame::new(vec![
+            Series::new("n_fragment", vec![1u64, 2]),
+            Series::new("tsse", vec![5.0, 6.0]),
+            Series::new("sample", vec!["x", "y"]),
+        ]).unwrap()).unwrap();
+
+        let out = AnnData::<H5>::new(dir.path().join("output.h5ad")).unwrap();
+        let stat = liftover_fragments(&adata, &out, &liftover, None, &HashSet::new(), 1).unwrap();
+        assert_eq!((stat.mapped, stat.split, stat.unmapped), (2, 0, 1));
+
+        let obs = out.read_obs().unwrap();
+        let n_fragment: Vec<_> = obs.column("n_fragment").unwrap()
+            .u64().unwrap().into_iter().flatten().collect();
+        assert_eq!(n_fragment, vec![1, 1]);
+        assert!(obs.column("tsse").is_err());
+        assert!(obs.column("frac_mito").is_ok());
+        let sample: Vec<_> = obs.column("sample").unwrap()
+            .utf8().unwrap().into_iter().flatten().collect();
+        assert_eq!(sample, vec!["x", "y"]);
+    }
+}
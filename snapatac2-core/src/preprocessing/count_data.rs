@@ -1,28 +1,62 @@
 mod import;
 mod coverage;
+mod contact;
 mod genome;
+mod chrom_sizes;
 mod matrix;
+mod fragment_file;
+mod concat;
+mod subset;
 
 pub use crate::preprocessing::qc;
 pub use import::{import_fragments, import_contacts};
-pub use coverage::{GenomeCoverage, ContactMap, CoverageType, fragments_to_insertions};
+pub use coverage::{GenomeCoverage, ContactMap, CoverageType, CountingStrategy, fragments_to_insertions};
+pub use contact::{ContactIndex, ContactLayout};
 pub use genome::{
     Transcript, Promoters, FeatureCounter, TranscriptCount, GeneCount,
+    GeneScore, GeneScoreModel, GeneScoreOptions, GenomePartition,
     read_transcripts_from_gff, read_transcripts_from_gtf,
     ChromSizes, ChromValueIter, ChromValues, GenomeBaseIndex, 
 };
-pub use matrix::{create_gene_matrix, create_tile_matrix, create_peak_matrix};
+pub use chrom_sizes::ContigType;
+pub use matrix::{
+    create_gene_matrix, create_tile_matrix, create_tile_matrices, create_partition_matrix,
+    create_peak_matrix, GeneQuantification,
+};
+pub use fragment_file::{FragmentFileData, FragmentOrder};
+pub use concat::concat_dataset;
+pub use subset::subset_fragments;
+pub(crate) use subset::{make_arraydata, update_fragment_qc};
 
 use anndata::{container::{ChunkedArrayElem, StackedChunkedArrayElem}, ArrayElemOp};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use bed_utils::bed::{tree::BedTree, GenomicRange};
-use anndata::{AnnDataOp, ElemCollectionOp, AxisArraysOp, AnnDataSet, Backend, AnnData};
+use anndata::{AnnDataOp, ElemCollectionOp, AxisArraysOp, AnnDataSet, Backend, AnnData, data::utils::to_csr_data};
 use ndarray::Array2;
 use polars::frame::DataFrame;
 use nalgebra_sparse::CsrMatrix;
-use anyhow::{Result, Context, bail};
+use anyhow::{Result, Context, bail, ensure};
 use num::integer::div_ceil;
-use std::str::FromStr;
+use itertools::Itertools;
+use std::{collections::{HashMap, HashSet}, str::FromStr};
+
+/// How insertion profiles around TSSs are reported by `SnapData::tss_enrichment`.
+#[derive(Debug, Clone, Copy)]
+pub enum TssProfileMode<'a> {
+    /// One profile per cell.
+    Cell,
+    /// Profiles summed by the group labels of cells.
+    Group(&'a [&'a str]),
+}
+
+#[derive(Debug, Clone)]
+pub struct TssEnrichment {
+    pub scores: Vec<f64>,
+    /// Insertion profiles around TSSs, with `2 * window + 1` columns. Rows
+    /// correspond to cells, or to `groups` if profiles are summed by groups.
+    pub profile: Option<CsrMatrix<u32>>,
+    pub groups: Option<Vec<String>>,
+}
 
 /// The `SnapData` trait represents an interface for reading and
 /// manipulating single-cell assay data. It extends the `AnnDataOp` trait,
@@ -43,10 +77,32 @@ pub trait SnapData: AnnDataOp {
         Ok(res)
     }
 
+    /// Whether the fragments are paired-end, i.e., stored in `.obsm["fragment_paired"]`
+    /// rather than `.obsm["fragment_single"]`.
+    fn fragment_is_paired(&self) -> Result<bool> {
+        let keys = self.obsm().keys();
+        if keys.iter().any(|x| x == "fragment_single") {
+            Ok(false)
+        } else if keys.iter().any(|x| x == "fragment_paired") {
+            Ok(true)
+        } else {
+            bail!("neither 'fragment_single' nor 'fragment_paired' is present in the '.obsm'")
+        }
+    }
+
     /// Read insertion counts stored in the `.obsm` matrix.
     fn get_count_iter(&self, chunk_size: usize) ->
         Result<GenomeCoverage<Box<dyn ExactSizeIterator<Item = (CoverageType, usize, usize)>>>>;
 
+    /// Return the storage layout of the contact matrices.
+    /// Data without the `.uns["contact_layout"]` entry use the legacy `Full` layout.
+    fn read_contact_layout(&self) -> Result<ContactLayout> {
+        match self.uns().get_item::<DataFrame>("contact_layout")? {
+            None => Ok(ContactLayout::Full),
+            Some(df) => ContactLayout::from_dataframe(&df),
+        }
+    }
+
     fn contact_count_iter(&self, chunk_size: usize) -> Result<ContactMap<Self::CountIter>>;
 
     /// Read counts stored in the `X` matrix.
@@ -70,18 +126,115 @@ pub trait SnapData: AnnDataOp {
 
     /// QC metrics for the data.
 
-    /// Compute TSS enrichment.
-    fn tss_enrichment(&self, promoter: &BedTree<bool>) -> Result<Vec<f64>> {
-        Ok(self.get_count_iter(2000)?.into_raw().flat_map(|(fragments, _, _)| {
-            fragments.into_par_iter()
-                .map(|x| qc::tss_enrichment(x.into_iter(), promoter))
-                .collect::<Vec<_>>()
+    /// Compute TSS enrichment scores, and optionally the insertion profiles around TSSs.
+    /// `promoter` must be created by `qc::make_promoter_map` with `opts.window`.
+    fn tss_enrichment(
+        &self,
+        promoter: &BedTree<bool>,
+        opts: &qc::TssEnrichmentOptions,
+        profile: Option<TssProfileMode>,
+    ) -> Result<TssEnrichment> {
+        let n_pos = 2 * opts.window as usize + 1;
+        let (labels, groups) = match profile {
+            Some(TssProfileMode::Group(group_by)) => {
+                let groups: Vec<String> = group_by.iter().unique().sorted().map(|x| x.to_string()).collect();
+                let idx: HashMap<&str, usize> = groups.iter().enumerate().map(|(i, x)| (x.as_str(), i)).collect();
+                (Some(group_by.iter().map(|x| idx[x]).collect::<Vec<_>>()), Some(groups))
+            },
+            _ => (None, None),
+        };
+        let mut group_profile = vec![vec![0u64; n_pos]; groups.as_ref().map_or(0, |x| x.len())];
+        let mut cell_profile = Vec::new();
+        let mut signals = Vec::new();
+        self.get_count_iter(2000)?.into_raw().try_for_each(|(fragments, start, end)| {
+            let profiles: Vec<_> = fragments.into_par_iter()
+                .map(|x| qc::tss_profile(x.into_iter(), promoter, opts.window))
+                .collect();
+            if let Some(labels) = labels.as_ref() {
+                ensure!(end <= labels.len(), "the length of group_by is smaller than the number of cells");
+                profiles.iter().zip(start..end).for_each(|(p, i)|
+                    group_profile[labels[i]].iter_mut().zip(p).for_each(|(a, b)| *a += *b)
+                );
+            }
+            profiles.into_iter().for_each(|p| {
+                signals.push(qc::tss_signal(&p, opts));
+                if let Some(TssProfileMode::Cell) = profile {
+                    cell_profile.push(p.into_iter().enumerate()
+                        .filter(|(_, x)| *x > 0).map(|(i, x)| (i, x as u32)).collect::<Vec<_>>());
+                }
+            });
+            Ok(())
+        })?;
+
+        // Signac replaces zero backgrounds with the mean background of all cells.
+        if opts.method == qc::TssEnrichmentMethod::Signac {
+            let mean = signals.iter().map(|x| x.1).sum::<f64>() / signals.len() as f64;
+            signals.iter_mut().filter(|x| x.1 == 0.0).for_each(|x| x.1 = mean);
+        }
+        let scores = signals.into_iter().map(|(s, b)| s / (b + opts.pseudocount)).collect();
+        let profile = match profile {
+            None => None,
+            Some(TssProfileMode::Cell) => {
+                let (r, c, offset, ind, data) = to_csr_data(cell_profile, n_pos);
+                Some(CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap())
+            },
+            Some(TssProfileMode::Group(_)) => {
+                let rows = group_profile.into_iter().map(|p| p.into_iter().enumerate()
+                    .filter(|(_, x)| *x > 0).map(|(i, x)| (i, x as u32)).collect::<Vec<_>>()
+                ).collect();
+                let (r, c, offset, ind, data) = to_csr_data(rows, n_pos);
+                Some(CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap())
+            },
+        };
+        Ok(TssEnrichment { scores, profile, groups })
+    }
+
+    /// Compute QC metrics of chromatin contacts stored in `.obsm["contact"]`.
+    /// See `qc::ContactSummary` for the meaning of the arguments.
+    fn contact_qc(
+        &self,
+        mitochondrial_dna: &HashSet<String>,
+        short_range: u64,
+        long_range: u64,
+        distance_bins: &[u64],
+    ) -> Result<Vec<qc::ContactQC>> {
+        Ok(self.contact_count_iter(2000)?.into_raw().flat_map(|(contacts, _, _)| {
+            contacts.into_par_iter().map(|x| {
+                let mut summary = qc::ContactSummary::new(
+                    mitochondrial_dna, short_range, long_range, distance_bins
+                );
+                x.iter().for_each(|c| summary.update(c));
+                summary.get_qc()
+            }).collect::<Vec<_>>()
         }).collect())
     }
 
     /// Compute the fragment size distribution.
     fn fragment_size_distribution(&self, max_size: usize) -> Result<Vec<usize>>;
 
+    /// Compute fragment size histograms and nucleosome banding metrics of each cell.
+    /// Histograms are returned as a cell-by-bin matrix. See `qc::fragment_size_qc`.
+    fn fragment_size_qc(
+        &self,
+        max_size: usize,
+        bin_size: usize,
+    ) -> Result<(Vec<qc::FragmentSizeQC>, CsrMatrix<u32>)> {
+        ensure!(bin_size > 0, "bin_size must be positive");
+        let mut result = Vec::with_capacity(self.n_obs());
+        for (fragments, _, _) in self.get_count_iter(2000)?.into_raw() {
+            ensure!(
+                fragments.iter().flatten().all(|f| f.strand.is_none()),
+                "fragment sizes are not available for single-end data",
+            );
+            result.extend(fragments.into_par_iter().map(|x|
+                qc::fragment_size_qc(x.into_iter().map(|f| f.end - f.start), max_size, bin_size)
+            ).collect::<Vec<_>>());
+        }
+        let histograms = result.iter_mut().map(|x| std::mem::take(&mut x.histogram)).collect();
+        let (r, c, offset, ind, data) = to_csr_data(histograms, 1 + div_ceil(max_size, bin_size));
+        Ok((result, CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()))
+    }
+
     /// Compute the fraction of reads in each region.
     fn frip<D>(&self, regions: &Vec<BedTree<D>>) -> Result<Array2<f64>> {
         let vec = qc::fraction_of_reads_in_region(self.get_count_iter(2000)?.into_raw(), regions)
@@ -111,6 +264,7 @@ impl<B: Backend> SnapData for AnnData<B> {
     fn contact_count_iter(&self, chunk_size: usize) -> Result<ContactMap<Self::CountIter>> {
         Ok(ContactMap::new(
             self.read_chrom_sizes()?,
+            self.read_contact_layout()?,
             self.obsm().get_item_iter("contact", chunk_size).unwrap(),
         ))
     }
@@ -127,6 +281,10 @@ impl<B: Backend> SnapData for AnnData<B> {
 impl<B: Backend> SnapData for AnnDataSet<B> {
     type CountIter = StackedChunkedArrayElem<B, CsrMatrix<u8>>;
 
+    fn fragment_is_paired(&self) -> Result<bool> {
+        self.adatas().inner().iter().next().context("the AnnDataSet is empty")?.1.fragment_is_paired()
+    }
+
     fn get_count_iter(&self, chunk_size: usize) ->
         Result<GenomeCoverage<Box<dyn ExactSizeIterator<Item = (CoverageType, usize, usize)>>>>
     {
@@ -146,6 +304,7 @@ impl<B: Backend> SnapData for AnnDataSet<B> {
     fn contact_count_iter(&self, chunk_size: usize) -> Result<ContactMap<Self::CountIter>> {
         Ok(ContactMap::new(
             self.read_chrom_sizes()?,
+            self.read_contact_layout()?,
             self.adatas()
                 .inner()
                 .get_obsm()
@@ -0,0 +1,238 @@
+//! # Chromosome Sizes
+//!
+//! Constructors of `ChromSizes` from common file formats, i.e., FASTA indices (`.fai`),
+//! `.chrom.sizes` files, UCSC `.2bit` headers and BAM/CRAM headers, as well as built-in
+//! tables for commonly used assemblies.
+use crate::preprocessing::count_data::ChromSizes;
+
+use anyhow::{bail, ensure, Context, Result};
+use noodles::{bam, cram, sam};
+use std::{
+    io::{BufRead, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+const HG38: [(&str, u64); 25] = [
+    ("chr1", 248956422), ("chr2", 242193529), ("chr3", 198295559), ("chr4", 190214555),
+    ("chr5", 181538259), ("chr6", 170805979), ("chr7", 159345973), ("chr8", 145138636),
+    ("chr9", 138394717), ("chr10", 133797422), ("chr11", 135086622), ("chr12", 133275309),
+    ("chr13", 114364328), ("chr14", 107043718), ("chr15", 101991189), ("chr16", 90338345),
+    ("chr17", 83257441), ("chr18", 80373285), ("chr19", 58617616), ("chr20", 64444167),
+    ("chr21", 46709983), ("chr22", 50818468), ("chrX", 156040895), ("chrY", 57227415),
+    ("chrM", 16569),
+];
+
+const HG19: [(&str, u64); 25] = [
+    ("chr1", 249250621), ("chr2", 243199373), ("chr3", 198022430), ("chr4", 191154276),
+    ("chr5", 180915260), ("chr6", 171115067), ("chr7", 159138663), ("chr8", 146364022),
+    ("chr9", 141213431), ("chr10", 135534747), ("chr11", 135006516), ("chr12", 133851895),
+    ("chr13", 115169878), ("chr14", 107349540), ("chr15", 102531392), ("chr16", 90354753),
+    ("chr17", 81195210), ("chr18", 78077248), ("chr19", 59128983), ("chr20", 63025520),
+    ("chr21", 48129895), ("chr22", 51304566), ("chrX", 155270560), ("chrY", 59373566),
+    ("chrM", 16571),
+];
+
+const MM10: [(&str, u64); 22] = [
+    ("chr1", 195471971), ("chr2", 182113224), ("chr3", 160039680), ("chr4", 156508116),
+    ("chr5", 151834684), ("chr6", 149736546), ("chr7", 145441459), ("chr8", 129401213),
+    ("chr9", 124595110), ("chr10", 130694993), ("chr11", 122082543), ("chr12", 120129022),
+    ("chr13", 120421639), ("chr14", 124902244), ("chr15", 104043685), ("chr16", 98207768),
+    ("chr17", 94987271), ("chr18", 90702639), ("chr19", 61431566), ("chrX", 171031299),
+    ("chrY", 91744698), ("chrM", 16299),
+];
+
+const MM39: [(&str, u64); 22] = [
+    ("chr1", 195154279), ("chr2", 181755017), ("chr3", 159745316), ("chr4", 156860686),
+    ("chr5", 151758149), ("chr6", 149588044), ("chr7", 144995196), ("chr8", 130127694),
+    ("chr9", 124359700), ("chr10", 130530862), ("chr11", 121973369), ("chr12", 120092757),
+    ("chr13", 120883175), ("chr14", 125139656), ("chr15", 104073951), ("chr16", 98008968),
+    ("chr17", 95294699), ("chr18", 90720763), ("chr19", 61420004), ("chrX", 169476592),
+    ("chrY", 91455967), ("chrM", 16299),
+];
+
+const TWOBIT_SIGNATURE: u32 = 0x1A412743;
+
+/// Categories of non-primary contigs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContigType {
+    Primary,
+    /// Alternate haplotypes and patches, e.g., `chr6_GL000250v2_alt`, `chr1_KN196472v1_fix`.
+    Alt,
+    /// Unlocalized contigs, e.g., `chr1_KI270706v1_random`.
+    Random,
+    /// Unplaced contigs and decoys, e.g., `chrUn_KI270302v1`, `GL000220.1`, `chrEBV`.
+    Unplaced,
+}
+
+impl ContigType {
+    pub fn from_name(name: &str) -> Self {
+        let name_ = name.strip_prefix("chr").unwrap_or(name);
+        if name_.ends_with("_alt") || name_.ends_with("_fix") || name_.contains("_hap") {
+            ContigType::Alt
+        } else if name_.ends_with("_random") {
+            ContigType::Random
+        } else if name_.starts_with("Un") || name_.ends_with("_decoy") || name_ == "EBV" ||
+            name_.starts_with("GL") || name_.starts_with("KI") || name_.starts_with("JH")
+        {
+            ContigType::Unplaced
+        } else {
+            ContigType::Primary
+        }
+    }
+}
+
+impl ChromSizes {
+    /// Built-in chromosome sizes of the primary assembly (including chrM).
+    /// Supported assemblies: hg38 (GRCh38), hg19 (GRCh37), mm10 (GRCm38), mm39 (GRCm39).
+    pub fn from_assembly(assembly: &str) -> Result<Self> {
+        let sizes: &[(&str, u64)] = match assembly {
+            "hg38" | "GRCh38" => &HG38,
+            "hg19" | "GRCh37" => &HG19,
+            "mm10" | "GRCm38" => &MM10,
+            "mm39" | "GRCm39" => &MM39,
+            _ => bail!("unknown assembly: {}", assembly),
+        };
+        Ok(sizes.iter().map(|(k, v)| (*k, *v)).collect())
+    }
+
+    /// Read a tab-delimited file whose first two columns are chromosome names and sizes.
+    /// This handles both `.chrom.sizes` files and FASTA indices (`.fai`).
+    pub fn read_chrom_sizes<R: BufRead>(reader: R) -> Result<Self> {
+        reader.lines().filter(|line| line.as_ref().map_or(true, |x|
+            !x.trim().is_empty() && !x.starts_with('#')
+        )).map(|line| {
+            let line = line?;
+            let mut fields = line.split('\t');
+            let chrom = fields.next().unwrap().to_string();
+            let size = fields.next()
+                .with_context(|| format!("missing chromosome size: {}", line))?
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid chromosome size: {}", line))?;
+            Ok((chrom, size))
+        }).collect()
+    }
+
+    /// Read chromosome sizes from a FASTA index (`.fai`).
+    pub fn read_fai<R: BufRead>(reader: R) -> Result<Self> {
+        Self::read_chrom_sizes(reader)
+    }
+
+    /// Read chromosome sizes from the header of a UCSC `.2bit` file.
+    pub fn read_2bit<R: Read + Seek>(mut reader: R) -> Result<Self> {
+        fn read_u32<R: Read>(reader: &mut R, is_le: bool) -> Result<u32> {
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf)?;
+            Ok(if is_le { u32::from_le_bytes(buf) } else { u32::from_be_bytes(buf) })
+        }
+
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf)?;
+        let is_le = if u32::from_le_bytes(buf) == TWOBIT_SIGNATURE {
+            true
+        } else if u32::from_be_bytes(buf) == TWOBIT_SIGNATURE {
+            false
+        } else {
+            bail!("not a 2bit file");
+        };
+        let version = read_u32(&mut reader, is_le)?;
+        ensure!(version <= 1, "unsupported 2bit version: {}", version);
+        let seq_count = read_u32(&mut reader, is_le)?;
+        let _reserved = read_u32(&mut reader, is_le)?;
+
+        let mut index = Vec::with_capacity(seq_count as usize);
+        for _ in 0..seq_count {
+            let mut name_size = [0; 1];
+            reader.read_exact(&mut name_size)?;
+            let mut name = vec![0; name_size[0] as usize];
+            reader.read_exact(&mut name)?;
+            let offset = if version == 0 {
+                read_u32(&mut reader, is_le)? as u64
+            } else {
+                let mut buf = [0; 8];
+                reader.read_exact(&mut buf)?;
+                if is_le { u64::from_le_bytes(buf) } else { u64::from_be_bytes(buf) }
+            };
+            index.push((String::from_utf8(name)?, offset));
+        }
+
+        index.into_iter().map(|(name, offset)| {
+            reader.seek(SeekFrom::Start(offset))?;
+            let size = read_u32(&mut reader, is_le)? as u64;
+            Ok((name, size))
+        }).collect()
+    }
+
+    /// Read chromosome sizes from the reference sequence dictionary of a SAM header.
+    pub fn from_sam_header(header: &sam::Header) -> Self {
+        header.reference_sequences().iter()
+            .map(|(name, rs)| (name.as_str(), rs.length().get() as u64))
+            .collect()
+    }
+
+    /// Read chromosome sizes from the header of a BAM or CRAM file.
+    pub fn read_alignment_header<P: AsRef<Path>>(file: P) -> Result<Self> {
+        let file = file.as_ref();
+        let header = if file.extension().map_or(false, |x| x == "cram") {
+            let mut reader = cram::reader::Builder::default().build_from_path(file)?;
+            reader.read_file_definition()?;
+            reader.read_file_header()?
+        } else {
+            let mut reader = bam::reader::Builder::default().build_from_path(file)?;
+            reader.read_header()?
+        };
+        Ok(Self::from_sam_header(&header))
+    }
+
+    /// Remove alternate, unlocalized or unplaced contigs.
+    pub fn remove_contigs(self, alt: bool, random: bool, unplaced: bool) -> Self {
+        self.into_iter().filter(|(chr, _)| match ContigType::from_name(chr) {
+            ContigType::Primary => true,
+            ContigType::Alt => !alt,
+            ContigType::Random => !random,
+            ContigType::Unplaced => !unplaced,
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_chrom_sizes() {
+        let fai = "chr1\t248956422\t112\t70\t71\n\
+                   chr1_KI270706v1_random\t175055\t253105752\t70\t71\n\
+                   chrUn_KI270302v1\t2274\t253283413\t70\t71\n\
+                   chr6_GL000250v2_alt\t4672374\t253285818\t70\t71\n\
+                   chrM\t16569\t258025431\t70\t71\n";
+        let sizes = ChromSizes::read_fai(fai.as_bytes()).unwrap();
+        assert_eq!(sizes.get("chr1"), Some(248956422));
+        assert_eq!(sizes.get("chrM"), Some(16569));
+
+        let primary = sizes.remove_contigs(true, true, true);
+        assert_eq!(
+            primary,
+            vec![("chr1", 248956422), ("chrM", 16569)].into_iter().collect(),
+        );
+    }
+
+    #[test]
+    fn test_read_2bit() {
+        let mut data: Vec<u8> = Vec::new();
+        [TWOBIT_SIGNATURE, 0, 2, 0].iter().for_each(|x| data.extend(x.to_le_bytes()));
+        let header_len = 16 + (1 + 4 + 4) + (1 + 4 + 4);
+        data.push(4);
+        data.extend(b"chr1");
+        data.extend((header_len as u32).to_le_bytes());
+        data.push(4);
+        data.extend(b"chr2");
+        data.extend((header_len as u32 + 4).to_le_bytes());
+        data.extend(1000u32.to_le_bytes());
+        data.extend(25u32.to_le_bytes());
+
+        let sizes = ChromSizes::read_2bit(Cursor::new(data)).unwrap();
+        assert_eq!(sizes, vec![("chr1", 1000), ("chr2", 25)].into_iter().collect());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::preprocessing::count_data::{ChromSizes, SnapData};
+
+use anndata::{
+    data::CsrNonCanonical, AnnDataOp, AnnDataSet, ArrayData, AxisArraysOp, Backend,
+    ElemCollectionOp,
+};
+use anyhow::{bail, Context, Result};
+use log::info;
+use polars::prelude::{DataFrame, NamedFrom, Series};
+use std::collections::HashSet;
+
+/// Concatenate the component AnnData objects of an `AnnDataSet` into a single AnnData.
+///
+/// The fragment matrices stored in `.obsm["fragment_paired"]` or `.obsm["fragment_single"]`
+/// are merged, the `.obs` columns shared by all components are stacked, and a `sample`
+/// column recording the name of the originating component is added. Columns of the
+/// dataset-level `.obs` are carried over as well.
+/// If any barcode appears in more than one component, all barcodes are prefixed by
+/// the sample name, i.e., `sample+barcode`.
+///
+/// # Arguments
+///
+/// * `dataset` - The AnnDataSet to be concatenated.
+/// * `out` - The output AnnData object.
+/// * `chunk_size` - The number of cells to process at a time.
+pub fn concat_dataset<B, O>(dataset: &AnnDataSet<B>, out: &O, chunk_size: usize) -> Result<()>
+where
+    B: Backend,
+    O: AnnDataOp,
+{
+    let adatas = dataset.adatas().inner();
+
+    // Check reference sequences
+    let chrom_sizes = common_chrom_sizes(
+        adatas.iter().map(|(name, adata)| Ok((name.as_str(), adata.read_chrom_sizes()?)))
+    )?;
+
+    // Make unique barcodes
+    let mut samples = Vec::new();
+    let mut barcodes = Vec::new();
+    adatas.iter().for_each(|(name, adata)| {
+        adata.obs_names().into_vec().into_iter().for_each(|bc| {
+            samples.push(name.clone());
+            barcodes.push(bc);
+        });
+    });
+    let mut unique = HashSet::new();
+    if !barcodes.iter().all(|x| unique.insert(x.as_str())) {
+        info!("Duplicated barcodes found. Barcodes will be prefixed by the sample names.");
+        barcodes = samples.iter().zip(barcodes.into_iter())
+            .map(|(s, bc)| format!("{}+{}", s, bc)).collect();
+    }
+
+    // Merge fragments
+    let obsm = adatas.get_obsm();
+    if let Some(fragment) = obsm.get_item_iter("fragment_paired", chunk_size) {
+        out.obsm().add_iter(
+            "fragment_paired",
+            fragment.map(|(x, _, _): (CsrNonCanonical<u32>, _, _)| ArrayData::from(x)),
+        )?;
+    } else if let Some(insertion) = obsm.get_item_iter("fragment_single", chunk_size) {
+        out.obsm().add_iter(
+            "fragment_single",
+            insertion.map(|(x, _, _): (CsrNonCanonical<i32>, _, _)| ArrayData::from(x)),
+        )?;
+    } else {
+        bail!("neither 'fragment_single' nor 'fragment_paired' is present in the '.obsm'")
+    }
+
+    // Merge obs
+    let component_obs = adatas.iter()
+        .map(|(_, adata)| adata.read_obs())
+        .collect::<Result<Vec<_>>>()?;
+    let common_columns: Vec<String> = component_obs[0]
+        .get_column_names()
+        .into_iter()
+        .filter(|col| *col != "sample" && component_obs.iter().all(|df| df.column(col).is_ok()))
+        .map(|x| x.to_string())
+        .collect();
+    let mut obs = DataFrame::default();
+    for df in component_obs {
+        let df = df.select(&common_columns)?;
+        if obs.width() == 0 {
+            obs = df;
+        } else {
+            obs.vstack_mut(&df)?;
+        }
+    }
+    for col in dataset.read_obs()?.get_columns() {
+        if col.name() != "sample" && !common_columns.iter().any(|x| x == col.name()) {
+            obs.with_column(col.clone())?;
+        }
+    }
+    obs.with_column(Series::new("sample", samples))?;
+
+    out.set_obs_names(barcodes.into())?;
+    out.set_obs(obs)?;
+    out.uns().add("reference_sequences", chrom_sizes.to_dataframe())?;
+    Ok(())
+}
+
+/// Return the reference sequences shared by all samples. The order of chromosomes
+/// must be identical as well, as it determines the column indices of the fragment matrices.
+fn common_chrom_sizes<'a, I>(samples: I) -> Result<ChromSizes>
+where
+    I: IntoIterator<Item = Result<(&'a str, ChromSizes)>>,
+{
+    let mut chrom_sizes: Option<ChromSizes> = None;
+    for sample in samples {
+        let (name, sizes) = sample?;
+        match &chrom_sizes {
+            None => chrom_sizes = Some(sizes),
+            Some(x) => if !x.into_iter().eq(&sizes) {
+                bail!("the reference sequences of '{}' differ from those of other samples", name);
+            },
+        }
+    }
+    chrom_sizes.context("the AnnDataSet is empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_common_chrom_sizes() {
+        let a: ChromSizes = [("chr1", 100), ("chr2", 50)].into_iter().collect();
+        let b: ChromSizes = [("chr2", 50), ("chr1", 100)].into_iter().collect();
+        assert_eq!(
+            common_chrom_sizes([Ok(("a", a.clone())), Ok(("a2", a.clone()))]).unwrap(),
+            a,
+        );
+        assert!(common_chrom_sizes([Ok(("a", a)), Ok(("b", b))]).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! # Contact Index
+//!
+//! `ContactIndex` maps pairs of genomic loci to the column indices of the contact
+//! matrices stored in `.obsm["contact"]`. Contacts are canonicalized to the upper
+//! triangle, i.e., `(chrom1, pos1) <= (chrom2, pos2)` in genome order, and are
+//! stored in blocks, one per pair of chromosomes. Within each block, the index is
+//! computed from chromosome-local positions, which keeps the number of columns
+//! below `G * (G + 1) / 2` for a genome of size `G` and avoids overflows.
+//!
+//! Three layouts are supported:
+//!
+//! * `Full` - the legacy layout, `pos1 * G + pos2`, where `pos1` and `pos2` are
+//!   genome-wide positions.
+//! * `UpperTriangle` - all intra- and inter-chromosomal contacts in the upper triangle.
+//!   For a chromosome of size `L`, contact `(i, j)` with `i <= j` is stored at
+//!   `i * L - i * (i - 1) / 2 + (j - i)`. For two chromosomes of sizes `L1` and `L2`,
+//!   contact `(i, j)` is stored at `i * L2 + j`.
+//! * `Banded(d)` - intra-chromosomal contacts with `j - i <= d` only.
+//!   Contact `(i, j)` is stored at `i * (d + 1) + (j - i)`.
+use crate::preprocessing::count_data::{ChromSizes, GenomeBaseIndex};
+
+use anyhow::{bail, Result};
+use polars::prelude::{DataFrame, NamedFrom, Series};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactLayout {
+    Full,
+    UpperTriangle,
+    Banded(u64),
+}
+
+impl ContactLayout {
+    pub fn to_dataframe(&self) -> DataFrame {
+        let (layout, max_distance) = match self {
+            ContactLayout::Full => ("full", 0),
+            ContactLayout::UpperTriangle => ("upper_triangle", 0),
+            ContactLayout::Banded(d) => ("banded", *d),
+        };
+        DataFrame::new(vec![
+            Series::new("layout", [layout]),
+            Series::new("max_distance", [max_distance]),
+        ]).unwrap()
+    }
+
+    pub fn from_dataframe(df: &DataFrame) -> Result<Self> {
+        let layout = df.column("layout")?.utf8()?.get(0);
+        let max_distance = df.column("max_distance")?.u64()?.get(0);
+        match (layout, max_distance) {
+            (Some("full"), _) => Ok(ContactLayout::Full),
+            (Some("upper_triangle"), _) => Ok(ContactLayout::UpperTriangle),
+            (Some("banded"), Some(d)) => Ok(ContactLayout::Banded(d)),
+            _ => bail!("invalid contact layout"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContactIndex {
+    layout: ContactLayout,
+    index: GenomeBaseIndex,
+    sizes: Vec<u64>,
+    /// Start offsets of blocks. The last element is the total number of columns.
+    offsets: Vec<u64>,
+    /// Chromosome pairs of blocks.
+    blocks: Vec<(usize, usize)>,
+}
+
+impl ContactIndex {
+    pub fn new(chrom_sizes: &ChromSizes, layout: ContactLayout) -> Self {
+        let index = GenomeBaseIndex::new(chrom_sizes);
+        let sizes: Vec<u64> = index.chrom_sizes().map(|(_, s)| s).collect();
+        let n = sizes.len();
+        let blocks: Vec<(usize, usize)> = match layout {
+            ContactLayout::Full => Vec::new(),
+            ContactLayout::UpperTriangle => (0..n).flat_map(|i| (i..n).map(move |j| (i, j))).collect(),
+            ContactLayout::Banded(_) => (0..n).map(|i| (i, i)).collect(),
+        };
+        let mut offsets = vec![0];
+        let mut acc = 0;
+        blocks.iter().for_each(|(i, j)| {
+            let (l1, l2) = (sizes[*i], sizes[*j]);
+            acc += match layout {
+                ContactLayout::Banded(d) => l1 * (d + 1),
+                _ => if i == j { l1 * (l1 + 1) / 2 } else { l1 * l2 },
+            };
+            offsets.push(acc);
+        });
+        Self { layout, index, sizes, offsets, blocks }
+    }
+
+    pub fn layout(&self) -> ContactLayout {
+        self.layout
+    }
+
+    /// Number of columns.
+    pub fn len(&self) -> usize {
+        match self.layout {
+            ContactLayout::Full => self.index.len() * self.index.len(),
+            _ => *self.offsets.last().unwrap() as usize,
+        }
+    }
+
+    fn block_index(&self, c1: usize, c2: usize) -> usize {
+        match self.layout {
+            ContactLayout::UpperTriangle => {
+                let n = self.sizes.len();
+                c1 * n - c1 * c1.saturating_sub(1) / 2 + c2 - c1
+            },
+            _ => c1,
+        }
+    }
+
+    /// Return the column index of a contact, or `None` if the contact is excluded by the layout.
+    /// Loci are swapped if necessary so that the contact is in the upper triangle.
+    pub fn encode(&self, chrom1: &str, pos1: u64, chrom2: &str, pos2: u64) -> Option<usize> {
+        if self.layout == ContactLayout::Full {
+            let i = self.index.get_position_rev(chrom1, pos1);
+            let j = self.index.get_position_rev(chrom2, pos2);
+            return Some(i * self.index.len() + j);
+        }
+
+        let c1 = self.index.chroms.get_index_of(chrom1)?;
+        let c2 = self.index.chroms.get_index_of(chrom2)?;
+        let ((c1, i), (c2, j)) = if (c1, pos1) <= (c2, pos2) {
+            ((c1, pos1), (c2, pos2))
+        } else {
+            ((c2, pos2), (c1, pos1))
+        };
+        if i >= self.sizes[c1] || j >= self.sizes[c2] {
+            return None;
+        }
+        let local = match self.layout {
+            ContactLayout::Banded(d) => {
+                if c1 != c2 || j - i > d {
+                    return None;
+                }
+                i * (d + 1) + (j - i)
+            },
+            _ => if c1 == c2 {
+                triangle_offset(i, self.sizes[c1]) + (j - i)
+            } else {
+                i * self.sizes[c2] + j
+            },
+        };
+        Some((self.offsets[self.block_index(c1, c2)] + local) as usize)
+    }
+
+    /// Return the loci of a contact given its column index.
+    pub fn decode(&self, idx: usize) -> ((&String, u64), (&String, u64)) {
+        if self.layout == ContactLayout::Full {
+            let n = self.index.len();
+            return (self.index.get_position(idx / n), self.index.get_position(idx % n));
+        }
+
+        let idx = idx as u64;
+        // Empty blocks share offsets with their successors, so take the last block starting at or before `idx`.
+        let b = self.offsets.partition_point(|x| *x <= idx) - 1;
+        let (c1, c2) = self.blocks[b];
+        let local = idx - self.offsets[b];
+        let (i, j) = match self.layout {
+            ContactLayout::Banded(d) => {
+                let i = local / (d + 1);
+                (i, i + local % (d + 1))
+            },
+            _ => if c1 == c2 {
+                let l = self.sizes[c1];
+                let i = triangle_row(local, l);
+                (i, i + local - triangle_offset(i, l))
+            } else {
+                (local / self.sizes[c2], local % self.sizes[c2])
+            },
+        };
+        let chroms = &self.index.chroms;
+        ((chroms.get_index(c1).unwrap(), i), (chroms.get_index(c2).unwrap(), j))
+    }
+}
+
+/// Number of entries before row `i` in the upper triangle (including the diagonal)
+/// of a `l x l` matrix.
+fn triangle_offset(i: u64, l: u64) -> u64 {
+    i * l - i * i.saturating_sub(1) / 2
+}
+
+/// Find the row of the `k`-th entry in the upper triangle of a `l x l` matrix.
+fn triangle_row(k: u64, l: u64) -> u64 {
+    let b = (2 * l + 1) as f64;
+    let mut i = ((b - (b * b - 8.0 * k as f64).max(0.0).sqrt()) / 2.0).floor() as u64;
+    i = i.min(l - 1);
+    while i > 0 && triangle_offset(i, l) > k {
+        i -= 1;
+    }
+    while i + 1 < l && triangle_offset(i + 1, l) <= k {
+        i += 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contact_index() {
+        let sizes = [("chr1", 7), ("chr2", 5), ("chr3", 3)];
+        let chrom_sizes: ChromSizes = sizes.into_iter().collect();
+        let loci: Vec<(usize, u64)> = sizes.iter().enumerate()
+            .flat_map(|(c, (_, s))| (0..*s).map(move |i| (c, i)))
+            .collect();
+
+        for layout in [ContactLayout::UpperTriangle, ContactLayout::Banded(2)] {
+            let index = ContactIndex::new(&chrom_sizes, layout);
+            let mut seen = std::collections::HashSet::new();
+            for a in loci.iter() {
+                for b in loci.iter() {
+                    if let Some(i) = index.encode(sizes[a.0].0, a.1, sizes[b.0].0, b.1) {
+                        assert!(i < index.len());
+                        let ((c1, p1), (c2, p2)) = index.decode(i);
+                        let (x, y) = if a <= b { (a, b) } else { (b, a) };
+                        assert_eq!((c1.as_str(), p1), (sizes[x.0].0, x.1));
+                        assert_eq!((c2.as_str(), p2), (sizes[y.0].0, y.1));
+                        seen.insert(i);
+                    }
+                }
+            }
+            if layout == ContactLayout::UpperTriangle {
+                assert_eq!(seen.len(), index.len());
+            }
+        }
+    }
+}
@@ -1,12 +1,30 @@
-use crate::preprocessing::{count_data::genome::{FeatureCounter, GenomeBaseIndex, ChromSizes}, Fragment};
+use crate::preprocessing::{count_data::{genome::{FeatureCounter, GenomeBaseIndex, GenomePartition, ChromSizes}, contact::{ContactIndex, ContactLayout}}, Fragment, Contact};
 
 use std::collections::HashMap;
+use anyhow::{ensure, Result};
 use anndata::data::{utils::to_csr_data, CsrNonCanonical};
 use bed_utils::bed::{BEDLike, Strand, GenomicRange};
 use nalgebra_sparse::{CsrMatrix, pattern::SparsityPattern};
 use num::traits::{FromPrimitive, One, Zero, SaturatingAdd};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use std::{collections::{BTreeMap, HashSet}, ops::AddAssign};
+use std::{collections::{BTreeMap, HashSet}, ops::{AddAssign, Range}};
+
+/// How paired-end fragments are counted. Single-end reads are always counted
+/// by their insertion sites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CountingStrategy {
+    /// Count the two Tn5 insertions of a fragment independently, so a fragment
+    /// may be counted twice by the same feature.
+    #[default]
+    Insertion,
+    /// Count a fragment once for each feature it overlaps, as in Signac.
+    /// Feature counters that score single positions, e.g., `GeneScore`, only
+    /// see the fragment midpoint.
+    Fragment,
+    /// Count a fragment once for each feature containing either of its
+    /// insertions, as in the paired-insertion mode of ArchR.
+    PairedInsertion,
+}
 
 pub enum CoverageType {
     FragmentSingle(CsrNonCanonical<i32>),
@@ -22,6 +40,8 @@ pub struct GenomeCoverage<I> {
     coverage: I,
     resolution: usize,
     exclude_chroms: HashSet<String>,
+    strategy: CountingStrategy,
+    fragment_size_range: Option<(u64, u64)>,
 }
 
 impl<I> GenomeCoverage<I>
@@ -34,6 +54,8 @@ where
             coverage,
             resolution: 1,
             exclude_chroms: HashSet::new(),
+            strategy: CountingStrategy::default(),
+            fragment_size_range: None,
         }
     }
 
@@ -61,6 +83,21 @@ where
         self
     }
 
+    /// Set how paired-end fragments are counted by `into_values` and `aggregate_by`.
+    pub fn with_counting_strategy(mut self, strategy: CountingStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Only keep fragments whose sizes are in `[min, max)`, e.g., `(0, 147)` for
+    /// nucleosome-free fragments. Single-end data only store read lengths, so
+    /// the range is ignored for them.
+    pub fn with_fragment_size_range(mut self, min: u64, max: u64) -> Result<Self> {
+        ensure!(min < max, "invalid fragment size range: [{}, {})", min, max);
+        self.fragment_size_range = Some((min, max));
+        Ok(self)
+    }
+
     pub fn exclude(mut self, chroms: &[&str]) -> Self {
         self.exclude_chroms = chroms
             .iter()
@@ -73,6 +110,7 @@ where
     /// Return an iterator of raw fragments.
     pub fn into_raw(self) -> impl ExactSizeIterator<Item = (Vec<Vec<Fragment>>, usize, usize)> {
         let index = self.index;
+        let size_range = self.fragment_size_range;
         self.coverage.map(move |(raw_mat, a, b)| {
             let beds = match raw_mat {
                 CoverageType::FragmentSingle(mat) => {
@@ -114,7 +152,7 @@ where
                     (0..(row_offsets.len() - 1)).into_par_iter().map(|i| {
                         let row_start = row_offsets[i];
                         let row_end = row_offsets[i + 1];
-                        (row_start..row_end).map(|j| {
+                        (row_start..row_end).filter(|j| in_range(size_range, values[*j] as u64)).map(|j| {
                             let size = values[j];
                             let (chrom, start) = index.get_position(col_indices[j]);
                             Fragment {
@@ -160,68 +198,115 @@ where
         T: Zero + One + FromPrimitive + SaturatingAdd + Send + Sync,
     {
         let index = self.get_gindex();
+        self.into_binned_values(vec![index]).map(|(mut mats, i, j)| (mats.pop().unwrap(), i, j))
+    }
+
+    /// Output the coverage matrices at several resolutions in a single pass.
+    /// The resolution set by `with_resolution` is ignored.
+    pub fn into_multi_resolution_values<T>(
+        self,
+        resolutions: &[usize],
+    ) -> impl ExactSizeIterator<Item = (Vec<CsrMatrix<T>>, usize, usize)>
+    where
+        T: Zero + One + FromPrimitive + SaturatingAdd + Send + Sync,
+    {
+        let indices = resolutions.iter().map(|s| self.get_gindex().with_step(*s)).collect();
+        self.into_binned_values(indices)
+    }
+
+    /// Output the coverage matrix of variable-width bins. The resolution set by
+    /// `with_resolution` is ignored.
+    pub fn into_partition_values<T>(
+        self,
+        partition: GenomePartition,
+    ) -> impl ExactSizeIterator<Item = (CsrMatrix<T>, usize, usize)>
+    where
+        T: Zero + One + FromPrimitive + SaturatingAdd + Send + Sync,
+    {
+        self.into_binned_values(vec![partition]).map(|(mut mats, i, j)| (mats.pop().unwrap(), i, j))
+    }
+
+    fn into_binned_values<T, B>(
+        self,
+        binnings: Vec<B>,
+    ) -> impl ExactSizeIterator<Item = (Vec<CsrMatrix<T>>, usize, usize)>
+    where
+        T: Zero + One + FromPrimitive + SaturatingAdd + Send + Sync,
+        B: Binning,
+    {
         let ori_index = self.index;
+        let size_range = self.fragment_size_range;
+        let strategy = self.strategy;
+        let exclude_chroms = self.exclude_chroms;
+        let keep = move |chrom: &str| exclude_chroms.is_empty() || !exclude_chroms.contains(chrom);
         self.coverage.map(move |(raw_mat, i, j)| {
-            let new_mat = match raw_mat {
+            let n = j - i;
+            let new_counts = || -> Vec<BTreeMap<usize, T>> { binnings.iter().map(|_| BTreeMap::new()).collect() };
+            let rows: Vec<Vec<BTreeMap<usize, T>>> = match raw_mat {
                 CoverageType::FragmentSingle(mat) => {
                     let row_offsets = mat.row_offsets();
                     let col_indices = mat.col_indices();
-                    let n = j - i;
-                    let vec = (0..n)
-                        .into_par_iter()
-                        .map(|row| {
-                            let mut count: BTreeMap<usize, T> = BTreeMap::new();
-                            let row_start = row_offsets[row];
-                            let row_end = row_offsets[row + 1];
-
-                            for k in row_start..row_end {
-                                let (chrom, pos) = ori_index.get_position(col_indices[k]);
-                                if self.exclude_chroms.is_empty()
-                                    || !self.exclude_chroms.contains(chrom)
-                                {
-                                    let i = index.get_position_rev(chrom, pos);
-                                    let entry = count.entry(i).or_insert(Zero::zero());
-                                    *entry = entry.saturating_add(&One::one());
-                                }
+                    let values = mat.values();
+                    (0..n).into_par_iter().map(|row| {
+                        let mut counts = new_counts();
+                        for k in row_offsets[row]..row_offsets[row + 1] {
+                            let (chrom, pos) = ori_index.get_position(col_indices[k]);
+                            if keep(chrom) {
+                                binnings.iter().zip(counts.iter_mut()).for_each(|(b, count)|
+                                    add_bins(count, b.get_bins(chrom, pos, pos))
+                                );
                             }
-                            count.into_iter().collect::<Vec<_>>()
-                        })
-                        .collect::<Vec<_>>();
-                    let (r, c, offset, ind, data) = to_csr_data(vec, index.len());
-                    CsrMatrix::try_from_csr_data(r,c,offset,ind, data).unwrap()
+                        }
+                        counts
+                    }).collect()
                 },
                 CoverageType::FragmentPaired(mat) => {
                     let row_offsets = mat.row_offsets();
                     let col_indices = mat.col_indices();
                     let values = mat.values();
-                    let n = j - i;
-                    let vec = (0..n)
-                        .into_par_iter()
-                        .map(|row| {
-                            let mut count: BTreeMap<usize, T> = BTreeMap::new();
-                            let row_start = row_offsets[row];
-                            let row_end = row_offsets[row + 1];
-                            for k in row_start..row_end {
-                                let (chrom, start)= ori_index.get_position(col_indices[k]);
-                                let end = start + values[k] as u64 - 1;
-                                if self.exclude_chroms.is_empty()
-                                    || !self.exclude_chroms.contains(chrom)
-                                {
-                                    [start, end].into_iter().for_each(|pos| {
-                                        let i = index.get_position_rev(chrom, pos);
-                                        let entry = count.entry(i).or_insert(Zero::zero());
-                                        *entry = entry.saturating_add(&One::one());
-                                    });
-                                }
+                    (0..n).into_par_iter().map(|row| {
+                        let mut counts = new_counts();
+                        for k in row_offsets[row]..row_offsets[row + 1] {
+                            if !in_range(size_range, values[k] as u64) {
+                                continue;
                             }
-                            count.into_iter().collect::<Vec<_>>()
-                        })
-                        .collect::<Vec<_>>();
-                    let (r, c, offset, ind, data) = to_csr_data(vec, index.len());
-                    CsrMatrix::try_from_csr_data(r,c,offset,ind, data).unwrap()
+                            let (chrom, start) = ori_index.get_position(col_indices[k]);
+                            let end = start + values[k] as u64 - 1;
+                            if keep(chrom) {
+                                binnings.iter().zip(counts.iter_mut()).for_each(|(b, count)| {
+                                    let r1 = b.get_bins(chrom, start, start);
+                                    let r2 = b.get_bins(chrom, end, end);
+                                    match strategy {
+                                        CountingStrategy::Insertion => {
+                                            add_bins(count, r1);
+                                            add_bins(count, r2);
+                                        },
+                                        CountingStrategy::Fragment => add_bins(count, b.get_bins(chrom, start, end)),
+                                        CountingStrategy::PairedInsertion if r1 == r2 => add_bins(count, r1),
+                                        CountingStrategy::PairedInsertion => {
+                                            add_bins(count, r1);
+                                            add_bins(count, r2);
+                                        },
+                                    }
+                                });
+                            }
+                        }
+                        counts
+                    }).collect()
                 },
             };
-            (new_mat, i, j)
+
+            let mut columns: Vec<Vec<Vec<(usize, T)>>> = binnings.iter().map(|_| Vec::with_capacity(n)).collect();
+            rows.into_iter().for_each(|counts|
+                counts.into_iter().zip(columns.iter_mut()).for_each(|(count, x)|
+                    x.push(count.into_iter().collect())
+                )
+            );
+            let mats = columns.into_iter().zip(binnings.iter()).map(|(vec, b)| {
+                let (r, c, offset, ind, data) = to_csr_data(vec, b.num_bins());
+                CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()
+            }).collect();
+            (mats, i, j)
         })
     }
 
@@ -232,18 +317,21 @@ where
     ) -> impl ExactSizeIterator<Item = (CsrMatrix<T>, usize, usize)>
     where
         C: FeatureCounter<Value = T> + Clone + Sync,
-        T: Send,
+        T: Copy + PartialOrd + AddAssign + Send,
     {
         if !self.exclude_chroms.is_empty() {
             todo!("Implement exclude_chroms")
         }
         let n_col = counter.num_features();
+        let strategy = self.strategy;
         counter.reset();
         self.into_raw().map(move |(data, i, j)| {
             let vec = data
                 .into_par_iter()
                 .map(|beds| {
                     let mut coverage = counter.clone();
+                    // Counts of fragments that must be counted at most once per feature.
+                    let mut fragment_counts: BTreeMap<usize, T> = BTreeMap::new();
                     beds.into_iter().for_each(|mut x| {
                         match x.strand() {
                             Some(Strand::Forward) => {
@@ -257,18 +345,46 @@ where
                                 coverage.insert(&x, 1);
                             },
                             None => {
-                                coverage.insert(
-                                    &GenomicRange::new(x.chrom(), x.start(), x.start() + 1),
-                                    1,
-                                );
-                                coverage.insert(
-                                    &GenomicRange::new(x.chrom(), x.end() - 1, x.end()),
-                                    1,
-                                );
+                                let start = GenomicRange::new(x.chrom(), x.start(), x.start() + 1);
+                                let end = GenomicRange::new(x.chrom(), x.end() - 1, x.end());
+                                match strategy {
+                                    CountingStrategy::Insertion => {
+                                        coverage.insert(&start, 1);
+                                        coverage.insert(&end, 1);
+                                    },
+                                    CountingStrategy::Fragment => {
+                                        let mut tmp = counter.clone();
+                                        tmp.insert(&x, 1);
+                                        tmp.get_counts().into_iter().for_each(|(k, v)|
+                                            add_count(&mut fragment_counts, k, v)
+                                        );
+                                    },
+                                    CountingStrategy::PairedInsertion => {
+                                        let mut tmp = counter.clone();
+                                        tmp.insert(&start, 1);
+                                        let mut counts: BTreeMap<_, _> = tmp.get_counts().into_iter().collect();
+                                        tmp.reset();
+                                        tmp.insert(&end, 1);
+                                        tmp.get_counts().into_iter().for_each(|(k, v)| {
+                                            let c = counts.entry(k).or_insert(v);
+                                            if *c < v { *c = v }
+                                        });
+                                        counts.into_iter().for_each(|(k, v)|
+                                            add_count(&mut fragment_counts, k, v)
+                                        );
+                                    },
+                                }
                             },
                         }
                     });
-                    coverage.get_counts()
+                    if fragment_counts.is_empty() {
+                        coverage.get_counts()
+                    } else {
+                        coverage.get_counts().into_iter().for_each(|(k, v)|
+                            add_count(&mut fragment_counts, k, v)
+                        );
+                        fragment_counts.into_iter().collect()
+                    }
                 })
                 .collect::<Vec<_>>();
             let (r, c, offset, ind, data) = to_csr_data(vec, n_col);
@@ -277,6 +393,56 @@ where
     }
 }
 
+/// Assignment of genomic positions to bins.
+trait Binning: Sync {
+    fn num_bins(&self) -> usize;
+
+    /// Indices of the bins overlapping the closed interval `[start, end]`.
+    fn get_bins(&self, chrom: &str, start: u64, end: u64) -> Range<usize>;
+}
+
+impl Binning for GenomeBaseIndex {
+    fn num_bins(&self) -> usize {
+        self.len()
+    }
+
+    fn get_bins(&self, chrom: &str, start: u64, end: u64) -> Range<usize> {
+        if self.chroms.contains(chrom) {
+            self.get_position_rev(chrom, start)..self.get_position_rev(chrom, end) + 1
+        } else {
+            0..0
+        }
+    }
+}
+
+impl Binning for GenomePartition {
+    fn num_bins(&self) -> usize {
+        self.len()
+    }
+
+    fn get_bins(&self, chrom: &str, start: u64, end: u64) -> Range<usize> {
+        GenomePartition::get_bins(self, chrom, start, end)
+    }
+}
+
+fn add_bins<T: Zero + One + SaturatingAdd>(count: &mut BTreeMap<usize, T>, bins: Range<usize>) {
+    bins.for_each(|i| {
+        let entry = count.entry(i).or_insert(Zero::zero());
+        *entry = entry.saturating_add(&One::one());
+    });
+}
+
+fn in_range(range: Option<(u64, u64)>, size: u64) -> bool {
+    range.map_or(true, |(min, max)| size >= min && size < max)
+}
+
+fn add_count<T: AddAssign>(counts: &mut BTreeMap<usize, T>, k: usize, v: T) {
+    match counts.entry(k) {
+        std::collections::btree_map::Entry::Occupied(mut e) => *e.get_mut() += v,
+        std::collections::btree_map::Entry::Vacant(e) => { e.insert(v); },
+    }
+}
+
 pub fn fragments_to_insertions(
     fragments: CsrNonCanonical<i32>,
 ) -> CsrMatrix<u8> {
@@ -321,8 +487,11 @@ pub fn fragments_to_insertions(
     CsrMatrix::try_from_pattern_and_values(pattern, new_values).unwrap()
 }
 
+/// `ContactMap` represents single-cell contact matrices stored in the layout
+/// described by `ContactIndex`.
 pub struct ContactMap<I> {
     index: GenomeBaseIndex,
+    contact_index: ContactIndex,
     coverage: I,
     resolution: usize,
 }
@@ -331,9 +500,10 @@ impl<I> ContactMap<I>
 where
     I: ExactSizeIterator<Item = (CsrMatrix<u8>, usize, usize)>,
 {
-    pub fn new(chrom_sizes: ChromSizes, coverage: I) -> Self {
+    pub fn new(chrom_sizes: ChromSizes, layout: ContactLayout, coverage: I) -> Self {
         Self {
             index: GenomeBaseIndex::new(&chrom_sizes),
+            contact_index: ContactIndex::new(&chrom_sizes, layout),
             coverage,
             resolution: 1,
         }
@@ -343,56 +513,167 @@ where
         self.index.with_step(self.resolution)
     }
 
+    pub fn get_contact_index(&self) -> &ContactIndex {
+        &self.contact_index
+    }
+
     /// Set the resolution of the coverage matrix.
     pub fn with_resolution(mut self, s: usize) -> Self {
         self.resolution = s;
         self
     }
 
-    /// Output the raw coverage matrix.
+    /// Return an iterator of raw contacts at base resolution. The resolution is ignored.
+    /// Cell barcodes of the contacts are left empty.
+    pub fn into_raw(self) -> impl ExactSizeIterator<Item = (Vec<Vec<Contact>>, usize, usize)> {
+        let contact_index = self.contact_index;
+        self.coverage.map(move |(mat, a, b)| {
+            let contacts = (0..mat.nrows()).into_par_iter().map(|i| {
+                let row = mat.get_row(i).unwrap();
+                row.col_indices().iter().zip(row.values()).map(|(idx, val)| {
+                    let ((chrom1, start1), (chrom2, start2)) = contact_index.decode(*idx);
+                    Contact {
+                        chrom1: chrom1.to_string(),
+                        start1,
+                        chrom2: chrom2.to_string(),
+                        start2,
+                        barcode: String::new(),
+                        count: *val as u32,
+                    }
+                }).collect()
+            }).collect();
+            (contacts, a, b)
+        })
+    }
+
+    /// Output the contact matrices binned at the given resolution. Contact `(i1, i2)`
+    /// between bins `i1` and `i2` is stored at column `i1 * n + i2`, where `n` is the
+    /// number of bins. Contacts imported with `import_contacts` are canonicalized to
+    /// the upper triangle, so `i1 <= i2`.
     pub fn into_values<T: Zero + FromPrimitive + AddAssign + Send>(
         self,
     ) -> impl ExactSizeIterator<Item = (CsrMatrix<T>, usize, usize)> {
         let index = self.get_gindex();
-        let ori_index = self.index;
-        let genome_size = ori_index.len();
+        let contact_index = self.contact_index;
         let new_size = index.len();
         self.coverage.map(move |(mat, i, j)| {
-            let new_mat = if self.resolution <= 1 {
-                let (pattern, data) = mat.into_pattern_and_values();
-                let new_data = data
-                    .into_iter()
-                    .map(|x| T::from_u8(x).unwrap())
-                    .collect::<Vec<_>>();
-                CsrMatrix::try_from_pattern_and_values(pattern, new_data).unwrap()
-            } else {
-                let n = j - i;
-                let vec = (0..n)
-                    .into_par_iter()
-                    .map(|k| {
-                        let row = mat.get_row(k).unwrap();
-                        let mut count: BTreeMap<usize, T> = BTreeMap::new();
-                        row.col_indices()
-                            .into_iter()
-                            .zip(row.values())
-                            .for_each(|(idx, val)| {
-                                let ridx = idx / genome_size;
-                                let cidx = idx % genome_size;
-                                let locus1 = ori_index.get_region(ridx);
-                                let locus2 = ori_index.get_region(cidx);
-                                let i1 = index.get_position_rev(locus1.chrom(), locus1.start());
-                                let i2 = index.get_position_rev(locus2.chrom(), locus2.start());
-                                let i = i1 * new_size + i2;
-                                let val = T::from_u8(*val).unwrap();
-                                *count.entry(i).or_insert(Zero::zero()) += val;
-                            });
-                        count.into_iter().collect::<Vec<_>>()
-                    })
-                    .collect::<Vec<_>>();
-                let (r, c, offset, ind, data) = to_csr_data(vec, new_size * new_size);
-                CsrMatrix::try_from_csr_data(r,c,offset,ind, data).unwrap()
-            };
-            (new_mat, i, j)
+            let n = j - i;
+            let vec = (0..n)
+                .into_par_iter()
+                .map(|k| {
+                    let row = mat.get_row(k).unwrap();
+                    let mut count: BTreeMap<usize, T> = BTreeMap::new();
+                    row.col_indices()
+                        .into_iter()
+                        .zip(row.values())
+                        .for_each(|(idx, val)| {
+                            let ((chr1, pos1), (chr2, pos2)) = contact_index.decode(*idx);
+                            let i1 = index.get_position_rev(chr1, pos1);
+                            let i2 = index.get_position_rev(chr2, pos2);
+                            let i = i1 * new_size + i2;
+                            let val = T::from_u8(*val).unwrap();
+                            *count.entry(i).or_insert(Zero::zero()) += val;
+                        });
+                    count.into_iter().collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let (r, c, offset, ind, data) = to_csr_data(vec, new_size * new_size);
+            (CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap(), i, j)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anndata::data::utils::from_csr_data;
+    use bed_utils::bed::tree::{GenomeRegions, SparseCoverage};
+    use std::str::FromStr;
+
+    fn paired_coverage(
+        chrom_sizes: &ChromSizes,
+        fragments: &[&[(u64, u32)]],
+    ) -> GenomeCoverage<impl ExactSizeIterator<Item = (CoverageType, usize, usize)>> {
+        let index = GenomeBaseIndex::new(chrom_sizes);
+        let counts: Vec<Vec<(usize, u32)>> = fragments.iter().map(|x|
+            x.iter().map(|(start, size)| (index.get_position_rev("chr1", *start), *size)).collect()
+        ).collect();
+        let n = counts.len();
+        let (r, c, offset, ind, data) = to_csr_data(counts, index.len());
+        let mat: CsrNonCanonical<u32> = from_csr_data(r, c, offset, ind, data).unwrap().try_into().unwrap();
+        GenomeCoverage::new(chrom_sizes.clone(), std::iter::once((CoverageType::FragmentPaired(mat), 0, n)))
+    }
+
+    fn to_rows<T: Copy>(mat: &CsrMatrix<T>) -> Vec<Vec<(usize, T)>> {
+        mat.row_iter().map(|row|
+            row.col_indices().iter().copied().zip(row.values().iter().copied()).collect()
+        ).collect()
+    }
+
+    #[test]
+    fn test_counting_strategy() {
+        let chrom_sizes: ChromSizes = [("chr1", 1000)].into_iter().collect();
+        // Fragments are given as (start, size).
+        let fragments: [&[(u64, u32)]; 2] = [
+            &[(100, 50), (180, 150)],
+            &[(10, 20), (40, 360)],
+        ];
+        let regions: GenomeRegions<GenomicRange> = ["chr1:0-50", "chr1:100-200", "chr1:150-350"]
+            .into_iter().map(|x| GenomicRange::from_str(x).unwrap()).collect();
+
+        let expected_bins = [
+            (CountingStrategy::Insertion, vec![vec![(1, 3), (3, 1)], vec![(0, 3), (3, 1)]]),
+            (CountingStrategy::Fragment, vec![vec![(1, 2), (2, 1), (3, 1)], vec![(0, 2), (1, 1), (2, 1), (3, 1)]]),
+            (CountingStrategy::PairedInsertion, vec![vec![(1, 2), (3, 1)], vec![(0, 2), (3, 1)]]),
+        ];
+        for (strategy, expected) in expected_bins {
+            let (mat, _, _) = paired_coverage(&chrom_sizes, &fragments)
+                .with_resolution(100)
+                .with_counting_strategy(strategy)
+                .into_values::<u32>().next().unwrap();
+            assert_eq!(to_rows(&mat), expected, "{:?}", strategy);
+        }
+
+        let expected_features = [
+            (CountingStrategy::Insertion, vec![vec![(1, 3), (2, 2)], vec![(0, 3)]]),
+            (CountingStrategy::Fragment, vec![vec![(1, 2), (2, 1)], vec![(0, 2), (1, 1), (2, 1)]]),
+            (CountingStrategy::PairedInsertion, vec![vec![(1, 2), (2, 1)], vec![(0, 2)]]),
+        ];
+        for (strategy, expected) in expected_features {
+            let (mat, _, _) = paired_coverage(&chrom_sizes, &fragments)
+                .with_counting_strategy(strategy)
+                .aggregate_by(SparseCoverage::new(&regions)).next().unwrap();
+            assert_eq!(to_rows(&mat), expected, "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn test_fragment_size_range() {
+        let chrom_sizes: ChromSizes = [("chr1", 1000)].into_iter().collect();
+        let fragments: [&[(u64, u32)]; 1] = [&[(100, 50), (180, 150)]];
+        assert!(paired_coverage(&chrom_sizes, &fragments).with_fragment_size_range(100, 100).is_err());
+
+        let raw: Vec<_> = paired_coverage(&chrom_sizes, &fragments)
+            .with_fragment_size_range(0, 147).unwrap()
+            .into_raw().next().unwrap().0;
+        assert_eq!(raw[0].iter().map(|x| (x.start(), x.end())).collect::<Vec<_>>(), vec![(100, 150)]);
+        let (mat, _, _) = paired_coverage(&chrom_sizes, &fragments)
+            .with_resolution(100)
+            .with_fragment_size_range(147, 1000).unwrap()
+            .into_values::<u32>().next().unwrap();
+        assert_eq!(to_rows(&mat), vec![vec![(1, 1), (3, 1)]]);
+
+        // Single-end data store read lengths, so the range is ignored.
+        let index = GenomeBaseIndex::new(&chrom_sizes);
+        let counts = vec![vec![
+            (index.get_position_rev("chr1", 100), 50i32),
+            (index.get_position_rev("chr1", 300), -50i32),
+        ]];
+        let (r, c, offset, ind, data) = to_csr_data(counts, index.len());
+        let mat: CsrNonCanonical<i32> = from_csr_data(r, c, offset, ind, data).unwrap().try_into().unwrap();
+        let raw = GenomeCoverage::new(chrom_sizes, std::iter::once((CoverageType::FragmentSingle(mat), 0, 1)))
+            .with_fragment_size_range(0, 10).unwrap()
+            .into_raw().next().unwrap().0;
+        assert_eq!(raw[0].len(), 2);
+    }
+}
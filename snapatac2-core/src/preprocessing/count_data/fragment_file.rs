@@ -0,0 +1,559 @@
+//! # Fragment File Backed `SnapData`
+//!
+//! `FragmentFileData` exposes a fragment file as a `SnapData` source without
+//! importing it into `.obsm` first. The fragment file is streamed every time
+//! the count matrices are requested, and cells are emitted in the order of
+//! the observations registered in the underlying AnnData object.
+//!
+//! Barcode-sorted fragment files are streamed directly. Coordinate-sorted
+//! fragment files, recognized by the presence of a tabix index (`.tbi`),
+//! are sorted by barcode on the fly using an external sort.
+use crate::preprocessing::{
+    count_data::{
+        import::count_fragments, SnapData, ChromSizes, GenomeBaseIndex, GenomeCoverage,
+        ContactMap, CoverageType,
+    },
+    qc::Fragment,
+};
+use crate::utils::open_file_for_read;
+
+use anndata::{
+    data::{array::utils::{from_csr_data, to_csr_data}, ArrayChunk, CsrNonCanonical, DataFrameIndex},
+    AnnDataOp, ArrayData, ElemCollectionOp, HasShape, WriteArrayData,
+};
+use anyhow::{bail, Context, Result};
+use bed_utils::bed::{self, BEDLike, Strand};
+use indexmap::IndexSet;
+use log::warn;
+use nalgebra_sparse::CsrMatrix;
+use num::integer::div_ceil;
+use polars::frame::DataFrame;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    collections::HashSet,
+    iter::Peekable,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// The order of records in a fragment file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentOrder {
+    /// Fragments from the same barcode are stored contiguously.
+    Barcode,
+    /// Fragments are sorted by genomic coordinates, e.g., tabix-indexed files.
+    Coordinate,
+}
+
+/// `FragmentFileData` wraps an AnnData object and a fragment file.
+/// The AnnData object holds the cell barcodes and the reference sequences,
+/// while the count data are read from the fragment file on demand.
+pub struct FragmentFileData<A> {
+    adata: A,
+    fragment_file: PathBuf,
+    order: FragmentOrder,
+    is_paired: bool,
+    tempdir: Option<PathBuf>,
+}
+
+impl<A: AnnDataOp> FragmentFileData<A> {
+    /// Open a fragment file. Records without a barcode are skipped.
+    /// All records are parsed when the file is opened, and an error is returned
+    /// if any of them is invalid.
+    ///
+    /// # Arguments
+    ///
+    /// * `adata` - The AnnData object used to store the cell barcodes and annotations.
+    ///             Its `.obs_names` and `.uns["reference_sequences"]` will be overwritten.
+    /// * `fragment_file` - A barcode-sorted or tabix-indexed fragment file.
+    /// * `chrom_sizes` - The chromosome sizes.
+    /// * `white_list` - If provided, only barcodes in the list are retained.
+    /// * `tempdir` - Directory used to sort coordinate-sorted fragment files.
+    pub fn open<P: AsRef<Path>>(
+        adata: A,
+        fragment_file: P,
+        chrom_sizes: &ChromSizes,
+        white_list: Option<&HashSet<String>>,
+        tempdir: Option<PathBuf>,
+    ) -> Result<Self> {
+        let fragment_file = fragment_file.as_ref().to_path_buf();
+        let mut tbi = fragment_file.clone().into_os_string();
+        tbi.push(".tbi");
+        let order = if Path::new(&tbi).exists() {
+            FragmentOrder::Coordinate
+        } else {
+            FragmentOrder::Barcode
+        };
+
+        let (barcodes, is_paired) = scan_barcodes(&fragment_file, order, white_list)?;
+        adata.set_obs_names(barcodes.into_iter().collect())?;
+        adata.uns().add("reference_sequences", chrom_sizes.to_dataframe())?;
+        Ok(Self { adata, fragment_file, order, is_paired, tempdir })
+    }
+
+    pub fn order(&self) -> FragmentOrder {
+        self.order
+    }
+
+    pub fn is_paired(&self) -> bool {
+        self.is_paired
+    }
+
+    /// Return the wrapped AnnData object.
+    pub fn into_inner(self) -> A {
+        self.adata
+    }
+}
+
+/// Read all records of a fragment file, including those without a barcode.
+fn read_records(file: &Path) -> Result<impl Iterator<Item = Result<Fragment>> + Send> {
+    let reader = bed::io::Reader::new(open_file_for_read(file)?, Some("#".to_string()));
+    let file = file.to_path_buf();
+    Ok(reader.into_records::<Fragment>().map(move |x|
+        x.with_context(|| format!("invalid record in fragment file: {}", file.display()))
+    ))
+}
+
+/// Read non-empty fragments with a barcode in the order they are stored in the file.
+fn read_fragments(file: &Path) -> Result<impl Iterator<Item = Result<Fragment>> + Send> {
+    Ok(read_records(file)?.filter(|x| x.as_ref().map_or(true, |x|
+        x.len() > 0 && x.barcode.is_some()
+    )))
+}
+
+/// Read fragments grouped by barcodes. Coordinate-sorted files are sorted by
+/// barcode using an external sort, which consumes the whole file before returning.
+fn read_fragments_by_barcode(
+    file: &Path,
+    order: FragmentOrder,
+    tempdir: Option<PathBuf>,
+) -> Result<Box<dyn Iterator<Item = Result<Fragment>> + Send>> {
+    let fragments = read_fragments(file)?;
+    match order {
+        FragmentOrder::Barcode => Ok(Box::new(fragments)),
+        FragmentOrder::Coordinate => {
+            let error = Arc::new(Mutex::new(None));
+            let error_ = error.clone();
+            let fragments = fragments.map_while(move |x| match x {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    *error_.lock().unwrap() = Some(e);
+                    None
+                },
+            });
+            let sorted = bed::sort_bed_by_key(fragments, |x| x.barcode.clone(), tempdir);
+            if let Some(e) = error.lock().unwrap().take() {
+                return Err(e);
+            }
+            Ok(Box::new(sorted.map(Ok)))
+        },
+    }
+}
+
+/// Collect the barcodes in the order they will be emitted by `read_fragments_by_barcode`,
+/// and determine whether the fragments are paired-end.
+fn scan_barcodes(
+    file: &Path,
+    order: FragmentOrder,
+    white_list: Option<&HashSet<String>>,
+) -> Result<(IndexSet<String>, bool)> {
+    let mut is_paired = None;
+    let mut barcodes: IndexSet<String> = IndexSet::new();
+    let mut prev: Option<String> = None;
+    let mut num_missing = 0;
+    for frag in read_records(file)? {
+        let frag = frag?;
+        let bc = match frag.barcode.as_deref() {
+            Some(bc) if frag.len() > 0 => bc,
+            Some(_) => continue,
+            None => {
+                num_missing += 1;
+                continue;
+            },
+        };
+        if is_paired.is_none() {
+            is_paired = Some(frag.strand.is_none());
+        }
+        if prev.as_deref() == Some(bc) {
+            continue;
+        }
+        if white_list.map_or(true, |x| x.contains(bc)) {
+            if !barcodes.insert(bc.to_string()) && order == FragmentOrder::Barcode {
+                bail!(
+                    "fragment file is neither sorted by barcodes nor indexed by tabix: {}",
+                    file.display()
+                );
+            }
+        }
+        prev = Some(bc.to_string());
+    }
+    if num_missing > 0 {
+        warn!("{} records without a barcode were skipped: {}", num_missing, file.display());
+    }
+    if order == FragmentOrder::Coordinate {
+        barcodes.sort();
+    }
+    Ok((barcodes, is_paired.unwrap_or(false)))
+}
+
+/// An iterator that reads chunks of cells from a barcode-ordered fragment stream.
+///
+/// Every record of the fragment file is parsed and validated by `FragmentFileData::open`,
+/// so reading can only fail afterwards if the file is modified or becomes unreadable.
+/// In that case the iterator panics rather than yielding fewer chunks than its length.
+struct FragmentChunks {
+    fragments: Peekable<Box<dyn Iterator<Item = Result<Fragment>> + Send>>,
+    barcodes: IndexSet<String>,
+    genome_index: GenomeBaseIndex,
+    is_paired: bool,
+    chunk_size: usize,
+    current: usize,
+}
+
+impl FragmentChunks {
+    /// Collect the fragments of the next barcode in the white list.
+    fn next_cell(&mut self) -> Result<Option<Vec<Fragment>>> {
+        loop {
+            let first = match self.fragments.next() {
+                None => return Ok(None),
+                Some(x) => x?,
+            };
+            let barcode = first.barcode.clone();
+            let mut cell = vec![first];
+            while let Some(frag) = self.fragments.next_if(|x|
+                x.as_ref().map_or(false, |x| x.barcode == barcode)
+            ) {
+                cell.push(frag?);
+            }
+            if barcode.map_or(false, |x| self.barcodes.contains(&x)) {
+                return Ok(Some(cell));
+            }
+        }
+    }
+
+    fn next_cells(&mut self, n: usize) -> Result<Vec<Vec<Fragment>>> {
+        let mut cells = Vec::with_capacity(n);
+        for _ in 0..n {
+            match self.next_cell()? {
+                Some(cell) => cells.push(cell),
+                None => bail!(
+                    "unexpected end of the fragment file: {} cells are missing",
+                    self.barcodes.len() - self.current - cells.len(),
+                ),
+            }
+        }
+        Ok(cells)
+    }
+}
+
+impl Iterator for FragmentChunks {
+    type Item = (CoverageType, usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n_obs = self.barcodes.len();
+        if self.current >= n_obs {
+            return None;
+        }
+        let n = self.chunk_size.min(n_obs - self.current);
+        let cells = self.next_cells(n).unwrap_or_else(|e|
+            panic!("failed to read the fragment file after it was validated: {:#}", e)
+        );
+
+        let mito = HashSet::new();
+        let num_features = self.genome_index.len();
+        let mat = if self.is_paired {
+            let counts: Vec<_> = cells
+                .into_par_iter()
+                .map(|x| count_fragments::<u32>(&mito, &self.genome_index, x).1)
+                .collect();
+            let (r, c, offset, ind, data) = to_csr_data(counts, num_features);
+            let csr: CsrNonCanonical<u32> = from_csr_data(r, c, offset, ind, data)
+                .unwrap().try_into().unwrap();
+            CoverageType::FragmentPaired(csr)
+        } else {
+            let counts: Vec<_> = cells
+                .into_par_iter()
+                .map(|x| count_fragments::<i32>(&mito, &self.genome_index, x).1)
+                .collect();
+            let (r, c, offset, ind, data) = to_csr_data(counts, num_features);
+            let csr: CsrNonCanonical<i32> = from_csr_data(r, c, offset, ind, data)
+                .unwrap().try_into().unwrap();
+            CoverageType::FragmentSingle(csr)
+        };
+        let start = self.current;
+        self.current += n;
+        Some((mat, start, self.current))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = div_ceil(self.barcodes.len() - self.current, self.chunk_size);
+        (n, Some(n))
+    }
+}
+
+impl ExactSizeIterator for FragmentChunks {}
+
+impl<A: AnnDataOp> SnapData for FragmentFileData<A> {
+    type CountIter = std::iter::Empty<(CsrMatrix<u8>, usize, usize)>;
+
+    fn fragment_is_paired(&self) -> Result<bool> {
+        Ok(self.is_paired)
+    }
+
+    fn get_count_iter(&self, chunk_size: usize) ->
+        Result<GenomeCoverage<Box<dyn ExactSizeIterator<Item = (CoverageType, usize, usize)>>>>
+    {
+        let chrom_sizes = self.read_chrom_sizes()?;
+        let chunks = FragmentChunks {
+            fragments: read_fragments_by_barcode(
+                &self.fragment_file, self.order, self.tempdir.clone(),
+            )?.peekable(),
+            barcodes: self.obs_names().into_vec().into_iter().collect(),
+            genome_index: GenomeBaseIndex::new(&chrom_sizes),
+            is_paired: self.is_paired,
+            chunk_size,
+            current: 0,
+        };
+        Ok(GenomeCoverage::new(chrom_sizes, Box::new(chunks)))
+    }
+
+    fn contact_count_iter(&self, _chunk_size: usize) -> Result<ContactMap<Self::CountIter>> {
+        bail!("contact data cannot be read from a fragment file")
+    }
+
+    fn fragment_size_distribution(&self, max_size: usize) -> Result<Vec<usize>> {
+        if !self.is_paired {
+            bail!("fragment sizes are not available for single-end fragments")
+        }
+        let barcodes: HashSet<String> = self.obs_names().into_vec().into_iter().collect();
+        let mut size_dist = vec![0; max_size+1];
+        for x in read_fragments(&self.fragment_file)? {
+            let x = x?;
+            if x.barcode.as_ref().map_or(false, |bc| barcodes.contains(bc)) {
+                let v = x.len() as usize;
+                if v <= max_size {
+                    size_dist[v] += 1;
+                } else {
+                    size_dist[0] += 1;
+                }
+            }
+        }
+        Ok(size_dist)
+    }
+}
+
+/// Delegate the AnnData interface to the wrapped object.
+impl<A: AnnDataOp> AnnDataOp for FragmentFileData<A> {
+    type X = A::X;
+    type ElemCollectionRef<'a> = A::ElemCollectionRef<'a> where Self: 'a;
+    type AxisArraysRef<'a> = A::AxisArraysRef<'a> where Self: 'a;
+
+    fn x(&self) -> Self::X {
+        self.adata.x()
+    }
+
+    fn set_x_from_iter<I, D>(&self, iter: I) -> Result<()>
+    where
+        I: Iterator<Item = D>,
+        D: ArrayChunk + Into<ArrayData>,
+    {
+        self.adata.set_x_from_iter(iter)
+    }
+
+    fn set_x<D: WriteArrayData + Into<ArrayData> + HasShape>(&self, data: D) -> Result<()> {
+        self.adata.set_x(data)
+    }
+
+    fn del_x(&self) -> Result<()> {
+        self.adata.del_x()
+    }
+
+    fn n_obs(&self) -> usize {
+        self.adata.n_obs()
+    }
+
+    fn n_vars(&self) -> usize {
+        self.adata.n_vars()
+    }
+
+    fn obs_names(&self) -> DataFrameIndex {
+        self.adata.obs_names()
+    }
+
+    fn var_names(&self) -> DataFrameIndex {
+        self.adata.var_names()
+    }
+
+    fn set_obs_names(&self, index: DataFrameIndex) -> Result<()> {
+        self.adata.set_obs_names(index)
+    }
+
+    fn set_var_names(&self, index: DataFrameIndex) -> Result<()> {
+        self.adata.set_var_names(index)
+    }
+
+    fn obs_ix<'a, I: IntoIterator<Item = &'a str>>(&self, names: I) -> Result<Vec<usize>> {
+        self.adata.obs_ix(names)
+    }
+
+    fn var_ix<'a, I: IntoIterator<Item = &'a str>>(&self, names: I) -> Result<Vec<usize>> {
+        self.adata.var_ix(names)
+    }
+
+    fn read_obs(&self) -> Result<DataFrame> {
+        self.adata.read_obs()
+    }
+
+    fn read_var(&self) -> Result<DataFrame> {
+        self.adata.read_var()
+    }
+
+    fn set_obs(&self, obs: DataFrame) -> Result<()> {
+        self.adata.set_obs(obs)
+    }
+
+    fn set_var(&self, var: DataFrame) -> Result<()> {
+        self.adata.set_var(var)
+    }
+
+    fn del_obs(&self) -> Result<()> {
+        self.adata.del_obs()
+    }
+
+    fn del_var(&self) -> Result<()> {
+        self.adata.del_var()
+    }
+
+    fn uns(&self) -> Self::ElemCollectionRef<'_> {
+        self.adata.uns()
+    }
+
+    fn obsm(&self) -> Self::AxisArraysRef<'_> {
+        self.adata.obsm()
+    }
+
+    fn obsp(&self) -> Self::AxisArraysRef<'_> {
+        self.adata.obsp()
+    }
+
+    fn varm(&self) -> Self::AxisArraysRef<'_> {
+        self.adata.varm()
+    }
+
+    fn varp(&self) -> Self::AxisArraysRef<'_> {
+        self.adata.varp()
+    }
+
+    fn del_uns(&self) -> Result<()> {
+        self.adata.del_uns()
+    }
+
+    fn del_obsm(&self) -> Result<()> {
+        self.adata.del_obsm()
+    }
+
+    fn del_obsp(&self) -> Result<()> {
+        self.adata.del_obsp()
+    }
+
+    fn del_varm(&self) -> Result<()> {
+        self.adata.del_varm()
+    }
+
+    fn del_varp(&self) -> Result<()> {
+        self.adata.del_varp()
+    }
+
+    fn layers(&self) -> Self::AxisArraysRef<'_> {
+        self.adata.layers()
+    }
+
+    fn del_layers(&self) -> Result<()> {
+        self.adata.del_layers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anndata::AnnData;
+    use anndata_hdf5::H5;
+    use std::io::Write;
+
+    fn round_trip(records: &[&str], order: FragmentOrder) -> Vec<(String, Vec<(String, u64, u64)>)> {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("fragments.tsv");
+        let mut writer = std::fs::File::create(&file).unwrap();
+        records.iter().for_each(|x| writeln!(writer, "{}", x).unwrap());
+        drop(writer);
+
+        let (barcodes, is_paired) = scan_barcodes(&file, order, None).unwrap();
+        assert!(is_paired);
+        let chrom_sizes: ChromSizes = [("chr1", 1000), ("chr2", 1000)].into_iter().collect();
+        let chunks = FragmentChunks {
+            fragments: read_fragments_by_barcode(&file, order, Some(dir.path().to_path_buf()))
+                .unwrap().peekable(),
+            barcodes: barcodes.clone(),
+            genome_index: GenomeBaseIndex::new(&chrom_sizes),
+            is_paired,
+            chunk_size: 2,
+            current: 0,
+        };
+        let cells: Vec<_> = GenomeCoverage::new(chrom_sizes, chunks).into_raw()
+            .flat_map(|(x, _, _)| x).collect();
+        barcodes.into_iter().zip(cells).map(|(bc, frags)|
+            (bc, frags.into_iter().map(|x| (x.chrom, x.start, x.end)).collect())
+        ).collect()
+    }
+
+    #[test]
+    fn test_fragment_file() {
+        let by_name = [
+            "chr1\t10\t60\tAAA\t1",
+            "chr2\t5\t50\tAAA\t1",
+            "chr1\t20\t80\tCCC\t1",
+            "chr1\t30\t90\t.\t1",
+            "chr1\t15\t40\tBBB\t1",
+            "chr1\t100\t200\tBBB\t1",
+        ];
+        let expected = vec![
+            ("AAA".to_string(), vec![("chr1".to_string(), 10, 60), ("chr2".to_string(), 5, 50)]),
+            ("CCC".to_string(), vec![("chr1".to_string(), 20, 80)]),
+            ("BBB".to_string(), vec![("chr1".to_string(), 15, 40), ("chr1".to_string(), 100, 200)]),
+        ];
+        assert_eq!(round_trip(&by_name, FragmentOrder::Barcode), expected);
+
+        let by_coordinate = [
+            "chr1\t10\t60\tAAA\t1",
+            "chr1\t15\t40\tBBB\t1",
+            "chr1\t20\t80\tCCC\t1",
+            "chr1\t30\t90\t.\t1",
+            "chr1\t100\t200\tBBB\t1",
+            "chr2\t5\t50\tAAA\t1",
+        ];
+        let mut expected = expected;
+        expected.sort();
+        assert_eq!(round_trip(&by_coordinate, FragmentOrder::Coordinate), expected);
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("fragments.tsv");
+        std::fs::write(&file, "chr1\t10\t60\tAAA\t1\nchr1\tten\t60\tAAA\t1\n").unwrap();
+        assert!(scan_barcodes(&file, FragmentOrder::Barcode, None).is_err());
+        let adata = AnnData::<H5>::new(dir.path().join("data.h5ad")).unwrap();
+        let chrom_sizes: ChromSizes = [("chr1", 1000)].into_iter().collect();
+        assert!(FragmentFileData::open(adata, &file, &chrom_sizes, None, None).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "cells are missing")]
+    fn test_fragment_file_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("fragments.tsv");
+        std::fs::write(&file, "chr1\t10\t60\tAAA\t1\nchr1\t20\t60\tBBB\t1\n").unwrap();
+        let adata = AnnData::<H5>::new(dir.path().join("data.h5ad")).unwrap();
+        let chrom_sizes: ChromSizes = [("chr1", 1000)].into_iter().collect();
+        let data = FragmentFileData::open(adata, &file, &chrom_sizes, None, None).unwrap();
+        std::fs::write(&file, "chr1\t10\t60\tAAA\t1\n").unwrap();
+        data.get_count_iter(2).unwrap().into_raw().for_each(drop);
+    }
+}
@@ -23,7 +23,7 @@ use bed_utils::bed::tree::GenomeRegions;
 use anyhow::Result;
 use std::{collections::{BTreeMap, HashMap}, fmt::Debug, io::BufRead};
 use indexmap::map::IndexMap;
-use bed_utils::bed::{GenomicRange, BEDLike, tree::SparseCoverage};
+use bed_utils::bed::{GenomicRange, BEDLike, tree::{BedTree, SparseCoverage}};
 use itertools::Itertools;
 use num::traits::{ToPrimitive, NumCast};
 use anndata::data::utils::to_csr_data;
@@ -358,6 +358,182 @@ impl FeatureCounter for GeneCount<'_> {
     }
 }
 
+/// Parameters of gene activity scores with exponential distance decay, following the
+/// gene score model of ArchR.
+#[derive(Debug, Clone)]
+pub struct GeneScoreOptions {
+    /// Insertions at distance `d` from a gene are weighted by `exp(-d / decay) + offset`.
+    pub decay: f64,
+    pub offset: f64,
+    /// Minimum and maximum extensions of gene domains upstream of TSSs.
+    pub upstream: (u64, u64),
+    /// Minimum and maximum extensions of gene domains downstream of gene ends,
+    /// or of TSSs if `include_gene_body` is false.
+    pub downstream: (u64, u64),
+    /// Whether gene domains stop at the boundaries of neighbouring genes.
+    /// Minimum extensions are always applied.
+    pub use_gene_boundaries: bool,
+    /// Whether distances are measured from gene bodies, in which case insertions
+    /// within gene bodies receive the maximal weight. Otherwise, distances are
+    /// measured from TSSs.
+    pub include_gene_body: bool,
+    /// If set, gene weights are inversely proportional to gene lengths, linearly
+    /// scaled to the range from 1 to this value.
+    pub gene_scale_factor: Option<f64>,
+}
+
+impl Default for GeneScoreOptions {
+    fn default() -> Self {
+        Self {
+            decay: 5000.0,
+            offset: (-1.0f64).exp(),
+            upstream: (1000, 100000),
+            downstream: (1000, 100000),
+            use_gene_boundaries: true,
+            include_gene_body: true,
+            gene_scale_factor: Some(5.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct GeneDomain {
+    name: String,
+    /// Distances are measured from this region, i.e., the gene body or the TSS.
+    anchor: GenomicRange,
+    weight: f64,
+}
+
+/// Gene domains and weights used by `GeneScore`.
+pub struct GeneScoreModel {
+    genes: Vec<GeneDomain>,
+    domains: BedTree<usize>,
+    opts: GeneScoreOptions,
+}
+
+impl GeneScoreModel {
+    /// Build gene domains from transcripts. Transcripts are merged into genes by
+    /// gene names, and the body of a gene spans all its transcripts on the
+    /// chromosome of its first transcript.
+    pub fn new(transcripts: &[Transcript], opts: GeneScoreOptions) -> Self {
+        let mut genes: IndexMap<&str, (&str, u64, u64, Strand)> = IndexMap::new();
+        transcripts.iter().for_each(|x| {
+            let left = <Position as TryInto<usize>>::try_into(x.left).unwrap() as u64 - 1;
+            let right = <Position as TryInto<usize>>::try_into(x.right).unwrap() as u64;
+            let gene = genes.entry(x.gene_name.as_str()).or_insert((x.chrom.as_str(), left, right, x.strand));
+            if gene.0 == x.chrom {
+                gene.1 = gene.1.min(left);
+                gene.2 = gene.2.max(right);
+            }
+        });
+
+        // Sorted gene boundaries of each chromosome.
+        let mut boundaries: HashMap<&str, (Vec<u64>, Vec<u64>)> = HashMap::new();
+        genes.values().for_each(|(chrom, start, end, _)| {
+            let b = boundaries.entry(*chrom).or_default();
+            b.0.push(*start);
+            b.1.push(*end);
+        });
+        boundaries.values_mut().for_each(|(starts, ends)| {
+            starts.sort_unstable();
+            ends.sort_unstable();
+        });
+
+        let inv_len: Vec<f64> = genes.values().map(|(_, start, end, _)| 1.0 / (end - start).max(1) as f64).collect();
+        let (min, max) = inv_len.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(a, b), x| (a.min(*x), b.max(*x)));
+        let mut domains = Vec::new();
+        let genes = genes.into_iter().zip(inv_len).enumerate().map(|(i, ((name, (chrom, start, end, strand)), m))| {
+            let forward = match strand {
+                Strand::Forward => true,
+                Strand::Reverse => false,
+                _ => panic!("Miss strand information for {}", name),
+            };
+            let (body_start, body_end) = (start, end);
+            let (start, end) = if opts.include_gene_body {
+                (start, end)
+            } else if forward {
+                (start, start + 1)
+            } else {
+                (end - 1, end)
+            };
+            let (left_ext, right_ext) = if forward { (opts.upstream, opts.downstream) } else { (opts.downstream, opts.upstream) };
+            let mut left = start.saturating_sub(left_ext.1);
+            let mut right = end + right_ext.1;
+            if opts.use_gene_boundaries {
+                let (starts, ends) = &boundaries[chrom];
+                let n = ends.partition_point(|x| *x <= body_start);
+                if n > 0 {
+                    left = left.max(ends[n - 1]);
+                }
+                if let Some(x) = starts.get(starts.partition_point(|x| *x < body_end)) {
+                    right = right.min(*x);
+                }
+            }
+            left = left.min(start.saturating_sub(left_ext.0));
+            right = right.max(end + right_ext.0);
+            domains.push((GenomicRange::new(chrom, left, right), i));
+
+            let weight = match opts.gene_scale_factor {
+                Some(factor) if max > min => 1.0 + (m - min) * (factor - 1.0) / (max - min),
+                _ => 1.0,
+            };
+            GeneDomain { name: name.to_string(), anchor: GenomicRange::new(chrom, start, end), weight }
+        }).collect();
+        Self { genes, domains: domains.into_iter().collect(), opts }
+    }
+
+    /// Weight of an insertion at `pos` for the `i`-th gene.
+    pub fn weight(&self, i: usize, pos: u64) -> f64 {
+        let gene = &self.genes[i];
+        let d = if pos < gene.anchor.start() {
+            gene.anchor.start() - pos
+        } else if pos >= gene.anchor.end() {
+            pos + 1 - gene.anchor.end()
+        } else {
+            0
+        };
+        ((-(d as f64) / self.opts.decay).exp() + self.opts.offset) * gene.weight
+    }
+}
+
+/// `GeneScore` computes gene activity scores, i.e., the sums of insertions weighted
+/// by their distances to genes. Regions longer than one base pair, e.g., tiles,
+/// are represented by their midpoints. Likewise, under `CountingStrategy::Fragment`
+/// only the midpoint of each fragment is scored.
+#[derive(Clone)]
+pub struct GeneScore<'a> {
+    model: &'a GeneScoreModel,
+    scores: BTreeMap<usize, f64>,
+}
+
+impl<'a> GeneScore<'a> {
+    pub fn new(model: &'a GeneScoreModel) -> Self {
+        Self { model, scores: BTreeMap::new() }
+    }
+}
+
+impl FeatureCounter for GeneScore<'_> {
+    type Value = f32;
+
+    fn reset(&mut self) { self.scores.clear(); }
+
+    fn insert<B: BEDLike, N: ToPrimitive + Copy>(&mut self, tag: &B, count: N) {
+        let pos = tag.start() + (tag.end() - tag.start()) / 2;
+        let count = count.to_f64().unwrap();
+        self.model.domains.find(&GenomicRange::new(tag.chrom(), pos, pos + 1)).for_each(|(_, i)|
+            *self.scores.entry(*i).or_insert(0.0) += self.model.weight(*i, pos) * count
+        );
+    }
+
+    fn get_feature_ids(&self) -> Vec<String> {
+        self.model.genes.iter().map(|x| x.name.clone()).collect()
+    }
+
+    fn get_counts(&self) -> Vec<(usize, Self::Value)> {
+        self.scores.iter().map(|(k, v)| (*k, *v as f32)).collect()
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ChromSizes(IndexMap<String, u64>);
 
@@ -606,6 +782,65 @@ impl GenomeBaseIndex {
     }
 }
 
+/// Non-overlapping genomic regions used as variable-width bins, e.g., bins
+/// adapted to gene density. Bins are ordered by chromosomes, in the order of
+/// their first appearance, and then by start positions.
+#[derive(Debug, Clone)]
+pub struct GenomePartition {
+    chroms: IndexMap<String, (usize, Vec<u64>, Vec<u64>)>,
+    len: usize,
+}
+
+impl GenomePartition {
+    pub fn new<I: IntoIterator<Item = GenomicRange>>(regions: I) -> Result<Self> {
+        let mut chroms: IndexMap<String, Vec<(u64, u64)>> = IndexMap::new();
+        regions.into_iter().for_each(|x|
+            chroms.entry(x.chrom().to_string()).or_default().push((x.start(), x.end()))
+        );
+        let mut len = 0;
+        let chroms = chroms.into_iter().map(|(chrom, mut bins)| {
+            bins.sort_unstable();
+            if let Some((a, b)) = bins.iter().tuple_windows().find(|(a, b)| a.1 > b.0) {
+                anyhow::bail!("bins {}:{}-{} and {}:{}-{} overlap", chrom, a.0, a.1, chrom, b.0, b.1);
+            }
+            let offset = len;
+            len += bins.len();
+            let (starts, ends) = bins.into_iter().unzip();
+            Ok((chrom, (offset, starts, ends)))
+        }).collect::<Result<_>>()?;
+        Ok(Self { chroms, len })
+    }
+
+    /// Number of bins.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Indices of the bins overlapping the closed interval `[start, end]`.
+    pub fn get_bins(&self, chrom: &str, start: u64, end: u64) -> Range<usize> {
+        match self.chroms.get(chrom) {
+            None => 0..0,
+            Some((offset, starts, ends)) => {
+                let lo = ends.partition_point(|x| *x <= start);
+                let hi = starts.partition_point(|x| *x <= end).max(lo);
+                offset + lo..offset + hi
+            },
+        }
+    }
+
+    pub fn get_region(&self, i: usize) -> GenomicRange {
+        let (chrom, (offset, starts, ends)) = self.chroms.iter()
+            .take_while(|(_, (offset, _, _))| *offset <= i).last().unwrap();
+        GenomicRange::new(chrom, starts[i - offset], ends[i - offset])
+    }
+
+    pub fn get_regions(&self) -> impl Iterator<Item = GenomicRange> + '_ {
+        self.chroms.iter().flat_map(|(chrom, (_, starts, ends))|
+            starts.iter().zip(ends).map(move |(s, e)| GenomicRange::new(chrom, *s, *e))
+        )
+    }
+}
+
 /// `ChromValues` is a type alias for a vector of `BedGraph<N>` objects.
 /// Each `BedGraph` instance represents a genomic region along with a
 /// numerical value (like coverage or score).
@@ -629,9 +864,10 @@ where
     pub fn aggregate_by<C>(
         self,
         mut counter: C,
-    ) -> impl ExactSizeIterator<Item = (CsrMatrix<T>, usize, usize)>
+    ) -> impl ExactSizeIterator<Item = (CsrMatrix<C::Value>, usize, usize)>
     where
-        C: FeatureCounter<Value = T> + Clone + Sync,
+        C: FeatureCounter + Clone + Sync,
+        C::Value: Send,
         T: Sync + Send + num::ToPrimitive,
     {
         let n_col = counter.num_features();
@@ -698,6 +934,74 @@ mod tests {
     use bed_utils::bed::BEDLike;
     use std::str::FromStr;
 
+    #[test]
+    fn test_gene_score() {
+        let transcript = |name: &str, left: usize, right: usize, strand| Transcript {
+            transcript_name: None,
+            transcript_id: name.to_string(),
+            gene_name: name.to_string(),
+            gene_id: name.to_string(),
+            is_coding: None,
+            chrom: "chr1".to_string(),
+            left: Position::try_from(left).unwrap(),
+            right: Position::try_from(right).unwrap(),
+            strand,
+        };
+        let transcripts = vec![
+            transcript("A", 10001, 20000, Strand::Forward),
+            transcript("B", 30001, 31000, Strand::Reverse),
+        ];
+        let opts = GeneScoreOptions { gene_scale_factor: None, ..Default::default() };
+        let model = GeneScoreModel::new(&transcripts, opts.clone());
+        let find = |pos| model.domains.find(&GenomicRange::new("chr1", pos, pos + 1)).map(|x| *x.1).sorted().collect::<Vec<_>>();
+        assert_eq!(find(0), vec![0]);
+        assert_eq!(find(19999), vec![0]);
+        assert_eq!(find(20000), vec![0, 1]);
+        assert_eq!(find(30000), vec![1]);
+        assert_eq!(find(130999), vec![1]);
+        assert!(find(131000).is_empty());
+
+        let mut counter = GeneScore::new(&model);
+        counter.insert(&GenomicRange::new("chr1", 15000, 15001), 1);
+        counter.insert(&GenomicRange::new("chr1", 25000, 25001), 2);
+        let counts = counter.get_counts();
+        let expected = [
+            1.0 + opts.offset + 2.0 * ((-5001.0f64 / 5000.0).exp() + opts.offset),
+            2.0 * ((-1.0f64).exp() + opts.offset),
+        ];
+        assert_eq!(counts.len(), 2);
+        counts.into_iter().for_each(|(i, v)| assert!((v as f64 - expected[i]).abs() < 1e-4));
+
+        // The longest gene has weight 1 and the shortest gene has weight `factor`.
+        let transcripts = vec![
+            transcripts[0].clone(),
+            transcripts[1].clone(),
+            transcript("C", 50001, 55000, Strand::Forward),
+        ];
+        let model = GeneScoreModel::new(&transcripts, GeneScoreOptions { gene_scale_factor: Some(5.0), ..opts });
+        assert_eq!(model.genes[0].weight, 1.0);
+        assert_eq!(model.genes[1].weight, 5.0);
+        assert!(model.genes[2].weight > 1.0 && model.genes[2].weight < 5.0);
+    }
+
+    #[test]
+    fn test_partition() {
+        let partition = GenomePartition::new(
+            ["chr1:100-200", "chr2:0-50", "chr1:0-100", "chr1:300-400"].into_iter()
+                .map(|x| GenomicRange::from_str(x).unwrap())
+        ).unwrap();
+        assert_eq!(partition.len(), 4);
+        assert_eq!(partition.get_bins("chr1", 150, 150), 1..2);
+        assert_eq!(partition.get_bins("chr1", 250, 250), 2..2);
+        assert_eq!(partition.get_bins("chr1", 50, 350), 0..3);
+        assert_eq!(partition.get_bins("chr2", 10, 10), 3..4);
+        assert_eq!(partition.get_bins("chr3", 10, 10), 0..0);
+        assert_eq!(partition.get_region(2).pretty_show(), "chr1:300-400");
+        assert!(GenomePartition::new(
+            ["chr1:0-100", "chr1:50-150"].into_iter().map(|x| GenomicRange::from_str(x).unwrap())
+        ).is_err());
+    }
+
     #[test]
     fn test_index1() {
         let chrom_sizes = vec![
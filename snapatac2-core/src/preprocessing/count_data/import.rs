@@ -1,5 +1,5 @@
 use crate::preprocessing::{
-    count_data::{ChromSizes, GenomeBaseIndex},
+    count_data::{ChromSizes, ContactIndex, ContactLayout, GenomeBaseIndex},
     qc::{Fragment, Contact, FragmentSummary, QualityControl},
 };
 
@@ -129,7 +129,7 @@ where
     from_csr_data(r, c, offset, ind, data).unwrap()
 }
 
-fn count_fragments<V>(
+pub(crate) fn count_fragments<V>(
     mitochrondrial_dna: &HashSet<String>,
     genome_index: &GenomeBaseIndex,
     fragments: Vec<Fragment>,
@@ -188,11 +188,15 @@ fn qc_to_df(qc: Vec<QualityControl>) -> DataFrame {
     .unwrap()
 }
 
-/// Import scHi-C contacts into AnnData
+/// Import scHi-C contacts into AnnData.
+/// Contacts are canonicalized to the upper triangle and stored using the layout
+/// described in `ContactIndex`. If `max_distance` is provided, only intra-chromosomal
+/// contacts whose loci are at most `max_distance` bp apart are kept.
 pub fn import_contacts<A, B, I>(
     anndata: &A,
     contacts: I,
     regions: &GenomeRegions<B>,
+    max_distance: Option<u64>,
     chunk_size: usize,
 ) -> Result<()>
 where
@@ -205,9 +209,11 @@ where
         .iter()
         .map(|x| x.chrom())
         .zip(regions.regions.iter().map(|x| x.end())).collect();
- 
-    let genome_index = GenomeBaseIndex::new(&chrom_sizes);
-    let genome_size = genome_index.len();
+    let layout = match max_distance {
+        Some(d) => ContactLayout::Banded(d),
+        None => ContactLayout::UpperTriangle,
+    };
+    let contact_index = ContactIndex::new(&chrom_sizes, layout);
 
     let spinner = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr_with_hz(1))
         .with_style(
@@ -238,15 +244,14 @@ where
                     .map(|x| {
                         let mut count = BTreeMap::new();
                         x.into_iter().for_each(|c| {
-                            let pos1 = genome_index.get_position_rev(&c.chrom1, c.start1);
-                            let pos2 = genome_index.get_position_rev(&c.chrom2, c.start2);
-                            let i = pos1 * genome_size + pos2; 
-                            count.entry(i).and_modify(|x| *x += c.count).or_insert(c.count);
+                            if let Some(i) = contact_index.encode(&c.chrom1, c.start1, &c.chrom2, c.start2) {
+                                count.entry(i).and_modify(|x| *x += c.count).or_insert(c.count);
+                            }
                         });
                         count.into_iter().collect::<Vec<_>>()
                     }).collect();
 
-                let (r, c, offset, ind, data) = to_csr_data(counts, genome_size*genome_size);
+                let (r, c, offset, ind, data) = to_csr_data(counts, contact_index.len());
                 CsrMatrix::try_from_csr_data(r, c, offset, ind, data).unwrap()
             }),
     )?;
@@ -268,6 +273,7 @@ where
             ),
         ])?,
     )?;
+    anndata.uns().add("contact_layout", layout.to_dataframe())?;
     anndata.set_obs_names(scanned_barcodes.into_iter().collect())?;
     Ok(())
 }
\ No newline at end of file
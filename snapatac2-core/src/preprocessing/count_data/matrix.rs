@@ -1,25 +1,46 @@
 use crate::preprocessing::count_data::{
-    SnapData,
+    SnapData, CountingStrategy, GenomeCoverage, CoverageType,
     FeatureCounter, TranscriptCount, GeneCount,
+    GeneScore, GeneScoreModel, GeneScoreOptions, GenomePartition,
     Promoters, Transcript,
 };
 
-use anndata::{AnnDataOp, AxisArraysOp};
+use anndata::{data::DataFrameIndex, AnnDataOp, AxisArraysOp};
 use indicatif::{ProgressIterator, ProgressStyle};
 use polars::prelude::{NamedFrom, DataFrame, Series};
-use anyhow::Result;
-use bed_utils::bed::{BEDLike, tree::{GenomeRegions, SparseCoverage}};
+use anyhow::{bail, ensure, Result};
+use bed_utils::bed::{BEDLike, GenomicRange, tree::{GenomeRegions, SparseCoverage}};
 
 
+type FragmentCounts = GenomeCoverage<Box<dyn ExactSizeIterator<Item = (CoverageType, usize, usize)>>>;
+
+fn fragment_counts<A: SnapData>(
+    adata: &A,
+    chunk_size: usize,
+    strategy: CountingStrategy,
+    fragment_size_range: Option<(u64, u64)>,
+) -> Result<FragmentCounts> {
+    let mut counts = adata.get_count_iter(chunk_size)?.with_counting_strategy(strategy);
+    if let Some((min, max)) = fragment_size_range {
+        counts = counts.with_fragment_size_range(min, max)?;
+    }
+    Ok(counts)
+}
+
 /// Create cell by bin matrix.
 /// 
 /// # Arguments
 /// 
 /// * `anndata` - 
+/// * `strategy` - How paired-end fragments are counted. Ignored for contact data.
+/// * `fragment_size_range` - If provided, only fragments with sizes in `[min, max)`
+///   are counted. Ignored for contact data.
 pub fn create_tile_matrix<A, B>(
     adata: &A,
     bin_size: usize,
     chunk_size: usize,
+    strategy: CountingStrategy,
+    fragment_size_range: Option<(u64, u64)>,
     exclude_chroms: Option<&[&str]>,
     out: Option<&B>,
     ) -> Result<()>
@@ -45,7 +66,8 @@ where
             adata.set_var_names(adata.n_vars().into())?;
         }
     } else {
-        let mut counts = adata.get_count_iter(chunk_size)?.with_resolution(bin_size);
+        let mut counts = fragment_counts(adata, chunk_size, strategy, fragment_size_range)?
+            .with_resolution(bin_size);
         if let Some(exclude_chroms) = exclude_chroms {
             counts = counts.exclude(exclude_chroms);
         }
@@ -63,10 +85,100 @@ where
     Ok(())
 }
 
+/// Create cell by bin matrices at several resolutions in a single pass over
+/// the fragments. The matrix with bin size `bin_sizes[i]` is written to `outs[i]`.
+pub fn create_tile_matrices<A, B>(
+    adata: &A,
+    bin_sizes: &[usize],
+    chunk_size: usize,
+    strategy: CountingStrategy,
+    fragment_size_range: Option<(u64, u64)>,
+    exclude_chroms: Option<&[&str]>,
+    outs: &[&B],
+    ) -> Result<()>
+where
+    A: SnapData,
+    B: AnnDataOp + Sync,
+{
+    ensure!(bin_sizes.len() == outs.len(), "the numbers of bin sizes and outputs differ");
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})"
+    ).unwrap();
+
+    let mut counts = fragment_counts(adata, chunk_size, strategy, fragment_size_range)?;
+    if let Some(exclude_chroms) = exclude_chroms {
+        counts = counts.exclude(exclude_chroms);
+    }
+    let feature_names: Vec<DataFrameIndex> = bin_sizes.iter()
+        .map(|s| counts.get_gindex().with_step(*s).to_index().into())
+        .collect();
+    let data_iter = counts.into_multi_resolution_values::<u32>(bin_sizes).progress_with_style(style);
+
+    // Each output is written by its own thread, which receives the matrices of
+    // its resolution as they are computed.
+    std::thread::scope(|scope| {
+        let (senders, writers): (Vec<_>, Vec<_>) = outs.iter().map(|out| {
+            let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+            let writer = scope.spawn(move || out.set_x_from_iter(receiver.into_iter()));
+            (sender, writer)
+        }).unzip();
+        for (mats, _, _) in data_iter {
+            // Stop early if a writer has failed. The error is reported below.
+            if senders.iter().zip(mats).any(|(sender, mat)| sender.send(mat).is_err()) {
+                break;
+            }
+        }
+        drop(senders);
+        writers.into_iter().try_for_each(|writer| writer.join().unwrap())
+    })?;
+    for (out, names) in outs.iter().zip(feature_names) {
+        out.set_obs_names(adata.obs_names())?;
+        out.set_var_names(names)?;
+    }
+    Ok(())
+}
+
+/// Create cell by bin matrix, where bins are non-overlapping regions provided
+/// by users, e.g., bins adapted to gene density.
+pub fn create_partition_matrix<A, I, B>(
+    adata: &A,
+    bins: I,
+    chunk_size: usize,
+    strategy: CountingStrategy,
+    fragment_size_range: Option<(u64, u64)>,
+    out: Option<&B>,
+    ) -> Result<()>
+where
+    A: SnapData,
+    I: IntoIterator<Item = GenomicRange>,
+    B: AnnDataOp,
+{
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})"
+    ).unwrap();
+    let partition = GenomePartition::new(bins)?;
+    let feature_names: Vec<String> = partition.get_regions().map(|x| x.pretty_show()).collect();
+    let data_iter = fragment_counts(adata, chunk_size, strategy, fragment_size_range)?
+        .into_partition_values::<u32>(partition)
+        .map(|x| x.0)
+        .progress_with_style(style);
+    if let Some(adata_out) = out {
+        adata_out.set_x_from_iter(data_iter)?;
+        adata_out.set_obs_names(adata.obs_names())?;
+        adata_out.set_var_names(feature_names.into())?;
+    } else {
+        adata.set_x_from_iter(data_iter)?;
+        adata.set_var_names(feature_names.into())?;
+    }
+    Ok(())
+}
+
 pub fn create_peak_matrix<A, I, D, B>(
     adata: &A,
     peaks: I,
     chunk_size: usize,
+    strategy: CountingStrategy,
+    fragment_size_range: Option<(u64, u64)>,
     out: Option<&B>,
     use_x: bool,
     ) -> Result<()>
@@ -86,7 +198,7 @@ where
         Box::new(adata.read_chrom_values(chunk_size)?
             .aggregate_by(counter).map(|x| x.0))
     } else {
-        Box::new(adata.get_count_iter(chunk_size)?
+        Box::new(fragment_counts(adata, chunk_size, strategy, fragment_size_range)?
             .aggregate_by(counter).map(|x| x.0))
     };
     if let Some(adata_out) =  out {
@@ -100,11 +212,34 @@ where
     Ok(())
 }
 
+/// How gene-level features are quantified.
+#[derive(Debug, Clone)]
+pub enum GeneQuantification {
+    /// Count insertions in promoters, i.e., regions extended from TSSs, and
+    /// optionally in gene bodies, with equal weights.
+    Count {
+        upstream: u64,
+        downstream: u64,
+        include_gene_body: bool,
+    },
+    /// Compute gene activity scores with exponential distance decay.
+    Score(GeneScoreOptions),
+}
+
+impl Default for GeneQuantification {
+    fn default() -> Self {
+        Self::Count { upstream: 2000, downstream: 0, include_gene_body: true }
+    }
+}
+
 pub fn create_gene_matrix<A, B>(
     adata: &A,
     transcripts: Vec<Transcript>,
     id_type: &str, 
+    quantification: GeneQuantification,
     chunk_size: usize,
+    strategy: CountingStrategy,
+    fragment_size_range: Option<(u64, u64)>,
     out: Option<&B>,
     use_x: bool,
     ) -> Result<()>
@@ -112,7 +247,35 @@ where
     A: SnapData,
     B: AnnDataOp,
 {
-    let promoters = Promoters::new(transcripts, 2000, 0, true);
+    let (upstream, downstream, include_gene_body) = match quantification {
+        GeneQuantification::Count { upstream, downstream, include_gene_body } =>
+            (upstream, downstream, include_gene_body),
+        GeneQuantification::Score(opts) => {
+            if id_type != "gene" {
+                bail!("gene scores can only be computed with id_type 'gene'");
+            }
+            let model = GeneScoreModel::new(&transcripts, opts);
+            let counter = GeneScore::new(&model);
+            let ids = counter.get_feature_ids();
+            let data: Box<dyn ExactSizeIterator<Item = _>> = if use_x {
+                Box::new(adata.read_chrom_values(chunk_size)?
+                    .aggregate_by(counter).map(|x| x.0))
+            } else {
+                Box::new(fragment_counts(adata, chunk_size, strategy, fragment_size_range)?
+                    .aggregate_by(counter).map(|x| x.0))
+            };
+            if let Some(adata_out) = out {
+                adata_out.set_x_from_iter(data)?;
+                adata_out.set_obs_names(adata.obs_names())?;
+                adata_out.set_var_names(ids.into())?;
+            } else {
+                adata.set_x_from_iter(data)?;
+                adata.set_var_names(ids.into())?;
+            }
+            return Ok(());
+        },
+    };
+    let promoters = Promoters::new(transcripts, upstream, downstream, include_gene_body);
     let transcript_counter: TranscriptCount<'_> = TranscriptCount::new(&promoters);
     match id_type {
         "transcript" => {
@@ -122,7 +285,7 @@ where
                 Box::new(adata.read_chrom_values(chunk_size)?
                     .aggregate_by(transcript_counter).map(|x| x.0))
             } else {
-                Box::new(adata.get_count_iter(chunk_size)?
+                Box::new(fragment_counts(adata, chunk_size, strategy, fragment_size_range)?
                     .aggregate_by(transcript_counter).map(|x| x.0))
             };
             if let Some(adata_out) = out {
@@ -143,7 +306,7 @@ where
                 Box::new(adata.read_chrom_values(chunk_size)?
                     .aggregate_by(gene_counter).map(|x| x.0))
             } else {
-                Box::new(adata.get_count_iter(chunk_size)?
+                Box::new(fragment_counts(adata, chunk_size, strategy, fragment_size_range)?
                     .aggregate_by(gene_counter).map(|x| x.0))
             };
             if let Some(adata_out) = out {
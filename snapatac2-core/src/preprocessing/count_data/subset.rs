@@ -0,0 +1,182 @@
+use crate::preprocessing::{
+    count_data::{import::count_fragments, SnapData, ChromSizes, GenomeBaseIndex},
+    qc::{Fragment, QualityControl},
+};
+
+use anndata::{
+    data::array::utils::{from_csr_data, to_csr_data},
+    AnnDataOp, ArrayData, AxisArraysOp, ElemCollectionOp,
+};
+use anyhow::{bail, Result};
+use bed_utils::bed::{tree::BedTree, BEDLike};
+use indicatif::{ProgressIterator, ProgressStyle};
+use polars::prelude::{DataFrame, NamedFrom, Series};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::collections::HashSet;
+
+/// QC metrics in `.obs` that are invalidated by subsetting the fragments.
+const STALE_QC_COLUMNS: [&str; 4] = ["frac_dup", "tsse", "nucleosome_signal", "nucleosome_periodicity"];
+
+/// Write a reduced copy of the fragment data, keeping only fragments located in
+/// the given chromosomes and/or overlapping the given regions.
+///
+/// All cells are retained. The `.uns["reference_sequences"]` of the output only
+/// contains the retained chromosomes, and the `n_fragment` and `frac_mito` columns
+/// of `.obs` are recomputed on the remaining fragments. Other fragment-derived QC
+/// metrics, i.e., `frac_dup`, `tsse`, `nucleosome_signal` and `nucleosome_periodicity`,
+/// cannot be recomputed from the stored fragments alone and are dropped. Other `.obs`
+/// columns are copied unchanged.
+///
+/// # Arguments
+///
+/// * `adata` - The input data.
+/// * `out` - The output AnnData object.
+/// * `chroms` - Chromosomes to keep.
+/// * `regions` - Genomic regions to keep. A fragment is kept if it overlaps any region.
+/// * `mitochrondrial_dna` - Names of mitochondrial chromosomes, used to recompute QC metrics.
+/// * `chunk_size` - The number of cells to process at a time.
+pub fn subset_fragments<A, O, I, D>(
+    adata: &A,
+    out: &O,
+    chroms: Option<&[&str]>,
+    regions: Option<I>,
+    mitochrondrial_dna: &HashSet<String>,
+    chunk_size: usize,
+) -> Result<()>
+where
+    A: SnapData,
+    O: AnnDataOp,
+    I: IntoIterator<Item = D>,
+    D: BEDLike,
+{
+    if chroms.is_none() && regions.is_none() {
+        bail!("either chromosomes or regions must be provided");
+    }
+
+    let chroms: Option<HashSet<&str>> = chroms.map(|x| x.iter().copied().collect());
+    let mut region_chroms = HashSet::new();
+    let regions: Option<BedTree<()>> = regions.map(|x| x.into_iter().map(|r| {
+        region_chroms.insert(r.chrom().to_string());
+        (r, ())
+    }).collect());
+
+    let chrom_sizes: ChromSizes = adata.read_chrom_sizes()?
+        .into_iter()
+        .filter(|(chr, _)|
+            chroms.as_ref().map_or(true, |x| x.contains(chr.as_str())) &&
+            (regions.is_none() || region_chroms.contains(chr))
+        )
+        .collect();
+    let genome_index = GenomeBaseIndex::new(&chrom_sizes);
+    let keep = |frag: &Fragment| {
+        genome_index.contain_chrom(frag.chrom()) &&
+            regions.as_ref().map_or(true, |x| x.is_overlapped(frag))
+    };
+
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})"
+    ).unwrap();
+    let is_paired = adata.fragment_is_paired()?;
+    let raw = adata.get_count_iter(chunk_size)?.into_raw();
+    let obsm_key = if is_paired { "fragment_paired" } else { "fragment_single" };
+
+    let mut qc = Vec::new();
+    let arrays = raw.progress_with_style(style).map(|(cells, _, _)| {
+        let cells: Vec<Vec<Fragment>> = cells.into_iter()
+            .map(|x| x.into_iter().filter(|f| keep(f)).collect())
+            .collect();
+        if is_paired {
+            make_arraydata::<u32>(cells, mitochrondrial_dna, &genome_index, &mut qc)
+        } else {
+            make_arraydata::<i32>(cells, mitochrondrial_dna, &genome_index, &mut qc)
+        }
+    });
+    out.obsm().add_iter(obsm_key, arrays)?;
+
+    let mut obs = adata.read_obs()?;
+    update_fragment_qc(&mut obs, &qc)?;
+    out.set_obs_names(adata.obs_names())?;
+    out.set_obs(obs)?;
+    out.uns().add("reference_sequences", chrom_sizes.to_dataframe())?;
+    Ok(())
+}
+
+/// Replace the fragment-derived QC metrics in `obs` after the fragments have been
+/// modified: `n_fragment` and `frac_mito` are set from `qc`, and the metrics that
+/// cannot be recomputed from the stored fragments are dropped.
+pub(crate) fn update_fragment_qc(obs: &mut DataFrame, qc: &[QualityControl]) -> Result<()> {
+    for col in STALE_QC_COLUMNS {
+        if obs.column(col).is_ok() {
+            obs.drop_in_place(col)?;
+        }
+    }
+    obs.with_column(Series::new(
+        "n_fragment",
+        qc.iter().map(|x| x.num_unique_fragment).collect::<Series>(),
+    ))?;
+    obs.with_column(Series::new(
+        "frac_mito",
+        qc.iter().map(|x| x.frac_mitochondrial).collect::<Series>(),
+    ))?;
+    Ok(())
+}
+
+pub(crate) fn make_arraydata<V>(
+    cells: Vec<Vec<Fragment>>,
+    mitochrondrial_dna: &HashSet<String>,
+    genome_index: &GenomeBaseIndex,
+    qc: &mut Vec<QualityControl>,
+) -> ArrayData
+where
+    V: TryFrom<i64> + Ord + std::marker::Send,
+    ArrayData: From<anndata::data::CsrNonCanonical<V>>,
+    ArrayData: From<nalgebra_sparse::CsrMatrix<V>>,
+    <V as TryFrom<i64>>::Error: std::fmt::Debug,
+{
+    let (q, counts): (Vec<_>, Vec<_>) = cells
+        .into_par_iter()
+        .map(|x| count_fragments::<V>(mitochrondrial_dna, genome_index, x))
+        .unzip();
+    qc.extend(q);
+    let (r, c, offset, ind, data) = to_csr_data(counts, genome_index.len());
+    from_csr_data(r, c, offset, ind, data).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anndata::{AnnData, data::CsrNonCanonical};
+    use anndata_hdf5::H5;
+    use bed_utils::bed::GenomicRange;
+
+    #[test]
+    fn test_subset_single_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let adata = AnnData::<H5>::new(dir.path().join("input.h5ad")).unwrap();
+        let chrom_sizes: ChromSizes = [("chr1", 1000), ("chr2", 1000)].into_iter().collect();
+        let genome_index = GenomeBaseIndex::new(&chrom_sizes);
+        // The first cell has no fragments, so the first chunk is empty.
+        let counts = vec![
+            vec![],
+            vec![
+                (genome_index.get_position_rev("chr1", 10), 50i32),
+                (genome_index.get_position_rev("chr2", 10), 50i32),
+            ],
+        ];
+        let (r, c, offset, ind, data) = to_csr_data(counts, genome_index.len());
+        let mat: CsrNonCanonical<i32> = from_csr_data(r, c, offset, ind, data).unwrap().try_into().unwrap();
+        adata.set_obs_names(vec!["a".to_string(), "b".to_string()].into()).unwrap();
+        adata.obsm().add("fragment_single", mat).unwrap();
+        adata.uns().add("reference_sequences", chrom_sizes.to_dataframe()).unwrap();
+
+        let out = AnnData::<H5>::new(dir.path().join("output.h5ad")).unwrap();
+        subset_fragments(
+            &adata, &out, Some(&["chr1"]), None::<Vec<GenomicRange>>, &HashSet::new(), 1,
+        ).unwrap();
+
+        assert_eq!(out.obsm().keys(), vec!["fragment_single".to_string()]);
+        let n_fragment: Vec<_> = out.read_obs().unwrap().column("n_fragment").unwrap()
+            .u64().unwrap().into_iter().flatten().collect();
+        assert_eq!(n_fragment, vec![0, 1]);
+    }
+}
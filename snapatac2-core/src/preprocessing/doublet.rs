@@ -0,0 +1,515 @@
+//! # Doublet Detection
+//!
+//! Two methods are provided:
+//!
+//! * Fragment-based doublet detection following AMULET (Thibodeau et al., 2021).
+//!   In a diploid genome, at most two fragments from a single nucleus can overlap
+//!   at any position. Loci covered by more than two fragments therefore indicate
+//!   that a barcode contains multiple nuclei. The number of such loci in each cell
+//!   is tested against a Poisson distribution whose mean is the average over all cells.
+//! * Scrublet (Wolock et al., 2019). Doublets are simulated by summing the counts of
+//!   random pairs of cells, and each cell is scored by the fraction of simulated
+//!   doublets among its nearest neighbours in a joint spectral embedding.
+use crate::{
+    knn::approximate_nearest_neighbour_graph,
+    preprocessing::{count_data::{CoverageType, GenomeCoverage}, Fragment},
+    utils::adjust_pvalues_bh,
+};
+
+use anndata::{data::{BoundedSelectInfoElem, SelectInfoElem}, AnnDataOp, ArrayElemOp, ArrayOp};
+use anyhow::{ensure, Context, Result};
+use bed_utils::bed::{tree::BedTree, GenomicRange};
+use itertools::{EitherOrBoth, Itertools};
+use log::info;
+use nalgebra::{DMatrix, SymmetricEigen};
+use nalgebra_sparse::CsrMatrix;
+use ndarray::{Array2, ArrayView2, Axis, s};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, IndexedParallelIterator, ParallelIterator};
+use statrs::function::gamma::gamma_lr;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone)]
+pub struct AmuletOptions {
+    /// Loci covered by more than this number of fragments are counted.
+    pub max_overlap: usize,
+    /// Chromosomes to ignore, e.g., sex chromosomes and mitochondrial DNA.
+    pub exclude_chroms: HashSet<String>,
+}
+
+impl Default for AmuletOptions {
+    fn default() -> Self {
+        Self {
+            max_overlap: 2,
+            exclude_chroms: ["chrX", "chrY", "chrM", "X", "Y", "MT"].into_iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Amulet {
+    /// Number of loci covered by more than `max_overlap` fragments in each cell.
+    pub num_overlaps: Vec<u64>,
+    pub pvalue: Vec<f64>,
+    pub qvalue: Vec<f64>,
+}
+
+/// Detect doublets using the AMULET algorithm.
+///
+/// # Arguments
+///
+/// * `coverage` - Paired-end fragments of cells.
+/// * `exclude` - Loci overlapping these regions, e.g., repeats and blacklist
+///   regions, are ignored.
+/// * `opts` - Parameters.
+pub fn amulet<I>(coverage: GenomeCoverage<I>, exclude: Option<&BedTree<()>>, opts: &AmuletOptions) -> Result<Amulet>
+where
+    I: ExactSizeIterator<Item = (CoverageType, usize, usize)>,
+{
+    let mut num_overlaps = Vec::new();
+    for (fragments, _, _) in coverage.into_raw() {
+        ensure!(
+            fragments.iter().flatten().all(|f| f.strand.is_none()),
+            "AMULET requires paired-end fragments",
+        );
+        num_overlaps.extend(fragments.into_par_iter().map(|x| count_overlaps(x, exclude, opts)).collect::<Vec<_>>());
+    }
+
+    let lambda = num_overlaps.iter().sum::<u64>() as f64 / num_overlaps.len() as f64;
+    let pvalue: Vec<f64> = num_overlaps.iter().map(|x|
+        if *x == 0 { 1.0 } else { gamma_lr(*x as f64, lambda) }
+    ).collect();
+    let qvalue = adjust_pvalues_bh(&pvalue);
+    Ok(Amulet { num_overlaps, pvalue, qvalue })
+}
+
+/// Count the loci covered by more than `opts.max_overlap` fragments.
+pub fn count_overlaps(mut fragments: Vec<Fragment>, exclude: Option<&BedTree<()>>, opts: &AmuletOptions) -> u64 {
+    fragments.retain(|x| !opts.exclude_chroms.contains(&x.chrom));
+    fragments.sort_by(|a, b| a.chrom.cmp(&b.chrom));
+    let mut count = 0;
+    fragments.iter().group_by(|x| x.chrom.as_str()).into_iter().for_each(|(chrom, group)| {
+        // Fragments are half-open intervals, so ends are processed before starts.
+        let events = group.flat_map(|x| [(x.start, 1i64), (x.end, -1)]).sorted();
+        let mut depth = 0;
+        let mut locus_start = None;
+        events.for_each(|(pos, delta)| {
+            depth += delta;
+            if depth > opts.max_overlap as i64 {
+                locus_start.get_or_insert(pos);
+            } else if let Some(start) = locus_start.take() {
+                let locus = GenomicRange::new(chrom, start, pos);
+                if !exclude.map_or(false, |x| x.is_overlapped(&locus)) {
+                    count += 1;
+                }
+            }
+        });
+    });
+    count
+}
+
+#[derive(Debug, Clone)]
+pub struct ScrubletOptions {
+    /// Number of components of the spectral embedding.
+    pub n_comps: usize,
+    /// Number of doublets to simulate relative to the number of observed cells.
+    pub sim_doublet_ratio: f64,
+    pub expected_doublet_rate: f64,
+    /// Number of nearest neighbours. If `None`, it is set to `round(0.5 * sqrt(n_obs))`.
+    pub n_neighbors: Option<usize>,
+    /// Number of subspace iterations used to compute the embedding.
+    pub n_iter: usize,
+    /// Number of rows processed at a time.
+    pub chunk_size: usize,
+    pub random_state: u64,
+}
+
+impl Default for ScrubletOptions {
+    fn default() -> Self {
+        Self {
+            n_comps: 15,
+            sim_doublet_ratio: 2.0,
+            expected_doublet_rate: 0.1,
+            n_neighbors: None,
+            n_iter: 10,
+            chunk_size: 5000,
+            random_state: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Scrublet {
+    /// Doublet scores of observed cells.
+    pub scores: Vec<f64>,
+    /// Doublet scores of simulated doublets.
+    pub sim_scores: Vec<f64>,
+    /// Parent cells of simulated doublets.
+    pub parents: Vec<(usize, usize)>,
+}
+
+/// Rows of the count matrix of observed cells, read on demand.
+pub trait RowSource {
+    fn nrows(&self) -> usize;
+    fn ncols(&self) -> usize;
+    /// Read the rows with the given sorted and unique indices.
+    fn read_rows(&self, rows: &[usize]) -> Result<CsrMatrix<f64>>;
+}
+
+impl RowSource for CsrMatrix<f64> {
+    fn nrows(&self) -> usize {
+        self.nrows()
+    }
+
+    fn ncols(&self) -> usize {
+        self.ncols()
+    }
+
+    fn read_rows(&self, rows: &[usize]) -> Result<CsrMatrix<f64>> {
+        Ok(self.select_axis(0, &SelectInfoElem::from(rows.to_vec())))
+    }
+}
+
+/// The selected features of `.X` in an AnnData object, read from the backend
+/// a chunk at a time.
+pub struct AnnDataRows<'a, A> {
+    adata: &'a A,
+    features: SelectInfoElem,
+    n_features: usize,
+}
+
+impl<'a, A: AnnDataOp> AnnDataRows<'a, A> {
+    pub fn new(adata: &'a A, features: SelectInfoElem) -> Self {
+        let n_features = BoundedSelectInfoElem::new(&features, adata.n_vars()).len();
+        Self { adata, features, n_features }
+    }
+}
+
+impl<A: AnnDataOp> RowSource for AnnDataRows<'_, A> {
+    fn nrows(&self) -> usize {
+        self.adata.n_obs()
+    }
+
+    fn ncols(&self) -> usize {
+        self.n_features
+    }
+
+    fn read_rows(&self, rows: &[usize]) -> Result<CsrMatrix<f64>> {
+        self.adata.x()
+            .slice(&[SelectInfoElem::from(rows.to_vec()), self.features.clone()])?
+            .context("the count matrix '.X' is empty")
+    }
+}
+
+/// Detect doublets using the Scrublet algorithm.
+///
+/// Neither the count matrix of observed cells nor the merged matrix of observed
+/// cells and simulated doublets is loaded into memory. Each pass of the embedding
+/// reads the observed cells chunk by chunk, and for each chunk of simulated doublets
+/// it reads the rows of their parents. Only dense matrices with `n_comps + 10`
+/// columns are kept for all cells.
+///
+/// # Arguments
+///
+/// * `mat` - Count matrix of cells, usually restricted to the selected features.
+/// * `opts` - Parameters.
+pub fn scrublet<M: RowSource>(mat: &M, opts: &ScrubletOptions) -> Result<Scrublet> {
+    let n_obs = mat.nrows();
+    ensure!(n_obs > 1 && mat.ncols() > 0, "matrix is empty");
+    let n_sim = (n_obs as f64 * opts.sim_doublet_ratio) as usize;
+    ensure!(n_sim > 0, "no doublets are simulated, please increase `sim_doublet_ratio`");
+    let mut rng = StdRng::seed_from_u64(opts.random_state);
+
+    info!("Simulating doublets...");
+    let parents = simulate_doublets(n_obs, n_sim, &mut rng);
+    let merged = MergedMatrix::new(mat, &parents, opts.chunk_size)?;
+
+    info!("Spectral embedding...");
+    let manifold = spectral_embedding(&merged, opts.n_comps, opts.n_iter, &mut rng)?;
+
+    info!("Calculating doublet scores...");
+    let k = opts.n_neighbors.unwrap_or_else(|| (0.5 * (n_obs as f64).sqrt()).round() as usize);
+    let mut scores = doublet_scores(manifold.view(), n_obs, k.max(1), opts.expected_doublet_rate);
+    let sim_scores = scores.split_off(n_obs);
+    Ok(Scrublet { scores, sim_scores, parents })
+}
+
+/// Randomly pair observed cells to simulate doublets.
+pub fn simulate_doublets<R: Rng>(n_obs: usize, n_sim: usize, rng: &mut R) -> Vec<(usize, usize)> {
+    (0..n_sim).map(|_| (rng.gen_range(0..n_obs), rng.gen_range(0..n_obs))).collect()
+}
+
+/// Compute doublet scores from an embedding whose first `n_obs` rows are observed
+/// cells and whose remaining rows are simulated doublets.
+pub fn doublet_scores(manifold: ArrayView2<f64>, n_obs: usize, k: usize, expected_doublet_rate: f64) -> Vec<f64> {
+    let rho = expected_doublet_rate;
+    let r = (manifold.nrows() - n_obs) as f64 / n_obs as f64;
+    // Adjust the number of neighbours based on the ratio of simulated to observed cells.
+    let k_adj = (k as f64 * (1.0 + r)).round() as usize;
+    let knn = approximate_nearest_neighbour_graph(&manifold.mapv(|x| x as f32), k_adj);
+    knn.row_iter().map(|row| {
+        let nd = row.col_indices().iter().filter(|j| **j >= n_obs).count() as f64;
+        let q = (nd + 1.0) / (row.nnz() as f64 + 2.0);
+        q * rho / r / (1.0 - rho - q * (1.0 - rho - rho / r))
+    }).collect()
+}
+
+type SparseRow = Vec<(usize, f64)>;
+
+/// Observed cells followed by simulated doublets. Rows are weighted by IDF,
+/// normalized to unit L2 norm, and then scaled by the inverse square root of
+/// their degrees in the cosine similarity graph.
+struct MergedMatrix<'a, M> {
+    mat: &'a M,
+    parents: &'a [(usize, usize)],
+    chunk_size: usize,
+    feature_weights: Vec<f64>,
+    /// Inverse degrees. Self-similarities are excluded from the degrees.
+    degree_inv: Vec<f64>,
+}
+
+impl<'a, M: RowSource> MergedMatrix<'a, M> {
+    fn new(mat: &'a M, parents: &'a [(usize, usize)], chunk_size: usize) -> Result<Self> {
+        let mut merged = Self {
+            mat,
+            parents,
+            chunk_size: chunk_size.max(1),
+            feature_weights: vec![1.0; mat.ncols()],
+            degree_inv: vec![1.0; mat.nrows() + parents.len()],
+        };
+
+        let mut doc_freq = vec![0.0; mat.ncols()];
+        for chunk in merged.chunks() {
+            chunk?.1.iter().flatten().for_each(|(j, _)| doc_freq[*j] += 1.0);
+        }
+        let n = merged.nrows() as f64;
+        if !doc_freq.iter().all_equal() {
+            merged.feature_weights = doc_freq.into_iter().map(|x| {
+                let x = if x == 0.0 { 1.0 } else if x == n { n - 1.0 } else { x };
+                (n / x).ln()
+            }).collect();
+        }
+
+        let mut col_sum = vec![0.0; mat.ncols()];
+        for chunk in merged.chunks() {
+            chunk?.1.iter().flatten().for_each(|(j, x)| col_sum[*j] += x);
+        }
+        let mut degree_inv = Vec::with_capacity(merged.nrows());
+        for chunk in merged.chunks() {
+            degree_inv.extend(chunk?.1.into_iter().map(|row| {
+                let d = row.iter().map(|(j, x)| x * col_sum[*j]).sum::<f64>() - 1.0;
+                if d > 0.0 { d.recip() } else { 0.0 }
+            }));
+        }
+        merged.degree_inv = degree_inv;
+        Ok(merged)
+    }
+
+    fn nrows(&self) -> usize {
+        self.mat.nrows() + self.parents.len()
+    }
+
+    /// Read the unnormalized rows from `start` to `end`. Simulated doublets are
+    /// the sums of their parents, which are read from the observed cells.
+    fn read_raw(&self, start: usize, end: usize) -> Result<Vec<SparseRow>> {
+        fn to_rows(mat: CsrMatrix<f64>) -> Vec<SparseRow> {
+            mat.row_iter().map(|row|
+                row.col_indices().iter().copied().zip(row.values().iter().copied()).collect()
+            ).collect()
+        }
+
+        let n_obs = self.mat.nrows();
+        let mut rows = Vec::with_capacity(end - start);
+        if start < n_obs {
+            let idx: Vec<usize> = (start..end.min(n_obs)).collect();
+            rows.extend(to_rows(self.mat.read_rows(&idx)?));
+        }
+        if end > n_obs {
+            let parents = &self.parents[start.max(n_obs) - n_obs..end - n_obs];
+            let idx: Vec<usize> = parents.iter().flat_map(|(a, b)| [*a, *b]).sorted().dedup().collect();
+            let pos: HashMap<usize, usize> = idx.iter().enumerate().map(|(k, i)| (*i, k)).collect();
+            let parent_rows = to_rows(self.mat.read_rows(&idx)?);
+            rows.extend(parents.par_iter().map(|(a, b)| {
+                let (a, b) = (&parent_rows[pos[a]], &parent_rows[pos[b]]);
+                a.iter().merge_join_by(b.iter(), |x, y| x.0.cmp(&y.0)).map(|x| match x {
+                    EitherOrBoth::Both(x, y) => (x.0, x.1 + y.1),
+                    EitherOrBoth::Left(x) | EitherOrBoth::Right(x) => *x,
+                }).collect::<Vec<_>>()
+            }).collect::<Vec<_>>());
+        }
+        Ok(rows)
+    }
+
+    /// Iterate over chunks of normalized rows, together with the index of the
+    /// first row in each chunk.
+    fn chunks(&self) -> impl Iterator<Item = Result<(usize, Vec<SparseRow>)>> + '_ {
+        let n = self.nrows();
+        (0..n).step_by(self.chunk_size).map(move |start| {
+            let end = (start + self.chunk_size).min(n);
+            let rows = self.read_raw(start, end)?.into_par_iter().enumerate().map(|(r, mut row)| {
+                row.iter_mut().for_each(|(j, x)| *x *= self.feature_weights[*j]);
+                let norm = row.iter().map(|(_, x)| x * x).sum::<f64>().sqrt();
+                let scale = if norm > 0.0 { self.degree_inv[start + r].sqrt() / norm } else { 0.0 };
+                row.iter_mut().for_each(|(_, x)| *x *= scale);
+                row
+            }).collect();
+            Ok((start, rows))
+        })
+    }
+
+    /// Multiply the normalized similarity matrix, without self-loops, by `q`.
+    /// This takes two passes over the data.
+    fn apply(&self, q: ArrayView2<f64>) -> Result<Array2<f64>> {
+        let mut z = Array2::zeros((self.mat.ncols(), q.ncols()));
+        for chunk in self.chunks() {
+            let (start, rows) = chunk?;
+            z.axis_iter_mut(Axis(1)).into_par_iter().enumerate().for_each(|(k, mut col)|
+                rows.iter().enumerate().for_each(|(r, row)| {
+                    let a = q[[start + r, k]];
+                    row.iter().for_each(|(j, x)| col[*j] += x * a);
+                })
+            );
+        }
+
+        let mut result = Array2::zeros(q.raw_dim());
+        for chunk in self.chunks() {
+            let (start, rows) = chunk?;
+            let mut out = result.slice_mut(s![start..start + rows.len(), ..]);
+            out.axis_iter_mut(Axis(0)).into_par_iter().zip(rows.par_iter()).enumerate().for_each(|(r, (mut out, row))| {
+                row.iter().for_each(|(j, x)| out.scaled_add(*x, &z.row(*j)));
+                out.scaled_add(-self.degree_inv[start + r], &q.row(start + r));
+            });
+        }
+        Ok(result)
+    }
+}
+
+/// Spectral embedding computed by randomized subspace iteration, so that
+/// only products of the similarity matrix and thin dense matrices are needed.
+fn spectral_embedding<M, R>(mat: &MergedMatrix<M>, n_comps: usize, n_iter: usize, rng: &mut R) -> Result<Array2<f64>>
+where
+    M: RowSource,
+    R: Rng,
+{
+    let n = mat.nrows();
+    let l = (n_comps + 10).min(n);
+    let mut q = Array2::from_shape_simple_fn((n, l), || rng.gen::<f64>() - 0.5);
+    orthonormalize(&mut q);
+    for _ in 0..n_iter {
+        q = mat.apply(q.view())?;
+        orthonormalize(&mut q);
+    }
+
+    // Rayleigh-Ritz projection.
+    let b = q.t().dot(&mat.apply(q.view())?);
+    let eigen = SymmetricEigen::new(DMatrix::from_fn(l, l, |i, j| 0.5 * (b[[i, j]] + b[[j, i]])));
+    // Components with non-positive eigenvalues are removed, and the rest are weighted
+    // by the square roots of their eigenvalues.
+    let order: Vec<usize> = (0..l)
+        .sorted_by(|i, j| eigen.eigenvalues[*j].total_cmp(&eigen.eigenvalues[*i]))
+        .take(n_comps)
+        .filter(|i| eigen.eigenvalues[*i] > 0.0)
+        .collect();
+    let v = Array2::from_shape_fn((l, order.len()), |(i, j)|
+        eigen.eigenvectors[(i, order[j])] * eigen.eigenvalues[order[j]].sqrt()
+    );
+    Ok(q.dot(&v))
+}
+
+/// Orthonormalize the columns of a matrix using the modified Gram-Schmidt process.
+fn orthonormalize(q: &mut Array2<f64>) {
+    for j in 0..q.ncols() {
+        for i in 0..j {
+            let (qi, mut qj) = q.multi_slice_mut((s![.., i], s![.., j]));
+            let r = qi.dot(&qj);
+            qj.scaled_add(-r, &qi);
+        }
+        let mut qj = q.column_mut(j);
+        let norm = qj.dot(&qj).sqrt();
+        if norm > 0.0 {
+            qj /= norm;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(chrom: &str, start: u64, end: u64) -> Fragment {
+        Fragment { chrom: chrom.to_string(), start, end, barcode: None, count: 1, strand: None }
+    }
+
+    #[test]
+    fn test_count_overlaps() {
+        let fragments = || vec![
+            fragment("chr1", 100, 200),
+            fragment("chr1", 150, 250),
+            fragment("chr1", 180, 300),
+            fragment("chr1", 250, 400),
+            fragment("chr1", 300, 500),
+            fragment("chr2", 100, 200),
+            fragment("chr2", 100, 200),
+            fragment("chr2", 150, 160),
+            fragment("chrX", 100, 200),
+            fragment("chrX", 100, 200),
+            fragment("chrX", 100, 200),
+        ];
+        let opts = AmuletOptions::default();
+        assert_eq!(count_overlaps(fragments(), None, &opts), 2);
+        let exclude: BedTree<()> = [(GenomicRange::new("chr2", 0, 1000), ())].into_iter().collect();
+        assert_eq!(count_overlaps(fragments(), Some(&exclude), &opts), 1);
+    }
+
+    #[test]
+    fn test_scrublet() {
+        // Two cell types with distinct accessible features, plus doublets of both types.
+        let (n_singlets, n_doublets) = (300, 15);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut coo = nalgebra_sparse::CooMatrix::new(n_singlets + n_doublets, 200);
+        for i in 0..n_singlets + n_doublets {
+            for j in 0..200 {
+                let p = if i >= n_singlets || (i % 2 == 0) == (j < 100) { 0.3 } else { 0.02 };
+                if rng.gen::<f64>() < p {
+                    coo.push(i, j, 1.0);
+                }
+            }
+        }
+        let res = scrublet(&CsrMatrix::from(&coo), &ScrubletOptions::default()).unwrap();
+        assert_eq!(res.sim_scores.len(), 2 * (n_singlets + n_doublets));
+        let singlet = res.scores[..n_singlets].iter().sum::<f64>() / n_singlets as f64;
+        let doublet = res.scores[n_singlets..].iter().sum::<f64>() / n_doublets as f64;
+        assert!(doublet > 2.0 * singlet, "{} {}", doublet, singlet);
+    }
+
+    #[test]
+    fn test_scrublet_anndata() {
+        use anndata::AnnData;
+        use anndata_hdf5::H5;
+
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut coo = nalgebra_sparse::CooMatrix::new(60, 40);
+        for i in 0..60 {
+            for j in 0..40 {
+                if rng.gen::<f64>() < 0.2 {
+                    coo.push(i, j, 1.0);
+                }
+            }
+        }
+        let mat = CsrMatrix::from(&coo);
+        let dir = tempfile::tempdir().unwrap();
+        let adata = AnnData::<H5>::new(dir.path().join("data.h5ad")).unwrap();
+        adata.set_x(mat.clone()).unwrap();
+
+        // Reading the rows in small chunks gives the same scores as in memory.
+        let opts = ScrubletOptions { chunk_size: 7, ..Default::default() };
+        let expected = scrublet(&mat.select_axis(1, &SelectInfoElem::from((0..30).collect::<Vec<_>>())), &opts).unwrap();
+        let rows = AnnDataRows::new(&adata, SelectInfoElem::from((0..30).collect::<Vec<_>>()));
+        assert_eq!(rows.ncols(), 30);
+        let res = scrublet(&rows, &opts).unwrap();
+        assert_eq!(res.parents, expected.parents);
+        res.scores.iter().chain(res.sim_scores.iter())
+            .zip(expected.scores.iter().chain(expected.sim_scores.iter()))
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-8, "{} {}", a, b));
+    }
+}
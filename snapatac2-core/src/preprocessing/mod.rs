@@ -1,11 +1,20 @@
 pub mod qc;
 pub mod bam;
 pub mod count_data;
+pub mod pairs;
+pub mod doublet;
+pub mod multiplet;
+pub mod normalization;
 
 pub use count_data::{import_fragments, import_contacts, Promoters, Transcript,
     read_transcripts_from_gff, read_transcripts_from_gtf,
-    create_gene_matrix, create_tile_matrix, create_peak_matrix,
-    GenomeCoverage, ContactMap, SnapData,
+    create_gene_matrix, create_tile_matrix, create_tile_matrices, create_partition_matrix,
+    create_peak_matrix,
+    GeneQuantification, GeneScoreOptions, CountingStrategy,
+    GenomeCoverage, ContactMap, SnapData, FragmentFileData, concat_dataset,
+    subset_fragments, TssEnrichment, TssProfileMode,
 };
 pub use bam::{make_fragment_file, FlagStat};
+pub use normalization::{normalize, Normalization, TfIdfMethod, TfIdfOptions};
+pub use pairs::PairsReader;
 pub use qc::{Fragment, Contact, CellBarcode, read_tss, make_promoter_map, get_barcode_count};
\ No newline at end of file
@@ -0,0 +1,200 @@
+//! # Barcode Multiplets
+//!
+//! In droplet-based protocols, a droplet may contain more than one gel bead, so that
+//! the fragments of a single cell are split across several barcodes. Because the
+//! same DNA fragment can be tagged by different beads in the droplet, such barcode
+//! multiplets share an unusual number of fragments with identical coordinates.
+//! Similar to the "excluded barcodes" logic of Cell Ranger ATAC, barcode pairs with
+//! excessive exact-fragment overlap are grouped, and in each group the barcode with
+//! the most fragments is kept as the representative.
+use crate::{
+    preprocessing::count_data::{CoverageType, GenomeCoverage},
+    utils::similarity::BorrowedSparsityPattern,
+};
+
+use itertools::Itertools;
+use rayon::{iter::{IntoParallelIterator, ParallelIterator}, slice::ParallelSliceMut};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct MultipletOptions {
+    /// Minimum number of shared fragments for a barcode pair to be considered.
+    pub min_shared: usize,
+    /// Minimum Jaccard index of the fragment sets for a barcode pair to be called.
+    pub min_jaccard: f64,
+    /// Fragments shared by more than this number of barcodes, e.g., PCR hot spots,
+    /// are not used to detect multiplets.
+    pub max_barcodes_per_fragment: usize,
+}
+
+impl Default for MultipletOptions {
+    fn default() -> Self {
+        Self {
+            min_shared: 5,
+            min_jaccard: 0.01,
+            max_barcodes_per_fragment: 10,
+        }
+    }
+}
+
+/// A barcode pair with excessive exact-fragment overlap.
+#[derive(Debug, Clone)]
+pub struct MultipletPair {
+    pub barcode1: usize,
+    pub barcode2: usize,
+    pub num_shared: usize,
+    pub jaccard: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct BarcodeMultiplets {
+    pub pairs: Vec<MultipletPair>,
+    /// The representative barcode of the multiplet group each barcode belongs to.
+    /// Barcodes that are not multiplets have `None` values.
+    pub groups: Vec<Option<usize>>,
+    /// Number of unique fragments in each barcode.
+    pub num_fragments: Vec<usize>,
+}
+
+impl BarcodeMultiplets {
+    /// Whether a barcode is a multiplet and not the representative of its group.
+    pub fn is_excluded(&self, i: usize) -> bool {
+        self.groups[i].map_or(false, |x| x != i)
+    }
+}
+
+/// Detect barcode multiplets from the fragments of barcodes.
+pub fn detect_multiplets<I>(coverage: GenomeCoverage<I>, opts: &MultipletOptions) -> BarcodeMultiplets
+where
+    I: ExactSizeIterator<Item = (CoverageType, usize, usize)>,
+{
+    // Fragment coordinates, with chromosomes replaced by their indices, and barcodes.
+    let chroms: HashMap<String, usize> = coverage.get_gindex().chrom_sizes()
+        .enumerate().map(|(i, (chr, _))| (chr.clone(), i)).collect();
+    let mut keys: Vec<(usize, u64, u64, usize)> = Vec::new();
+    let mut n_barcodes = 0;
+    coverage.into_raw().for_each(|(fragments, start, _)| {
+        n_barcodes += fragments.len();
+        let chunk: Vec<_> = fragments.into_par_iter().enumerate().flat_map_iter(|(i, xs)|
+            xs.into_iter().map(|x| (chroms[&x.chrom], x.start, x.end, start + i)).collect::<Vec<_>>()
+        ).collect();
+        keys.extend(chunk);
+    });
+    keys.par_sort_unstable();
+    keys.dedup();
+
+    let mut num_fragments = vec![0; n_barcodes];
+    keys.iter().for_each(|x| num_fragments[x.3] += 1);
+
+    // Sparsity pattern of fragments (rows) shared by barcodes (columns).
+    let mut offsets = vec![0];
+    let mut barcodes = Vec::new();
+    keys.iter().group_by(|x| (x.0, x.1, x.2)).into_iter().for_each(|(_, group)| {
+        let group: Vec<_> = group.map(|x| x.3).collect();
+        if group.len() > 1 && group.len() <= opts.max_barcodes_per_fragment {
+            barcodes.extend(group);
+            offsets.push(barcodes.len());
+        }
+    });
+    drop(keys);
+    let fragment_by_barcode = BorrowedSparsityPattern::new(&offsets, &barcodes, n_barcodes);
+    let barcode_by_fragment = fragment_by_barcode.transpose();
+
+    // Count shared fragments between barcodes.
+    let candidates: Vec<(usize, usize, usize)> = (0..n_barcodes).into_par_iter().flat_map_iter(|i| {
+        let mut counts = HashMap::new();
+        barcode_by_fragment.get_lane(i).unwrap().iter().for_each(|k|
+            fragment_by_barcode.get_lane(*k).unwrap().iter().filter(|j| **j > i).for_each(|j|
+                *counts.entry(*j).or_insert(0) += 1
+            )
+        );
+        counts.into_iter().filter(|(_, c)| *c >= opts.min_shared).map(move |(j, c)| (i, j, c))
+    }).collect();
+
+    // Jaccard indices of the fragment sets of candidate pairs, computed from the
+    // numbers of shared fragments instead of `utils::similarity::jaccard`, which
+    // would need a dense matrix for each group of connected barcodes.
+    let mut pairs: Vec<MultipletPair> = candidates.into_iter().map(|(i, j, num_shared)| MultipletPair {
+        barcode1: i,
+        barcode2: j,
+        num_shared,
+        jaccard: num_shared as f64 / (num_fragments[i] + num_fragments[j] - num_shared) as f64,
+    }).filter(|x| x.jaccard >= opts.min_jaccard).collect();
+    pairs.sort_unstable_by_key(|x| (x.barcode1, x.barcode2));
+
+    // Group multiplets and choose the barcodes with the most fragments as representatives.
+    let mut components = UnionFind::new(n_barcodes);
+    pairs.iter().for_each(|x| components.union(x.barcode1, x.barcode2));
+    let mut representative: HashMap<usize, usize> = HashMap::new();
+    pairs.iter().flat_map(|x| [x.barcode1, x.barcode2]).for_each(|i| {
+        let rep = representative.entry(components.find(i)).or_insert(i);
+        if (num_fragments[i], std::cmp::Reverse(i)) > (num_fragments[*rep], std::cmp::Reverse(*rep)) {
+            *rep = i;
+        }
+    });
+    let mut groups = vec![None; n_barcodes];
+    pairs.iter().flat_map(|x| [x.barcode1, x.barcode2]).for_each(|i|
+        groups[i] = Some(representative[&components.find(i)])
+    );
+    BarcodeMultiplets { pairs, groups, num_fragments }
+}
+
+struct UnionFind(Vec<usize>);
+
+impl UnionFind {
+    fn new(n: usize) -> Self {
+        Self((0..n).collect())
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.0[i] != i {
+            self.0[i] = self.0[self.0[i]];
+            i = self.0[i];
+        }
+        i
+    }
+
+    fn union(&mut self, i: usize, j: usize) {
+        let (a, b) = (self.find(i), self.find(j));
+        if a != b {
+            self.0[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessing::count_data::{ChromSizes, GenomeBaseIndex};
+    use anndata::data::{array::utils::{from_csr_data, to_csr_data}, CsrNonCanonical};
+
+    #[test]
+    fn test_detect_multiplets() {
+        let chrom_sizes: ChromSizes = [("chr1", 10000)].into_iter().collect();
+        let index = GenomeBaseIndex::new(&chrom_sizes);
+        let fragments = |starts: &[u64]| -> Vec<(usize, u32)> {
+            starts.iter().map(|x| (index.get_position_rev("chr1", *x), 50)).collect()
+        };
+        // Barcode 0 has 10 fragments, 2 of which are shared with barcode 1.
+        let counts = vec![
+            fragments(&(0..10).map(|x| x * 10).collect::<Vec<_>>()),
+            fragments(&[10, 20, 1000, 1010, 1020]),
+            fragments(&[3000, 3010]),
+        ];
+        let (r, c, offset, ind, data) = to_csr_data(counts, index.len());
+        let mat: CsrNonCanonical<u32> = from_csr_data(r, c, offset, ind, data).unwrap().try_into().unwrap();
+        let coverage = GenomeCoverage::new(
+            chrom_sizes, std::iter::once((CoverageType::FragmentPaired(mat), 0, 3)),
+        );
+
+        let opts = MultipletOptions { min_shared: 2, min_jaccard: 0.1, ..Default::default() };
+        let result = detect_multiplets(coverage, &opts);
+        assert_eq!(result.num_fragments, vec![10, 5, 2]);
+        assert_eq!(result.pairs.len(), 1);
+        let pair = &result.pairs[0];
+        assert_eq!((pair.barcode1, pair.barcode2, pair.num_shared), (0, 1, 2));
+        assert!((pair.jaccard - 2.0 / 13.0).abs() < 1e-12);
+        assert_eq!(result.groups, vec![Some(0), Some(0), None]);
+        assert!(result.is_excluded(1) && !result.is_excluded(0));
+    }
+}
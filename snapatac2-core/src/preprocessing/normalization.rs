@@ -0,0 +1,166 @@
+//! # Count Normalization
+//!
+//! Normalization of count matrices stored in `.X`, processed chunk by chunk so
+//! that backed data never need to be loaded into memory. Methods that require
+//! feature statistics, i.e., TF-IDF, read `.X` twice.
+use anndata::{AnnDataOp, ArrayElemOp, AxisArraysOp};
+use anyhow::{bail, Result};
+use indicatif::{ProgressIterator, ProgressStyle};
+use nalgebra_sparse::CsrMatrix;
+
+/// TF-IDF variants. TF is the count of a feature divided by the total count of
+/// the cell, and IDF is the number of cells divided by the total count of the
+/// feature, as in Signac.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TfIdfMethod {
+    /// `log1p(TF * IDF * scale_factor)`. This is Signac's method 1, and ArchR's
+    /// log-TF-IDF when combined with binarization.
+    LogTfIdf,
+    /// `TF * log(1 + IDF)`. This is Signac's method 2.
+    TfLogIdf,
+    /// `log1p(TF * scale_factor) * log(1 + IDF)`. This is Signac's method 3.
+    LogTfLogIdf,
+    /// `count * IDF`. This is Signac's method 4.
+    Idf,
+}
+
+#[derive(Debug, Clone)]
+pub struct TfIdfOptions {
+    pub method: TfIdfMethod,
+    pub scale_factor: f64,
+    /// Whether counts are binarized first, as in ArchR.
+    pub binarize: bool,
+}
+
+impl Default for TfIdfOptions {
+    fn default() -> Self {
+        Self {
+            method: TfIdfMethod::LogTfIdf,
+            scale_factor: 1e4,
+            binarize: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Normalization {
+    TfIdf(TfIdfOptions),
+    /// Scale the counts of each cell to sum to `scale_factor`, e.g., 1e6 for CPM,
+    /// optionally followed by `log1p`.
+    Cpm { scale_factor: f64, log1p: bool },
+    Log1p,
+    Binarize,
+}
+
+/// Normalize `.X` chunk by chunk.
+///
+/// # Arguments
+///
+/// * `adata` - The input data.
+/// * `method` - The normalization method.
+/// * `chunk_size` - The number of cells to process at a time.
+/// * `layer` - If provided, the result is stored in `.layers[layer]` of `adata`.
+/// * `out` - If provided, the result is written to `.X` of `out`.
+///
+/// Exactly one of `layer` and `out` must be provided, as `.X` cannot be
+/// overwritten while it is being read.
+pub fn normalize<A, B>(
+    adata: &A,
+    method: &Normalization,
+    chunk_size: usize,
+    layer: Option<&str>,
+    out: Option<&B>,
+) -> Result<()>
+where
+    A: AnnDataOp,
+    B: AnnDataOp,
+{
+    let idf = match method {
+        Normalization::TfIdf(opts) => Some(inverse_document_frequency(adata, opts.binarize, chunk_size)?),
+        _ => None,
+    };
+    let style = ProgressStyle::with_template(
+        "[{elapsed}] {bar:40.cyan/blue} {pos:>7}/{len:7} (eta: {eta})"
+    ).unwrap();
+    let data_iter = adata.x().iter(chunk_size).progress_with_style(style)
+        .map(|(mat, _, _): (CsrMatrix<f64>, _, _)| normalize_chunk(mat, method, idf.as_deref()));
+    match (layer, out) {
+        (Some(key), None) => adata.layers().add_iter(key, data_iter)?,
+        (None, Some(out)) => {
+            out.set_x_from_iter(data_iter)?;
+            out.set_obs_names(adata.obs_names())?;
+            out.set_var_names(adata.var_names())?;
+        },
+        _ => bail!("exactly one of layer and out must be provided"),
+    }
+    Ok(())
+}
+
+/// The number of cells divided by the total count of each feature.
+fn inverse_document_frequency<A: AnnDataOp>(adata: &A, binarize: bool, chunk_size: usize) -> Result<Vec<f64>> {
+    let mut col_sum = vec![0.0; adata.n_vars()];
+    adata.x().iter(chunk_size).for_each(|(mat, _, _): (CsrMatrix<f64>, _, _)|
+        mat.col_indices().iter().zip(mat.values()).for_each(|(i, v)|
+            col_sum[*i] += if binarize { 1.0 } else { *v }
+        )
+    );
+    let n = adata.n_obs() as f64;
+    Ok(col_sum.into_iter().map(|x| if x > 0.0 { n / x } else { 0.0 }).collect())
+}
+
+fn normalize_chunk(mut mat: CsrMatrix<f64>, method: &Normalization, idf: Option<&[f64]>) -> CsrMatrix<f32> {
+    match method {
+        Normalization::Binarize => mat.values_mut().iter_mut().for_each(|x| *x = 1.0),
+        Normalization::Log1p => mat.values_mut().iter_mut().for_each(|x| *x = x.ln_1p()),
+        Normalization::Cpm { scale_factor, log1p } => mat.row_iter_mut().for_each(|mut row| {
+            let total: f64 = row.values().iter().sum();
+            row.values_mut().iter_mut().for_each(|x| {
+                *x = *x / total * scale_factor;
+                if *log1p {
+                    *x = x.ln_1p();
+                }
+            });
+        }),
+        Normalization::TfIdf(opts) => {
+            let idf = idf.unwrap();
+            if opts.binarize {
+                mat.values_mut().iter_mut().for_each(|x| *x = 1.0);
+            }
+            mat.row_iter_mut().for_each(|mut row| {
+                let total: f64 = row.values().iter().sum();
+                let (cols, values) = row.cols_and_values_mut();
+                cols.iter().zip(values.iter_mut()).for_each(|(i, x)| {
+                    let tf = *x / total;
+                    *x = match opts.method {
+                        TfIdfMethod::LogTfIdf => (tf * idf[*i] * opts.scale_factor).ln_1p(),
+                        TfIdfMethod::TfLogIdf => tf * idf[*i].ln_1p(),
+                        TfIdfMethod::LogTfLogIdf => (tf * opts.scale_factor).ln_1p() * idf[*i].ln_1p(),
+                        TfIdfMethod::Idf => *x * idf[*i],
+                    };
+                });
+            });
+        },
+    }
+    let (pattern, values) = mat.into_pattern_and_values();
+    CsrMatrix::try_from_pattern_and_values(pattern, values.into_iter().map(|x| x as f32).collect()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tf_idf() {
+        // Two cells and two features. Feature 0 has a total count of 4 and
+        // feature 1 has a total count of 1.
+        let mat = CsrMatrix::try_from_csr_data(2, 2, vec![0, 2, 3], vec![0, 1, 0], vec![3.0, 1.0, 1.0]).unwrap();
+        let idf = [0.5, 2.0];
+        let opts = TfIdfOptions { method: TfIdfMethod::LogTfIdf, scale_factor: 1e4, binarize: false };
+        let result = normalize_chunk(mat.clone(), &Normalization::TfIdf(opts), Some(&idf));
+        let expected = [(0.75f64 * 0.5 * 1e4).ln_1p(), (0.25f64 * 2.0 * 1e4).ln_1p(), (0.5f64 * 1e4).ln_1p()];
+        result.values().iter().zip(expected).for_each(|(a, b)| assert!((*a as f64 - b).abs() < 1e-4));
+
+        let result = normalize_chunk(mat, &Normalization::Cpm { scale_factor: 1e6, log1p: false }, None);
+        assert_eq!(result.values(), &[750000.0, 250000.0, 1e6]);
+    }
+}
@@ -0,0 +1,179 @@
+//! # 4DN Pairs Reader
+//!
+//! Read chromatin contacts from files in the 4DN `.pairs` format
+//! (<https://github.com/4dn-dcic/pairix/blob/master/pairs_format_specification.md>).
+//! Columns are located using the `#columns:` header line, and chromosome sizes are
+//! taken from the `#chromsize:` header lines. Positions in `.pairs` files are 1-based
+//! and are converted to 0-based coordinates. Strand columns are ignored as `Contact`
+//! does not carry strand information.
+use crate::preprocessing::{count_data::ChromSizes, qc::Contact};
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::io::BufRead;
+
+/// Column names that are recognized as cell barcodes, in the order of preference.
+const BARCODE_COLUMNS: [&str; 4] = ["cell", "cell_id", "cell_barcode", "barcode"];
+
+#[derive(Debug, Clone)]
+pub struct PairsHeader {
+    pub columns: Vec<String>,
+    pub chrom_sizes: ChromSizes,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ColumnIndex {
+    chrom1: usize,
+    pos1: usize,
+    chrom2: usize,
+    pos2: usize,
+    barcode: usize,
+    count: Option<usize>,
+}
+
+impl ColumnIndex {
+    fn parse(&self, line: &str) -> Result<Contact> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let get = |i: usize| fields.get(i).copied()
+            .with_context(|| format!("missing column {} in record: {}", i + 1, line));
+        let parse_pos = |i: usize| -> Result<u64> {
+            let pos: u64 = lexical::parse(get(i)?)
+                .map_err(|_| anyhow::anyhow!("invalid position in record: {}", line))?;
+            Ok(pos.saturating_sub(1))
+        };
+        Ok(Contact {
+            chrom1: get(self.chrom1)?.to_string(),
+            start1: parse_pos(self.pos1)?,
+            chrom2: get(self.chrom2)?.to_string(),
+            start2: parse_pos(self.pos2)?,
+            barcode: get(self.barcode)?.to_string(),
+            count: match self.count {
+                None => 1,
+                Some(i) => lexical::parse(get(i)?)
+                    .map_err(|_| anyhow::anyhow!("invalid count in record: {}", line))?,
+            },
+        })
+    }
+}
+
+pub struct PairsReader<R> {
+    reader: R,
+    header: PairsHeader,
+    index: ColumnIndex,
+}
+
+impl<R: BufRead> PairsReader<R> {
+    /// Create a reader and parse the header.
+    /// If `barcode_column` is not provided, the first column among "cell", "cell_id",
+    /// "cell_barcode" and "barcode" is used.
+    pub fn new(mut reader: R, barcode_column: Option<&str>) -> Result<Self> {
+        let mut columns = Vec::new();
+        let mut chrom_sizes = Vec::new();
+        let mut line = String::new();
+        while reader.fill_buf()?.first() == Some(&b'#') {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if let Some(cols) = line.strip_prefix("#columns:") {
+                columns = cols.split_whitespace().map(|x| x.to_string()).collect();
+            } else if let Some(size) = line.strip_prefix("#chromsize:") {
+                let (chrom, size) = size.split_whitespace().collect_tuple()
+                    .with_context(|| format!("invalid chromsize line: {}", line))?;
+                chrom_sizes.push((chrom.to_string(), size.parse::<u64>()?));
+            }
+        }
+        if columns.is_empty() {
+            bail!("the '#columns:' header line is missing");
+        }
+
+        let find = |name: &str| columns.iter().position(|x| x == name);
+        let find_req = |name: &str| find(name).with_context(|| format!("column '{}' is missing", name));
+        let barcode = match barcode_column {
+            Some(col) => find_req(col)?,
+            None => BARCODE_COLUMNS.iter().find_map(|x| find(x))
+                .context("no cell barcode column is found")?,
+        };
+        let index = ColumnIndex {
+            chrom1: find_req("chr1")?,
+            pos1: find_req("pos1")?,
+            chrom2: find_req("chr2")?,
+            pos2: find_req("pos2")?,
+            barcode,
+            count: find("count"),
+        };
+        Ok(Self {
+            reader,
+            header: PairsHeader { columns, chrom_sizes: chrom_sizes.into_iter().collect() },
+            index,
+        })
+    }
+
+    pub fn header(&self) -> &PairsHeader {
+        &self.header
+    }
+
+    /// Return an iterator of contacts. Records are read in batches of `batch_size` lines,
+    /// and each batch is parsed in parallel. Read errors and malformed records are
+    /// returned as errors.
+    pub fn into_contacts(self, batch_size: usize) -> impl Iterator<Item = Result<Contact>> {
+        ParsedBatches { lines: self.reader.lines(), index: self.index, batch_size }.flatten()
+    }
+}
+
+struct ParsedBatches<L> {
+    lines: L,
+    index: ColumnIndex,
+    batch_size: usize,
+}
+
+impl<L: Iterator<Item = std::io::Result<String>>> Iterator for ParsedBatches<L> {
+    type Item = Vec<Result<Contact>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let batch: Vec<std::io::Result<String>> = self.lines.by_ref().take(self.batch_size).collect();
+        if batch.is_empty() {
+            return None;
+        }
+        Some(batch.into_par_iter()
+            .filter(|x| x.as_ref().map_or(true, |x| !x.is_empty() && !x.starts_with('#')))
+            .map(|x| x.context("failed to read the .pairs file").and_then(|x| self.index.parse(&x)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_pairs() {
+        let pairs = "## pairs format v1.0\n\
+                     #chromsize: chr1 1000\n\
+                     #chromsize: chr2 500\n\
+                     #columns: readID chr1 pos1 chr2 pos2 strand1 strand2 cell\n\
+                     r1\tchr1\t10\tchr1\t200\t+\t-\tAAAC\n\
+                     r2\tchr1\t11\tchr2\t20\t+\t+\tAAAC\n\
+                     r3\tchr2\t5\tchr2\t100\t-\t-\tCCGT\n";
+        let reader = PairsReader::new(pairs.as_bytes(), None).unwrap();
+        assert_eq!(
+            reader.header().chrom_sizes,
+            vec![("chr1", 1000), ("chr2", 500)].into_iter().collect(),
+        );
+        let contacts: Vec<_> = reader.into_contacts(2).map(|x| x.unwrap()).collect();
+        assert_eq!(contacts.len(), 3);
+        assert_eq!(contacts[1].chrom2, "chr2");
+        assert_eq!(contacts[1].start1, 10);
+        assert_eq!(contacts[1].start2, 19);
+        assert_eq!(contacts[2].barcode, "CCGT");
+
+        let pairs = "#columns: readID chr1 pos1 chr2 pos2 cell\n\
+                     r1\tchr1\t10\tchr1\t200\tAAAC\n\
+                     r2\tchr1\tx\tchr2\t20\tAAAC\n";
+        let contacts: Vec<_> = PairsReader::new(pairs.as_bytes(), None).unwrap()
+            .into_contacts(2).collect();
+        assert!(contacts[0].is_ok());
+        let err = contacts[1].as_ref().unwrap_err().to_string();
+        assert!(err.contains("r2\tchr1\tx"), "{}", err);
+    }
+}
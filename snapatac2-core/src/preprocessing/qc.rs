@@ -1,4 +1,4 @@
-use std::{io::{Read, BufRead, BufReader}, ops::Div, collections::{HashMap, HashSet}};
+use std::{io::{Read, BufRead, BufReader}, ops::Div, collections::{BTreeMap, HashMap, HashSet}};
 use anndata::data::CsrNonCanonical;
 use bed_utils::bed::{GenomicRange, BEDLike, tree::BedTree, ParseError, Strand};
 use anyhow::Result;
@@ -257,10 +257,64 @@ pub fn read_tss<R: Read>(file: R) -> impl Iterator<Item = (String, u64, bool)> {
 }
 
 
-pub fn make_promoter_map<I: Iterator<Item = (String, u64, bool)>>(iter: I) -> BedTree<bool> {
+/// Definition of the TSS enrichment score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TssEnrichmentMethod {
+    /// ENCODE/ArchR definition: the maximum of the smoothed insertion profile,
+    /// normalized by the mean insertions in the flanking regions.
+    Encode,
+    /// Signac definition: the mean of the normalized insertion profile at the two
+    /// central positions. Cells without insertions in the flanking regions are
+    /// normalized by the average background of all cells.
+    Signac,
+}
+
+#[derive(Debug, Clone)]
+pub struct TssEnrichmentOptions {
+    pub method: TssEnrichmentMethod,
+    /// Size of the window on each side of TSSs.
+    pub window: u64,
+    /// Size of the flanking regions at both ends of the window, used to estimate the background.
+    pub flank: u64,
+    /// Half window of the moving average applied to the insertion profile.
+    pub smooth: usize,
+    /// Pseudocount added to the background.
+    pub pseudocount: f64,
+}
+
+impl TssEnrichmentOptions {
+    pub fn encode() -> Self {
+        Self {
+            method: TssEnrichmentMethod::Encode,
+            window: 2000,
+            flank: 100,
+            smooth: 5,
+            pseudocount: 0.1,
+        }
+    }
+
+    pub fn signac() -> Self {
+        Self {
+            method: TssEnrichmentMethod::Signac,
+            window: 1000,
+            flank: 100,
+            smooth: 0,
+            pseudocount: 0.0,
+        }
+    }
+}
+
+impl Default for TssEnrichmentOptions {
+    fn default() -> Self {
+        Self::encode()
+    }
+}
+
+/// Make a map of regions spanning `window` bp on each side of TSSs.
+pub fn make_promoter_map<I: Iterator<Item = (String, u64, bool)>>(iter: I, window: u64) -> BedTree<bool> {
     iter
         .map( |(chr, tss, is_fwd)| {
-            let b = GenomicRange::new(chr, tss.saturating_sub(2000), tss + 2001);
+            let b = GenomicRange::new(chr, tss.saturating_sub(window), tss + window + 1);
             (b, is_fwd)
         }).collect()
 }
@@ -278,46 +332,64 @@ where
     barcodes
 }
 
-pub fn tss_enrichment<I>(fragments: I, promoter: &BedTree<bool>) -> f64
+/// Count insertions around TSSs. The result has `2 * window + 1` positions
+/// oriented by the strand of transcripts, with the TSS at position `window`.
+/// `promoter` must be created by `make_promoter_map` with the same `window`.
+pub fn tss_profile<I>(fragments: I, promoter: &BedTree<bool>, window: u64) -> Vec<u64>
 where
     I: Iterator<Item = Fragment>,
 {
-    fn find_pos<'a>(promoter: &'a BedTree<bool>, ins: &'a GenomicRange) -> impl Iterator<Item = usize> + 'a {
-        promoter.find(ins).map(|(entry, data)| {
-            let pos: u64 =
-                if *data {
-                    ins.start() - entry.start()
-                } else {
-                    4000 - (entry.end() - 1 - ins.start())
-                };
+    let find_pos = |ins: &GenomicRange| -> SmallVec<[usize; 2]> {
+        promoter.find(ins).map(|(entry, is_fwd)| {
+            let tss = entry.end() - 1 - window;
+            let pos = if *is_fwd {
+                ins.start() + window - tss
+            } else {
+                tss + window - ins.start()
+            };
             pos as usize
-        })
-    }
+        }).collect()
+    };
 
-    let mut counts = [0; 4001];
+    let mut counts = vec![0; 2 * window as usize + 1];
     fragments.for_each(|bed| match bed.strand {
         None => {
             let p1 = GenomicRange::new(bed.chrom(), bed.start(), bed.start() + 1);
             let p2 = GenomicRange::new(bed.chrom(), bed.end() - 1, bed.end());
-            find_pos(promoter, &p1).for_each(|pos| counts[pos] += 1);
-            find_pos(promoter, &p2).for_each(|pos| counts[pos] += 1);
+            find_pos(&p1).into_iter().for_each(|pos| counts[pos] += 1);
+            find_pos(&p2).into_iter().for_each(|pos| counts[pos] += 1);
         },
         Some(Strand::Forward) => {
             let p = GenomicRange::new(bed.chrom(), bed.start(), bed.start() + 1);
-            find_pos(promoter, &p).for_each(|pos| counts[pos] += 1);
+            find_pos(&p).into_iter().for_each(|pos| counts[pos] += 1);
         },
         Some(Strand::Reverse) => {
             let p = GenomicRange::new(bed.chrom(), bed.end() - 1, bed.end());
-            find_pos(promoter, &p).for_each(|pos| counts[pos] += 1);
+            find_pos(&p).into_iter().for_each(|pos| counts[pos] += 1);
         },
     });
-    let bg_count: f64 =
-        ( counts[ .. 100].iter().sum::<u64>() +
-        counts[3901 .. 4001].iter().sum::<u64>() ) as f64 /
-        200.0 + 0.1;
-    let tss_enrichment = moving_average(5, &counts)
-        .max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap().div(bg_count);
-    tss_enrichment
+    counts
+}
+
+/// Return the signal and the background of a TSS insertion profile,
+/// without the pseudocount.
+pub(crate) fn tss_signal(profile: &[u64], opts: &TssEnrichmentOptions) -> (f64, f64) {
+    let n = profile.len();
+    let flank = (opts.flank as usize).min(n / 2);
+    let background = (profile[..flank].iter().sum::<u64>() + profile[n - flank..].iter().sum::<u64>()) as f64
+        / (2 * flank) as f64;
+    let smoothed: Vec<f64> = moving_average(opts.smooth, profile).collect();
+    let signal = match opts.method {
+        TssEnrichmentMethod::Encode => smoothed.into_iter().fold(0.0, f64::max),
+        TssEnrichmentMethod::Signac => (smoothed[n / 2 - 1] + smoothed[n / 2]) / 2.0,
+    };
+    (signal, background)
+}
+
+/// Compute the TSS enrichment score from an insertion profile around TSSs.
+pub fn tss_enrichment(profile: &[u64], opts: &TssEnrichmentOptions) -> f64 {
+    let (signal, background) = tss_signal(profile, opts);
+    signal.div(background + opts.pseudocount)
 }
 
 /// Compute the fragment size distribution.
@@ -343,6 +415,83 @@ pub fn fragment_size_distribution<I>(data: I, max_size: usize) -> Vec<usize>
     size_dist
 }
 
+/// Upper bound (exclusive) of the sizes of nucleosome-free fragments.
+const NUCLEOSOME_FREE_MAX: u64 = 147;
+/// Upper bound (inclusive) of the sizes of mono-nucleosomal fragments.
+const MONO_NUCLEOSOME_MAX: u64 = 294;
+
+/// Fragment size QC metrics of a cell.
+#[derive(Debug, Clone)]
+pub struct FragmentSizeQC {
+    /// Ratio of mono-nucleosomal (147-294 bp) to nucleosome-free (< 147 bp) fragments.
+    pub nucleosome_signal: f64,
+    /// Normalized amplitude of the nucleosome periodicity of the fragment size
+    /// distribution. See `nucleosome_periodicity`.
+    pub periodicity: f64,
+    /// Sparse fragment size histogram as `(bin, count)` pairs. See `fragment_size_qc`.
+    pub histogram: Vec<(usize, u32)>,
+}
+
+/// Compute fragment size QC metrics from the sizes of fragments of a cell.
+/// The histogram has `1 + ceil(max_size / bin_size)` bins, where the bin `k > 0`
+/// counts fragments with sizes in `((k - 1) * bin_size, k * bin_size]`, and the
+/// first bin counts fragments larger than `max_size`. `bin_size` must be positive.
+pub fn fragment_size_qc<I>(sizes: I, max_size: usize, bin_size: usize) -> FragmentSizeQC
+where
+    I: Iterator<Item = u64>,
+{
+    let mut hist = vec![0u64; max_size + 1];
+    let (mut nfr, mut mono) = (0u64, 0u64);
+    sizes.for_each(|x| {
+        if x < NUCLEOSOME_FREE_MAX {
+            nfr += 1;
+        } else if x <= MONO_NUCLEOSOME_MAX {
+            mono += 1;
+        }
+        if x as usize <= max_size {
+            hist[x as usize] += 1;
+        } else {
+            hist[0] += 1;
+        }
+    });
+    let mut histogram: BTreeMap<usize, u32> = BTreeMap::new();
+    hist.iter().enumerate().filter(|(_, x)| **x > 0).for_each(|(i, x)| {
+        let bin = num::integer::div_ceil(i, bin_size);
+        *histogram.entry(bin).or_insert(0) += *x as u32;
+    });
+    FragmentSizeQC {
+        nucleosome_signal: mono as f64 / nfr as f64,
+        periodicity: nucleosome_periodicity(&hist[1..]),
+        histogram: histogram.into_iter().collect(),
+    }
+}
+
+/// Measure the nucleosome periodicity of a fragment size distribution, where
+/// `hist[i]` is the number of fragments of size `i + 1`. Fragment sizes between 50
+/// and 800 bp are detrended by subtracting a moving average over a window of about
+/// one nucleosome repeat, and the score is the largest Fourier amplitude of the
+/// residuals at periods between 160 and 220 bp, divided by the number of fragments.
+/// Distributions without nucleosome banding have scores close to 0.
+pub fn nucleosome_periodicity(hist: &[u64]) -> f64 {
+    let (lo, hi) = (49, hist.len().min(800));
+    if hi <= lo {
+        return f64::NAN;
+    }
+    let total = hist[lo..hi].iter().sum::<u64>() as f64;
+    if total == 0.0 {
+        return f64::NAN;
+    }
+    let trend: Vec<f64> = moving_average(100, hist).collect();
+    let residual: Vec<(f64, f64)> = (lo..hi).map(|i| ((i + 1) as f64, hist[i] as f64 - trend[i])).collect();
+    (160..=220).map(|period| {
+        let omega = 2.0 * std::f64::consts::PI / period as f64;
+        let (re, im) = residual.iter().fold((0.0, 0.0), |(re, im), (x, r)|
+            (re + r * (omega * x).cos(), im + r * (omega * x).sin())
+        );
+        (re * re + im * im).sqrt() / total
+    }).fold(0.0, f64::max)
+}
+
 /// Count the fraction of the reads in the given regions.
 pub fn fraction_of_reads_in_region<'a, I, D>(
     iter: I, regions: &'a Vec<BedTree<D>>,
@@ -370,4 +519,210 @@ where
         }).collect::<Vec<_>>();
         (frac, start, end)
     })
-}
\ No newline at end of file
+}
+
+/// Quality control metrics of chromatin contacts. Fractions are 0 when their
+/// denominators are 0, e.g., for cells without cis contacts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContactQC {
+    /// Number of contacts, excluding those involving mitochondrial chromosomes.
+    pub num_contacts: u64,
+    pub num_cis: u64,
+    pub num_trans: u64,
+    pub frac_mitochondrial: f64,
+    /// Fraction of cis contacts whose distance is smaller than `short_range`.
+    pub frac_short_cis: f64,
+    /// Fraction of cis contacts whose distance is at least `long_range`.
+    pub frac_long_cis: f64,
+    /// Fraction of cis contacts in each distance bin, i.e., the contact-distance
+    /// decay curve P(s).
+    pub decay: Vec<f64>,
+}
+
+impl ContactQC {
+    /// Ratio of cis to trans contacts. The number of trans contacts is floored at 1
+    /// so that cells without trans contacts have finite ratios.
+    pub fn cis_trans_ratio(&self) -> f64 {
+        self.num_cis as f64 / self.num_trans.max(1) as f64
+    }
+}
+
+/// Log-spaced distance bins starting from `min_distance`, with `bins_per_decade` bins
+/// per order of magnitude. The last edge is at least `max_distance`.
+pub fn log_distance_bins(min_distance: u64, max_distance: u64, bins_per_decade: usize) -> Vec<u64> {
+    let mut edges = vec![min_distance.max(1)];
+    let mut k = 1;
+    while *edges.last().unwrap() < max_distance {
+        let e = (min_distance.max(1) as f64 * 10f64.powf(k as f64 / bins_per_decade as f64)).round() as u64;
+        if e > *edges.last().unwrap() {
+            edges.push(e);
+        }
+        k += 1;
+    }
+    edges
+}
+
+pub struct ContactSummary<'a> {
+    num_cis: u64,
+    num_trans: u64,
+    num_mitochondrial: u64,
+    num_short_cis: u64,
+    num_long_cis: u64,
+    decay: Vec<u64>,
+    mitochondrial_dna: &'a HashSet<String>,
+    short_range: u64,
+    long_range: u64,
+    distance_bins: &'a [u64],
+}
+
+impl<'a> ContactSummary<'a> {
+    /// `distance_bins` are the edges of the distance bins used to compute the decay curve.
+    /// Contacts outside the range of the edges are not counted in the decay curve.
+    pub fn new(
+        mitochondrial_dna: &'a HashSet<String>,
+        short_range: u64,
+        long_range: u64,
+        distance_bins: &'a [u64],
+    ) -> Self {
+        ContactSummary {
+            num_cis: 0,
+            num_trans: 0,
+            num_mitochondrial: 0,
+            num_short_cis: 0,
+            num_long_cis: 0,
+            decay: vec![0; distance_bins.len().saturating_sub(1)],
+            mitochondrial_dna,
+            short_range,
+            long_range,
+            distance_bins,
+        }
+    }
+
+    pub fn update(&mut self, contact: &Contact) {
+        let count = contact.count as u64;
+        if self.mitochondrial_dna.contains(contact.chrom1.as_str()) ||
+            self.mitochondrial_dna.contains(contact.chrom2.as_str())
+        {
+            self.num_mitochondrial += count;
+        } else if contact.chrom1 != contact.chrom2 {
+            self.num_trans += count;
+        } else {
+            self.num_cis += count;
+            let d = contact.start1.abs_diff(contact.start2);
+            if d < self.short_range {
+                self.num_short_cis += count;
+            }
+            if d >= self.long_range {
+                self.num_long_cis += count;
+            }
+            let i = self.distance_bins.partition_point(|x| *x <= d);
+            if i > 0 && i < self.distance_bins.len() {
+                self.decay[i - 1] += count;
+            }
+        }
+    }
+
+    pub fn get_qc(self) -> ContactQC {
+        fn ratio(x: u64, total: u64) -> f64 {
+            if total == 0 { 0.0 } else { x as f64 / total as f64 }
+        }
+
+        let num_contacts = self.num_cis + self.num_trans;
+        let cis = self.num_cis;
+        ContactQC {
+            num_contacts,
+            num_cis: self.num_cis,
+            num_trans: self.num_trans,
+            frac_mitochondrial: ratio(self.num_mitochondrial, num_contacts + self.num_mitochondrial),
+            frac_short_cis: ratio(self.num_short_cis, cis),
+            frac_long_cis: ratio(self.num_long_cis, cis),
+            decay: self.decay.into_iter().map(|x| ratio(x, cis)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insertion(chrom: &str, pos: u64) -> Fragment {
+        Fragment {
+            chrom: chrom.to_string(),
+            start: pos,
+            end: pos + 1,
+            barcode: None,
+            count: 1,
+            strand: Some(Strand::Forward),
+        }
+    }
+
+    #[test]
+    fn test_tss_profile() {
+        let promoter = make_promoter_map(
+            vec![("chr1".to_string(), 1000, true), ("chr2".to_string(), 1000, false)].into_iter(),
+            10,
+        );
+        let fragments = vec![insertion("chr1", 995), insertion("chr2", 995), insertion("chr1", 1000)];
+        let profile = tss_profile(fragments.into_iter(), &promoter, 10);
+        assert_eq!(profile.len(), 21);
+        assert_eq!(profile[5], 1);
+        assert_eq!(profile[15], 1);
+        assert_eq!(profile[10], 1);
+
+        let mut profile = vec![1; 21];
+        profile[10] = 9;
+        profile[9] = 7;
+        let opts = TssEnrichmentOptions { window: 10, flank: 2, smooth: 0, ..TssEnrichmentOptions::encode() };
+        assert!((tss_enrichment(&profile, &opts) - 9.0 / 1.1).abs() < 1e-12);
+        let opts = TssEnrichmentOptions { window: 10, flank: 2, smooth: 0, ..TssEnrichmentOptions::signac() };
+        assert!((tss_enrichment(&profile, &opts) - 8.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_fragment_size_qc() {
+        let sizes = vec![50, 100, 146, 147, 200, 294, 295, 1500];
+        let qc = fragment_size_qc(sizes.into_iter(), 1000, 100);
+        assert_eq!(qc.nucleosome_signal, 1.0);
+        assert_eq!(qc.histogram, vec![(0, 1), (1, 2), (2, 3), (3, 2)]);
+    }
+
+    #[test]
+    fn test_contact_qc() {
+        let contact = |chrom1: &str, start1: u64, chrom2: &str, start2: u64| Contact {
+            chrom1: chrom1.to_string(),
+            start1,
+            chrom2: chrom2.to_string(),
+            start2,
+            barcode: "a".to_string(),
+            count: 1,
+        };
+        let mito = ["chrM".to_string()].into_iter().collect();
+        let bins = [1, 100, 10000];
+
+        let mut summary = ContactSummary::new(&mito, 50, 1000, &bins);
+        [
+            contact("chr1", 0, "chr1", 10),
+            contact("chr1", 0, "chr1", 5000),
+            contact("chr1", 0, "chr2", 10),
+            contact("chrM", 0, "chrM", 10),
+        ].iter().for_each(|c| summary.update(c));
+        let qc = summary.get_qc();
+        assert_eq!((qc.num_contacts, qc.num_cis, qc.num_trans), (3, 2, 1));
+        assert_eq!(qc.frac_mitochondrial, 0.25);
+        assert_eq!((qc.frac_short_cis, qc.frac_long_cis), (0.5, 0.5));
+        assert_eq!(qc.decay, vec![0.5, 0.5]);
+        assert_eq!(qc.cis_trans_ratio(), 2.0);
+
+        // Cells without cis or trans contacts.
+        let mut summary = ContactSummary::new(&mito, 50, 1000, &bins);
+        summary.update(&contact("chr1", 0, "chr2", 10));
+        let qc = summary.get_qc();
+        assert_eq!((qc.frac_short_cis, qc.frac_long_cis), (0.0, 0.0));
+        assert_eq!(qc.decay, vec![0.0, 0.0]);
+        assert_eq!(qc.cis_trans_ratio(), 0.0);
+
+        let qc = ContactSummary::new(&mito, 50, 1000, &bins).get_qc();
+        assert_eq!(qc.frac_mitochondrial, 0.0);
+        assert_eq!(qc.cis_trans_ratio(), 0.0);
+    }
+}
@@ -2,8 +2,8 @@ pub mod similarity;
 
 use std::path::Path;
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use flate2::{Compression, write::GzEncoder};
+use std::io::{BufWriter, Read, Write};
+use flate2::{Compression, write::GzEncoder, read::MultiGzDecoder};
 use anyhow::{Result, Context};
 
 use bed_utils::bed::{BEDLike, NarrowPeak, merge_bed_with};
@@ -67,6 +67,51 @@ pub fn open_file_for_write<P: AsRef<Path>>(
     Ok(writer)
 }
 
+/// Open a file for reading, possibly compressed. Supports gzip and zstd.
+pub fn open_file_for_read<P: AsRef<Path>>(file: P) -> Result<Box<dyn Read + Send>> {
+    let file = file.as_ref();
+    let open = || File::open(file).with_context(|| format!("cannot open file: {}", file.display()));
+    let reader: Box<dyn Read + Send> = if MultiGzDecoder::new(open()?).header().is_some() {
+        Box::new(MultiGzDecoder::new(open()?))
+    } else if file.extension().map_or(false, |x| x == "zst") {
+        Box::new(zstd::stream::read::Decoder::new(open()?)?)
+    } else {
+        Box::new(open()?)
+    };
+    Ok(reader)
+}
+
+/// Benjamini-Hochberg adjustment of p-values.
+pub fn adjust_pvalues_bh(pvalues: &[f64]) -> Vec<f64> {
+    let n = pvalues.len();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|a, b| pvalues[*b].partial_cmp(&pvalues[*a]).unwrap());
+    let mut qvalues = vec![0.0; n];
+    let mut cummin = 1.0f64;
+    order.into_iter().enumerate().for_each(|(k, i)| {
+        let rank = n - k;
+        cummin = cummin.min(pvalues[i] * n as f64 / rank as f64);
+        qvalues[i] = cummin;
+    });
+    qvalues
+}
+
+/// Median of a vector. `x` must be non-empty and free of NaNs.
+pub fn median(mut x: Vec<f64>) -> f64 {
+    x.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = x.len();
+    if n % 2 == 0 { (x[n / 2 - 1] + x[n / 2]) / 2.0 } else { x[n / 2] }
+}
+
+/// The `q`-th percentile of a vector, using linear interpolation between
+/// the closest ranks. `x` must be non-empty and free of NaNs.
+pub fn percentile(mut x: Vec<f64>, q: f64) -> f64 {
+    x.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let pos = (q / 100.0).clamp(0.0, 1.0) * (x.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    x[lo] + (x[hi] - x[lo]) * (pos - lo as f64)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,4 +145,22 @@ chr3\t0\t301\tb\t1000\t.\t74.1871\t290\t290.939\t100
 
         assert_eq!(expected, result);
     }
+
+    #[test]
+    fn test_adjust_pvalues_bh() {
+        let p = [0.01, 0.04, 0.03, 0.005, 0.5];
+        let expected = [0.025, 0.05, 0.05, 0.025, 0.5];
+        adjust_pvalues_bh(&p).into_iter().zip(expected).for_each(|(q, e)|
+            assert!((q - e).abs() < 1e-12, "{} != {}", q, e)
+        );
+    }
+
+    #[test]
+    fn test_median_percentile() {
+        assert_eq!(median(vec![3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(vec![4.0, 1.0, 2.0, 3.0]), 2.5);
+        assert_eq!(percentile(vec![1.0, 2.0, 3.0, 4.0, 5.0], 50.0), 3.0);
+        assert_eq!(percentile(vec![1.0, 2.0, 3.0, 4.0, 5.0], 80.0), 4.2);
+        assert_eq!(percentile(vec![1.0, 2.0, 3.0, 4.0, 5.0], 100.0), 5.0);
+    }
 }
\ No newline at end of file
@@ -366,6 +366,13 @@ where
     ArrayBase::from_vec(cor).into_shape((n1, n2)).unwrap()
 }
 
+/// Pearson correlation coefficient between two vectors.
+pub fn pearson(x: &[f64], y: &[f64]) -> f64 {
+    let x = Array2::from_shape_vec((1, x.len()), x.to_vec()).unwrap();
+    let y = Array2::from_shape_vec((1, y.len()), y.to_vec()).unwrap();
+    pearson2(x, y)[[0, 0]]
+}
+
 pub fn spearman2<T1, T2>(
     mat1: Array2<T1>,
     mat2: Array2<T2>,
@@ -16,6 +16,7 @@ def export_fragments(
     suffix: str = ".bed.zst",
     compression: Literal["gzip", "zstandard"] | None = None,
     compression_level: int | None = None,
+    fragment_size_range: tuple[int, int] | None = None,
 ) -> dict[str, str]:
     """Export and save fragments in a BED format file.
 
@@ -42,6 +43,9 @@ def export_fragments(
     compression_level
         Compression level. 1-9 for gzip, 1-22 for zstandard.
         If `None`, it is set to 6 for gzip and 3 for zstandard.
+    fragment_size_range
+        If provided, only fragments with sizes in `[min, max)` are exported,
+        e.g., `(0, 147)` for nucleosome-free fragments. Ignored for single-end data.
 
     Returns
     -------
@@ -67,6 +71,7 @@ def export_fragments(
 
     return internal.export_fragments(
         adata, list(ids), list(groupby), out_dir, prefix, suffix, selections, compression, compression_level,
+        fragment_size_range,
     )
 
 
@@ -78,6 +83,7 @@ def export_bigwig(
     out_dir: Path = "./",
     prefix: str = "",
     suffix: str = ".bw",
+    fragment_size_range: tuple[int, int] | None = None,
 ) -> dict[str, str]:
     """
     Export and create BigWig format files.
@@ -100,6 +106,9 @@ def export_bigwig(
         Text added to the output file name.
     suffix
         Text added to the output file name.
+    fragment_size_range
+        If provided, only fragments with sizes in `[min, max)` are counted,
+        e.g., `(0, 147)` for nucleosome-free fragments. Ignored for single-end data.
 
     Returns
     -------
@@ -114,4 +123,218 @@ def export_bigwig(
     
     return internal.export_bigwig(
         adata, list(groupby), selections, resolution, out_dir, prefix, suffix,
-    )
\ No newline at end of file
+        fragment_size_range,
+    )
+
+def export_cool(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    selections: list[str] | None = None,
+    resolutions: int | list[int] = 10000,
+    balance: Literal["ice", "kr"] | None = None,
+    out_dir: Path = "./",
+    prefix: str = "",
+    suffix: str | None = None,
+) -> dict[str, str]:
+    """
+    Export pseudobulk contact maps as cooler files.
+
+    Contacts of cells in the same group are summed and saved in the cooler format,
+    which can be visualized in HiGlass or analyzed with cooltools. If multiple
+    resolutions are given, a multi-resolution cooler (.mcool) is created for each group.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`, with contacts
+        stored in `.obsm['contact']`. See :func:`~snapatac2.pp.import_contacts`.
+    groupby
+        Group the cells. If a `str`, groups are obtained from
+        `.obs[groupby]`.
+    selections
+        Export only the selected groups.
+    resolutions
+        Bin size(s) of the contact maps.
+    balance
+        If set, balancing weights are computed using iterative correction ("ice")
+        or Knight-Ruiz balancing ("kr") and stored in the `weight` column of the bin table.
+    out_dir
+        Directory for saving the outputs.
+    prefix
+        Text added to the output file name.
+    suffix
+        Text added to the output file name. Defaults to ".cool" for a single
+        resolution and ".mcool" otherwise.
+
+    Returns
+    -------
+    dict[str, str]
+        A dictionary contains `(groupname, filename)` pairs. The file names are
+        formatted as `{prefix}{groupname}{suffix}`.
+    """
+    if isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    if selections is not None:
+        selections = set(selections)
+    if isinstance(resolutions, int):
+        resolutions = [resolutions]
+    if suffix is None:
+        suffix = ".cool" if len(resolutions) == 1 else ".mcool"
+
+    return internal.export_cool(
+        adata, list(groupby), selections, resolutions, balance, out_dir, prefix, suffix,
+    )
+
+def export_compartments(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    selections: list[str] | None = None,
+    resolution: int = 100000,
+    balance: Literal["ice", "kr"] | None = "ice",
+    gc_fasta: Path | None = None,
+    atac: internal.AnnData | internal.AnnDataSet | None = None,
+    out_dir: Path = "./",
+    prefix: str = "",
+    suffix: str = ".bw",
+) -> dict[str, str]:
+    """
+    Call A/B compartments from pseudobulk contact maps.
+
+    Contacts of cells in the same group are summed, and compartment scores are
+    computed for each chromosome as the leading eigenvector of the correlation
+    matrix of the observed/expected contact matrix. Since the sign of the
+    eigenvector is arbitrary, it is oriented to be positively correlated with
+    GC content or chromatin accessibility, so that positive scores correspond to
+    the A compartment.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`, with contacts
+        stored in `.obsm['contact']`. See :func:`~snapatac2.pp.import_contacts`.
+    groupby
+        Group the cells. If a `str`, groups are obtained from
+        `.obs[groupby]`.
+    selections
+        Export only the selected groups.
+    resolution
+        Bin size of the contact maps.
+    balance
+        Balance the contact maps using iterative correction ("ice") or
+        Knight-Ruiz balancing ("kr") before calling compartments.
+    gc_fasta
+        A FASTA file of the genome sequence. If provided, compartment scores are
+        oriented by the GC content of bins.
+    atac
+        ATAC-seq data with fragments stored in `.obsm['fragment_single']` or
+        `.obsm['fragment_paired']`. If provided and `gc_fasta` is `None`,
+        compartment scores are oriented by the accessibility of bins, aggregated
+        over all cells.
+    out_dir
+        Directory for saving the outputs.
+    prefix
+        Text added to the output file name.
+    suffix
+        Text added to the output file name. The output is in the bigwig format
+        if `suffix` ends with ".bw" or ".bigwig", and in the bedGraph format otherwise.
+
+    Returns
+    -------
+    dict[str, str]
+        A dictionary contains `(groupname, filename)` pairs. The file names are
+        formatted as `{prefix}{groupname}{suffix}`.
+    """
+    if isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    if selections is not None:
+        selections = set(selections)
+
+    return internal.export_compartments(
+        adata, list(groupby), selections, resolution, balance, gc_fasta, atac,
+        out_dir, prefix, suffix,
+    )
+
+def export_loops(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    selections: list[str] | None = None,
+    resolution: int = 10000,
+    balance: Literal["ice", "kr"] | None = "ice",
+    peak_width: int = 1,
+    window_width: int = 3,
+    min_distance: int = 20000,
+    max_distance: int = 2000000,
+    fdr: float = 0.1,
+    min_enrichment: float = 1.75,
+    min_enrichment_hv: float = 1.5,
+    cluster_radius: int = 2,
+    out_dir: Path = "./",
+    prefix: str = "",
+    suffix: str = ".bedpe",
+) -> dict[str, str]:
+    """
+    Call chromatin loops from pseudobulk contact maps.
+
+    Contacts of cells in the same group are summed, and loops are detected as
+    pixels enriched over their local neighbourhoods, following the HiCCUPS
+    algorithm [Rao14]_. For each pixel, local expected counts are computed from
+    the donut, lower-left, horizontal and vertical neighbourhoods. Observed counts
+    are tested against Poisson distributions, and the FDR is controlled within
+    lambda chunks, i.e., groups of pixels with similar expected counts.
+    Enriched pixels that are close to each other are merged into one loop.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`, with contacts
+        stored in `.obsm['contact']`. See :func:`~snapatac2.pp.import_contacts`.
+    groupby
+        Group the cells. If a `str`, groups are obtained from
+        `.obs[groupby]`.
+    selections
+        Export only the selected groups.
+    resolution
+        Bin size of the contact maps.
+    balance
+        Balance the contact maps using iterative correction ("ice") or
+        Knight-Ruiz balancing ("kr") before calling loops.
+    peak_width
+        Half width of the peak, in bins. Pixels within this distance from the
+        center are excluded from the neighbourhoods.
+    window_width
+        Half width of the neighbourhoods, in bins.
+    min_distance
+        Minimal distance between the two anchors of a loop.
+    max_distance
+        Maximal distance between the two anchors of a loop.
+    fdr
+        FDR threshold applied to each neighbourhood.
+    min_enrichment
+        Minimal observed/expected ratio over the donut and lower-left neighbourhoods.
+    min_enrichment_hv
+        Minimal observed/expected ratio over the horizontal and vertical neighbourhoods.
+    cluster_radius
+        Enriched pixels within this distance (in bins) are merged into one loop.
+    out_dir
+        Directory for saving the outputs.
+    prefix
+        Text added to the output file name.
+    suffix
+        Text added to the output file name.
+
+    Returns
+    -------
+    dict[str, str]
+        A dictionary contains `(groupname, filename)` pairs. The file names are
+        formatted as `{prefix}{groupname}{suffix}`.
+    """
+    if isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    if selections is not None:
+        selections = set(selections)
+
+    return internal.export_loops(
+        adata, list(groupby), selections, resolution, balance, peak_width,
+        window_width, min_distance, max_distance, fdr, min_enrichment,
+        min_enrichment_hv, cluster_radius, out_dir, prefix, suffix,
+    )
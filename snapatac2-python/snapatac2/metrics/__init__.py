@@ -1,7 +1,10 @@
 from __future__ import annotations
 
 from pathlib import Path
+from typing_extensions import Literal
 import numpy as np
+import pandas as pd
+import scipy.sparse
 
 import snapatac2
 import snapatac2._snapatac2 as internal
@@ -11,6 +14,13 @@ def tsse(
     adata: internal.AnnData | list[internal.AnnData],
     gene_anno: Genome | Path,
     *,
+    method: Literal["encode", "signac"] = "encode",
+    window: int | None = None,
+    flank: int | None = None,
+    smooth: int | None = None,
+    pseudocount: float | None = None,
+    profile: bool = False,
+    groupby: str | list[str] | None = None,
     inplace: bool = True,
     n_jobs: int = 8,
 ) -> np.ndarray | list[np.ndarray] | None:
@@ -18,6 +28,20 @@ def tsse(
 
     :func:`~snapatac2.pp.import_data` must be ran first in order to use this function.
 
+    Insertions within `window` bp of TSSs are counted at each position relative
+    to the TSS, oriented by the strand of transcripts. Two definitions of the
+    TSS enrichment score are supported:
+
+    - "encode": the ENCODE/ArchR definition. The insertion profile is smoothed
+      by a moving average, and the maximum of the smoothed profile is divided
+      by the average insertions in the flanking regions at both ends of the window.
+      Defaults: `window=2000`, `flank=100`, `smooth=5`, `pseudocount=0.1`.
+    - "signac": the Signac definition. The insertion profile is divided by the
+      average insertions in the flanking regions, and the score is the mean of
+      the two central positions. Cells without insertions in the flanking regions
+      are normalized by the average background of all cells.
+      Defaults: `window=1000`, `flank=100`, `smooth=0`, `pseudocount=0`.
+
     Parameters
     ----------
     adata
@@ -27,6 +51,22 @@ def tsse(
         In this case, the function will be applied to each AnnData object in parallel.
     gene_anno
         A :class:`~snapatac2.Genome` object or a GTF/GFF file containing the gene annotation.
+    method
+        Definition of the TSS enrichment score, either "encode" or "signac".
+    window
+        Size of the window on each side of TSSs.
+    flank
+        Size of the flanking regions at both ends of the window, used to estimate the background.
+    smooth
+        Half window of the moving average applied to the insertion profile.
+    pseudocount
+        Pseudocount added to the background.
+    profile
+        Whether to compute the insertion profiles around TSSs, which have
+        `2 * window + 1` positions with the TSS in the middle.
+    groupby
+        If provided, the insertion profiles are summed over cells in the same group.
+        If a `str`, groups are obtained from `.obs[groupby]`. Only used when `profile = True`.
     inplace
         Whether to add the results to `adata.obs` or return it as a dictionary.
     n_jobs
@@ -37,7 +77,12 @@ def tsse(
     -------
     np.ndarray | list[np.ndarray] | None
         If `inplace = True`, directly adds the results to `adata.obs['tsse']`.
-        Otherwise return the results.
+        Per-cell profiles are stored in `adata.obsm['tss_profile']`, and
+        per-group profiles are stored in `adata.uns['tss_profile']`, with group
+        names in `adata.uns['tss_profile_groups']`.
+        Otherwise return the results. If `profile = True`, a tuple of
+        scores and profiles is returned, where per-group profiles are
+        returned as a :class:`~pandas.DataFrame`.
 
     Examples
     --------
@@ -57,17 +102,52 @@ def tsse(
     if isinstance(adata, list):
         result = snapatac2._utils.anndata_par(
             adata,
-            lambda x: tsse(x, gene_anno, inplace=inplace),
+            lambda x: tsse(
+                x, gene_anno, method=method, window=window, flank=flank,
+                smooth=smooth, pseudocount=pseudocount, profile=profile,
+                groupby=groupby, inplace=inplace,
+            ),
             n_jobs=n_jobs,
         )
+        return None if inplace else result
+
+    if method == "encode":
+        defaults = dict(window=2000, flank=100, smooth=5, pseudocount=0.1)
+    elif method == "signac":
+        defaults = dict(window=1000, flank=100, smooth=0, pseudocount=0.0)
     else:
-        result = np.array(internal.tss_enrichment(adata, gene_anno))
-        if inplace:
-            adata.obs["tsse"] = result
+        raise NameError("method must be either 'encode' or 'signac'")
+    window = defaults["window"] if window is None else window
+    flank = defaults["flank"] if flank is None else flank
+    smooth = defaults["smooth"] if smooth is None else smooth
+    pseudocount = defaults["pseudocount"] if pseudocount is None else pseudocount
+    if isinstance(groupby, str):
+        groupby = adata.obs[groupby]
+    if groupby is not None:
+        groupby = list(groupby)
+
+    scores, tss_profile, groups = internal.tss_enrichment(
+        adata, gene_anno, method, window, flank, smooth, pseudocount, profile, groupby,
+    )
+    scores = np.array(scores)
+    if groups is not None:
+        tss_profile = pd.DataFrame(
+            tss_profile.toarray(), index=groups, columns=np.arange(-window, window + 1),
+        )
+
     if inplace:
+        adata.obs["tsse"] = scores
+        if tss_profile is not None:
+            if groups is None:
+                adata.obsm["tss_profile"] = tss_profile
+            else:
+                adata.uns["tss_profile"] = tss_profile.to_numpy()
+                adata.uns["tss_profile_groups"] = np.array(groups)
         return None
+    elif profile:
+        return scores, tss_profile
     else:
-        return result
+        return scores
 
 def frip(
     adata: internal.AnnData | list[internal.AnnData],
@@ -193,4 +273,169 @@ def frag_size_distr(
         if inplace:
             adata.uns[add_key] = result
         else:
-            return result
\ No newline at end of file
+            return result
+
+def frag_size_qc(
+    adata: internal.AnnData | list[internal.AnnData],
+    *,
+    max_recorded_size: int = 1000,
+    bin_size: int = 1,
+    add_key: str = "frag_size",
+    inplace: bool = True,
+    n_jobs: int = 8,
+) -> tuple[np.ndarray, np.ndarray, scipy.sparse.csr_matrix] | list | None:
+    """ Compute fragment size distributions and nucleosome banding metrics for each cell.
+
+    Unlike :func:`~snapatac2.metrics.frag_size_distr`, this function operates at the
+    single-cell level. The fragment size histogram of each cell is stored as a row of
+    a sparse matrix, where the column `k > 0` counts fragments with sizes in
+    `((k - 1) * bin_size, k * bin_size]`, and the first column counts fragments
+    larger than `max_recorded_size`. In addition, two metrics of nucleosome banding
+    are computed:
+
+    - `nucleosome_signal`: the ratio of mono-nucleosomal fragments (147-294 bp) to
+      nucleosome-free fragments (< 147 bp), as defined in Signac.
+    - `nucleosome_periodicity`: the strength of the ~200 bp periodicity of the
+      fragment size distribution. The distribution of fragment sizes between
+      50 and 800 bp is detrended, and the score is the largest Fourier amplitude
+      at periods between 160 and 220 bp, divided by the number of fragments.
+      Cells without nucleosome banding have scores close to 0.
+
+    This function requires paired-end data.
+    :func:`~snapatac2.pp.import_data` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+        `adata` could also be a list of AnnData objects.
+        In this case, the function will be applied to each AnnData object in parallel.
+    max_recorded_size
+        The maximum fragment size to record in the histograms.
+    bin_size
+        Bin size of the histograms.
+    add_key
+        Key used to store the histograms in `adata.obsm`.
+    inplace
+        Whether to add the results to `adata.obs` and `adata.obsm` or return them.
+    n_jobs
+        Number of jobs to run in parallel when `adata` is a list.
+        If `n_jobs=-1`, all CPUs will be used.
+
+    Returns
+    -------
+    tuple[np.ndarray, np.ndarray, scipy.sparse.csr_matrix] | list | None
+        If `inplace = True`, directly adds the metrics to
+        `adata.obs['nucleosome_signal']` and `adata.obs['nucleosome_periodicity']`,
+        and the histograms to `adata.obsm['`add_key`']`.
+        Otherwise return the nucleosome signal, the periodicity and the histograms.
+    """
+    if isinstance(adata, list):
+        result = snapatac2._utils.anndata_par(
+            adata,
+            lambda x: frag_size_qc(
+                x, max_recorded_size=max_recorded_size, bin_size=bin_size,
+                add_key=add_key, inplace=inplace,
+            ),
+            n_jobs=n_jobs,
+        )
+        return None if inplace else result
+
+    signal, periodicity, histograms = internal.fragment_size_qc(adata, max_recorded_size, bin_size)
+    signal = np.array(signal)
+    periodicity = np.array(periodicity)
+    if inplace:
+        adata.obs["nucleosome_signal"] = signal
+        adata.obs["nucleosome_periodicity"] = periodicity
+        adata.obsm[add_key] = histograms
+        return None
+    else:
+        return signal, periodicity, histograms
+
+def contact_qc(
+    adata: internal.AnnData | list[internal.AnnData],
+    *,
+    mitochondrial_dna: list[str] = ["chrM", "M"],
+    short_range: int = 20000,
+    long_range: int = 2000000,
+    min_distance: int = 1000,
+    max_distance: int = 300000000,
+    bins_per_decade: int = 8,
+    add_key: str = "contact_decay",
+    inplace: bool = True,
+    n_jobs: int = 8,
+) -> tuple[dict[str, np.ndarray], np.ndarray, np.ndarray] | list | None:
+    """ Compute quality control metrics of chromatin contacts for each cell.
+
+    The following metrics are computed: the number of contacts ("n_contact"),
+    the number of cis and trans contacts ("n_cis", "n_trans") and their ratio
+    ("cis_trans_ratio", with the number of trans contacts floored at 1), the fraction of contacts involving mitochondrial
+    chromosomes ("frac_mito"), and the fractions of cis contacts whose distance
+    is smaller than `short_range` ("frac_short_cis") or at least `long_range`
+    ("frac_long_cis"). In addition, the contact-distance decay curve P(s), i.e.,
+    the fraction of cis contacts in log-spaced distance bins, is computed for each cell.
+    The decay curves can be used to order cells by cell-cycle phase.
+    Fractions are 0 for cells without contacts in the corresponding denominator.
+
+    :func:`~snapatac2.pp.import_contacts` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        `adata` could also be a list of AnnData objects.
+        In this case, the function will be applied to each AnnData object in parallel.
+    mitochondrial_dna
+        Names of mitochondrial chromosomes.
+    short_range
+        Distance threshold for short-range cis contacts.
+    long_range
+        Distance threshold for long-range cis contacts.
+    min_distance
+        Left edge of the first distance bin.
+    max_distance
+        The distance bins extend to at least `max_distance`.
+    bins_per_decade
+        Number of distance bins per order of magnitude.
+    add_key
+        Key used to store the decay curves in `adata.obsm`. The bin edges are
+        stored in `adata.uns[add_key + "_bins"]`.
+    inplace
+        Whether to add the results to `adata.obs` and `adata.obsm` or return them.
+    n_jobs
+        Number of jobs to run in parallel when `adata` is a list.
+        If `n_jobs=-1`, all CPUs will be used.
+
+    Returns
+    -------
+    tuple[dict[str, np.ndarray], np.ndarray, np.ndarray] | list | None
+        If `inplace = True`, directly adds the results to `adata.obs`, `adata.obsm[add_key]`
+        and `adata.uns[add_key + "_bins"]`. Otherwise return the QC metrics, the decay
+        curves and the bin edges.
+    """
+    if isinstance(adata, list):
+        return snapatac2._utils.anndata_par(
+            adata,
+            lambda x: contact_qc(
+                x, mitochondrial_dna=mitochondrial_dna, short_range=short_range,
+                long_range=long_range, min_distance=min_distance, max_distance=max_distance,
+                bins_per_decade=bins_per_decade, add_key=add_key, inplace=inplace,
+            ),
+            n_jobs=n_jobs,
+        )
+    else:
+        metrics, decay, bins = internal.contact_qc(
+            adata, mitochondrial_dna, short_range, long_range, min_distance,
+            max_distance, bins_per_decade,
+        )
+        metrics = {k: np.array(v) for k, v in metrics.items()}
+        decay = np.array(decay)
+        bins = np.array(bins)
+        if inplace:
+            for k, v in metrics.items():
+                adata.obs[k] = v
+            adata.obsm[add_key] = decay
+            adata.uns[add_key + "_bins"] = bins
+        else:
+            return metrics, decay, bins
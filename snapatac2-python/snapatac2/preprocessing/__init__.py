@@ -3,4 +3,6 @@ from ._knn import knn
 from ._mnn_correct import mnc_correct
 from ._harmony import harmony
 from ._scanorama import scanorama_integrate
-from ._scrublet import scrublet, filter_doublets
\ No newline at end of file
+from ._scrublet import scrublet, filter_doublets
+from ._amulet import amulet
+from ._multiplet import detect_multiplets
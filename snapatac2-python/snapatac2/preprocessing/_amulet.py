@@ -0,0 +1,82 @@
+""" Implementation of the AMULET algorithm for single-cell ATAC-seq data
+"""
+from __future__ import annotations
+
+from pathlib import Path
+import numpy as np
+
+from .._utils import anndata_par
+import snapatac2._snapatac2 as internal
+
+def amulet(
+    adata: internal.AnnData | list[internal.AnnData],
+    blacklist: Path | None = None,
+    exclude_chroms: list[str] = ["chrX", "chrY", "chrM", "X", "Y", "MT"],
+    max_overlap: int = 2,
+    inplace: bool = True,
+    n_jobs: int = 8,
+) -> tuple[np.ndarray, np.ndarray, np.ndarray] | None:
+    """
+    Detect doublets using the AMULET algorithm.
+
+    In a diploid genome, at most two fragments from a single nucleus can overlap
+    at any position. This function counts, for each cell, the number of loci
+    covered by more than `max_overlap` fragments, and tests it against a Poisson
+    distribution whose mean is the average number of such loci over all cells
+    [Thibodeau21]_. Unlike :func:`~snapatac2.pp.scrublet`, it does not require
+    a count matrix or simulated doublets.
+
+    This function requires paired-end data.
+    :func:`~snapatac2.pp.import_data` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+        `adata` can also be a list of AnnData objects.
+        In this case, the function will be applied to each AnnData object in parallel.
+    blacklist
+        A BED file containing repeat or blacklist regions. Loci overlapping these
+        regions are ignored.
+    exclude_chroms
+        Chromosomes to ignore, e.g., sex chromosomes and mitochondrial DNA.
+    max_overlap
+        Loci covered by more than this number of fragments are counted.
+    inplace
+        Whether update the AnnData object inplace
+    n_jobs
+        Number of jobs to run in parallel when `adata` is a list.
+
+    Returns
+    -------
+    tuple[np.ndarray, np.ndarray, np.ndarray] | None:
+        if ``inplace = True``, it updates adata with the following fields:
+            - ``adata.obs["amulet_overlaps"]``: number of loci with more than `max_overlap` fragments
+            - ``adata.obs["amulet_pvalue"]``: p-value of being a doublet
+            - ``adata.obs["amulet_qvalue"]``: Benjamini-Hochberg adjusted p-value
+        Otherwise, these values are returned.
+    """
+    if isinstance(adata, list):
+        result = anndata_par(
+            adata,
+            lambda x: amulet(x, blacklist, exclude_chroms, max_overlap, inplace),
+            n_jobs=n_jobs,
+        )
+        if inplace:
+            return None
+        else:
+            return result
+
+    overlaps, pvalue, qvalue = internal.amulet(
+        adata, blacklist, list(exclude_chroms), max_overlap,
+    )
+    overlaps = np.array(overlaps)
+    pvalue = np.array(pvalue)
+    qvalue = np.array(qvalue)
+    if inplace:
+        adata.obs["amulet_overlaps"] = overlaps
+        adata.obs["amulet_pvalue"] = pvalue
+        adata.obs["amulet_qvalue"] = qvalue
+    else:
+        return overlaps, pvalue, qvalue
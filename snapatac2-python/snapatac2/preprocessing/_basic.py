@@ -5,13 +5,14 @@ from pathlib import Path
 import numpy as np
 from anndata import AnnData
 import logging
+import math
 
 import snapatac2
 import snapatac2._snapatac2 as internal
 from snapatac2.genome import Genome
 
 __all__ = ['make_fragment_file', 'import_data', 'import_contacts', 'add_tile_matrix',
-           'make_peak_matrix', 'filter_cells', 'select_features', 'make_gene_matrix'
+           'impute_contacts', 'normalize', 'make_peak_matrix', 'filter_cells', 'select_features', 'make_gene_matrix'
 ]
 
 def make_fragment_file(
@@ -284,6 +285,7 @@ def import_contacts(
     file: Path | None = None,
     genome: Genome | None = None,
     chrom_size: dict[str, int] | None = None,
+    max_distance: int | None = None,
     sorted_by_barcode: bool = True,
     chunk_size: int = 2000,
     tempdir: Path | None = None,
@@ -291,10 +293,16 @@ def import_contacts(
 ) -> internal.AnnData:
     """Import chromatin contacts.
 
+    Contacts can be stored either in a tab-delimited file with columns
+    "barcode, chrom1, pos1, chrom2, pos2[, count]", or in the 4DN `.pairs(.gz)` format.
+    For `.pairs` files, the columns are determined by the `#columns:` header line,
+    and the cell barcodes are read from the "cell", "cell_id", "cell_barcode" or
+    "barcode" column.
+
     Parameters
     ----------
     contact_file
-        File name of the fragment file.
+        File name of the contact file.
     file
         File name of the output h5ad file used to store the result. If provided,
         result will be saved to a backed AnnData, otherwise an in-memory AnnData
@@ -306,10 +314,15 @@ def import_contacts(
     chrom_size
         A dictionary containing chromosome sizes, for example,
         `{"chr1": 2393, "chr2": 2344, ...}`.
-        This is required if `genome` is not set.
+        This is required if `genome` is not set, unless the chromosome sizes are
+        declared in the header of a `.pairs` file.
         Setting `chrom_size` will override the chrom_size from the `genome` parameter.
+    max_distance
+        If set, only intra-chromosomal contacts whose loci are at most `max_distance`
+        base pairs apart are kept. This greatly reduces the storage at the cost of
+        discarding inter-chromosomal and long-range contacts.
     sorted_by_barcode
-        Whether the fragment file has been sorted by cell barcodes.
+        Whether the contact file has been sorted by cell barcodes.
         If `sorted_by_barcode == True`, this function makes use of small fixed amout of 
         memory. If `sorted_by_barcode == False` and `low_memory == False`,
         all data will be kept in memory. See `low_memory` for more details.
@@ -334,21 +347,24 @@ def import_contacts(
 
     adata = AnnData() if file is None else internal.AnnData(filename=file, backend=backend)
     internal.import_contacts(
-        adata, contact_file, chrom_size, sorted_by_barcode, chunk_size, tempdir
+        adata, contact_file, chrom_size, max_distance, sorted_by_barcode, chunk_size, tempdir
     )
     return adata
 
 def add_tile_matrix(
     adata: internal.AnnData | list[internal.AnnData],
     *,
-    bin_size: int = 500,
+    bin_size: int | list[int] = 500,
+    bin_file: Path | None = None,
     inplace: bool = True,
     chunk_size: int = 500,
     exclude_chroms: list[str] | str | None = ["chrM", "chrY", "M", "Y"],
-    file: Path | None = None,
+    counting_strategy: Literal['insertion', 'fragment', 'paired-insertion'] = 'insertion',
+    fragment_size_range: tuple[int, int] | None = None,
+    file: Path | list[Path] | None = None,
     backend: Literal['hdf5'] = 'hdf5',
     n_jobs: int = 8,
-) -> internal.AnnData | None:
+) -> internal.AnnData | list[internal.AnnData] | None:
     """Generate cell by bin count matrix.
 
     This function is used to generate and add a cell by bin count matrix to the AnnData
@@ -365,12 +381,28 @@ def add_tile_matrix(
         In this case, the function will be applied to each AnnData object in parallel.
     bin_size
         The size of consecutive genomic regions used to record the counts.
+        If a list, matrices at all resolutions are computed in a single pass
+        over the fragments and saved to the files given by `file`, which must
+        be a list of the same length. This requires `inplace=False`.
+    bin_file
+        A BED file of non-overlapping regions used as bins, e.g., bins adapted
+        to gene density. If provided, `bin_size` and `exclude_chroms` are ignored.
     inplace
         Whether to add the tile matrix to the AnnData object or return a new AnnData object.
     chunk_size
         Increasing the chunk_size speeds up I/O but uses more memory.
     exclude_chroms
         A list of chromosomes to exclude.
+    counting_strategy
+        How paired-end fragments are counted. "insertion": the two Tn5 insertions
+        of a fragment are counted independently. "fragment": a fragment is counted
+        once for each feature it overlaps, as in Signac. "paired-insertion": a
+        fragment is counted once for each feature containing either of its
+        insertions, as in ArchR. Single-end reads are always counted by their
+        insertion sites.
+    fragment_size_range
+        If provided, only fragments with sizes in `[min, max)` are counted,
+        e.g., `(0, 147)` for nucleosome-free fragments. Ignored for single-end data.
     file
         File name of the output file used to store the result. If provided, result will
         be saved to a backed AnnData, otherwise an in-memory AnnData is used.
@@ -383,10 +415,11 @@ def add_tile_matrix(
     
     Returns
     -------
-    AnnData | ad.AnnData | None
+    AnnData | ad.AnnData | list[AnnData] | None
         An annotated data matrix of shape `n_obs` x `n_vars`. Rows correspond to
         cells and columns to bins. If `file=None`, an in-memory AnnData will be
-        returned, otherwise a backed AnnData is returned.
+        returned, otherwise a backed AnnData is returned. If `bin_size` is a list,
+        a list of backed AnnData objects is returned.
 
     See Also
     --------
@@ -407,15 +440,30 @@ def add_tile_matrix(
     if isinstance(exclude_chroms, str):
         exclude_chroms = [exclude_chroms]
 
+    if isinstance(bin_size, list) and bin_file is None:
+        if inplace or not isinstance(file, list) or len(file) != len(bin_size):
+            raise ValueError("multiple bin sizes require `inplace=False` and a list of files of the same length")
+        outs = [internal.AnnData(filename=f, backend=backend, obs=adata.obs[:]) for f in file]
+        internal.mk_tile_matrices(
+            adata, bin_size, chunk_size, counting_strategy, fragment_size_range, exclude_chroms, outs,
+        )
+        return outs
+
+    def mk_matrix(x, out):
+        if bin_file is None:
+            internal.mk_tile_matrix(x, bin_size, chunk_size, counting_strategy, fragment_size_range, exclude_chroms, out)
+        else:
+            internal.mk_partition_matrix(x, bin_file, chunk_size, counting_strategy, fragment_size_range, out)
+
     if inplace:
         if isinstance(adata, list):
             snapatac2._utils.anndata_par(
                 adata,
-                lambda x: internal.mk_tile_matrix(x, bin_size, chunk_size, exclude_chroms, None),
+                lambda x: mk_matrix(x, None),
                 n_jobs=n_jobs,
             )
         else:
-            internal.mk_tile_matrix(adata, bin_size, chunk_size, exclude_chroms, None)
+            mk_matrix(adata, None)
     else:
         if file is None:
             if adata.isbacked:
@@ -424,7 +472,166 @@ def add_tile_matrix(
                 out = AnnData(obs=adata.obs[:])
         else:
             out = internal.AnnData(filename=file, backend=backend, obs=adata.obs[:])
-        internal.mk_tile_matrix(adata, bin_size, chunk_size, exclude_chroms, out)
+        mk_matrix(adata, out)
+        return out
+
+def impute_contacts(
+    adata: internal.AnnData | internal.AnnDataSet,
+    *,
+    resolution: int = 1000000,
+    pad: int = 1,
+    restart_prob: float = 0.5,
+    max_distance: int | None = None,
+    top_percent: float | None = 20,
+    key: str | None = None,
+    inplace: bool = True,
+    chunk_size: int = 500,
+    file: Path | None = None,
+    backend: Literal['hdf5'] = 'hdf5',
+) -> internal.AnnData | None:
+    """Impute single-cell contact maps.
+
+    This function implements the imputation procedure of scHiCluster [Zhou19]_.
+    For each cell and each chromosome, the binned contact matrix is smoothed
+    by a linear convolution, followed by a random walk with restart.
+    The imputed contacts within `max_distance` are then binarized by keeping
+    the top `top_percent` percent of each chromosome. The result can be used
+    as input to :func:`~snapatac2.tl.spectral`.
+
+    :func:`~snapatac2.pp.import_contacts` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    resolution
+        The bin size of the contact matrices.
+    pad
+        The size of the convolution window is `2 * pad + 1`.
+    restart_prob
+        The restart probability of the random walk.
+    max_distance
+        Only contacts whose distance is within `max_distance` are used as features.
+        If `None`, all intra-chromosomal contacts are used.
+    top_percent
+        Keep the top percentage of imputed values in each chromosome and binarize them.
+        If `None`, the imputed values are returned without binarization.
+    key
+        If provided, the result is stored in `.obsm[key]`. Otherwise, it is stored in `.X`.
+    inplace
+        Whether to add the result to the AnnData object or return a new AnnData object.
+    chunk_size
+        Increasing the chunk_size speeds up I/O but uses more memory.
+    file
+        File name of the output file used to store the result. If provided, result will
+        be saved to a backed AnnData, otherwise an in-memory AnnData is used.
+        This has no effect when `inplace=True`.
+    backend
+        The backend to use for storing the result.
+
+    Returns
+    -------
+    AnnData | ad.AnnData | None
+        An annotated data matrix of shape `n_obs` x `n_vars` if `inplace=False`.
+    """
+    if inplace:
+        internal.impute_contacts(
+            adata, resolution, pad, restart_prob, max_distance, top_percent, key, chunk_size, None,
+        )
+    else:
+        if file is None:
+            if adata.isbacked:
+                out = AnnData(obs=adata.obs[:].to_pandas())
+            else:
+                out = AnnData(obs=adata.obs[:])
+        else:
+            out = internal.AnnData(filename=file, backend=backend, obs=adata.obs[:])
+        internal.impute_contacts(
+            adata, resolution, pad, restart_prob, max_distance, top_percent, key, chunk_size, out,
+        )
+        return out
+
+def normalize(
+    adata: internal.AnnData | internal.AnnDataSet,
+    method: Literal['tfidf', 'cpm', 'log1p', 'binarize'] = 'tfidf',
+    *,
+    tfidf_method: Literal['log_tfidf', 'tf_logidf', 'logtf_logidf', 'idf'] = 'log_tfidf',
+    scale_factor: float | None = None,
+    binarize: bool = False,
+    log1p: bool = True,
+    key_added: str | None = None,
+    chunk_size: int = 2000,
+    file: Path | None = None,
+    backend: Literal['hdf5'] = 'hdf5',
+) -> internal.AnnData | None:
+    """Normalize the count matrix chunk by chunk.
+
+    Unlike normalizing `.X` after loading it into memory, this function streams
+    through `.X` in chunks of cells, so it works on backed data of any size.
+    The result is written either to a layer of `adata` or to `.X` of a new
+    AnnData object; `.X` itself is never modified.
+
+    The TF-IDF variants follow Signac [Stuart21]_. TF is the count of a feature
+    divided by the total count of the cell, and IDF is the number of cells divided
+    by the total count of the feature:
+
+    - "log_tfidf": `log1p(TF * IDF * scale_factor)`. Combined with `binarize=True`,
+      this is the log-TF-IDF transformation used by ArchR [Granja21]_.
+    - "tf_logidf": `TF * log(1 + IDF)`.
+    - "logtf_logidf": `log1p(TF * scale_factor) * log(1 + IDF)`.
+    - "idf": `count * IDF`.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+    method
+        The normalization method. "cpm" scales the counts of each cell to sum to
+        `scale_factor`.
+    tfidf_method
+        The TF-IDF variant to use when `method="tfidf"`.
+    scale_factor
+        The scale factor used by "tfidf" and "cpm". Defaults to 1e4 for "tfidf"
+        and 1e6 for "cpm".
+    binarize
+        Whether to binarize the counts before computing TF-IDF.
+    log1p
+        Whether to apply `log1p` after "cpm" normalization.
+    key_added
+        If provided, the result is stored in `.layers[key_added]`. Otherwise,
+        the result is returned as a new AnnData object.
+    chunk_size
+        Increasing the chunk_size speeds up I/O but uses more memory.
+    file
+        File name of the output file used to store the result. If provided, result will
+        be saved to a backed AnnData, otherwise an in-memory AnnData is used.
+        This has no effect when `key_added` is provided.
+    backend
+        The backend to use for storing the result.
+
+    Returns
+    -------
+    AnnData | ad.AnnData | None
+        An annotated data matrix of shape `n_obs` x `n_vars` if `key_added` is None.
+    """
+    if scale_factor is None:
+        scale_factor = 1e6 if method == 'cpm' else 1e4
+
+    if key_added is not None:
+        internal.normalize(
+            adata, method, tfidf_method, scale_factor, binarize, log1p, chunk_size, key_added, None,
+        )
+    else:
+        if file is None:
+            if adata.isbacked:
+                out = AnnData(obs=adata.obs[:].to_pandas())
+            else:
+                out = AnnData(obs=adata.obs[:])
+        else:
+            out = internal.AnnData(filename=file, backend=backend, obs=adata.obs[:])
+        internal.normalize(
+            adata, method, tfidf_method, scale_factor, binarize, log1p, chunk_size, None, out,
+        )
         return out
 
 def make_peak_matrix(
@@ -437,6 +644,8 @@ def make_peak_matrix(
     peak_file: Path | None = None,
     chunk_size: int = 500,
     use_x: bool = False,
+    counting_strategy: Literal['insertion', 'fragment', 'paired-insertion'] = 'insertion',
+    fragment_size_range: tuple[int, int] | None = None,
 ) -> internal.AnnData:
     """Generate cell by peak count matrix.
 
@@ -470,6 +679,17 @@ def make_peak_matrix(
     use_x
         If True, use the matrix stored in `.X` as raw counts.
         Otherwise the `.obsm['insertion']` is used.
+    counting_strategy
+        How paired-end fragments are counted. "insertion": the two Tn5 insertions
+        of a fragment are counted independently. "fragment": a fragment is counted
+        once for each feature it overlaps, as in Signac. "paired-insertion": a
+        fragment is counted once for each feature containing either of its
+        insertions, as in ArchR. Single-end reads are always counted by their
+        insertion sites. This has no effect when `use_x=True`.
+    fragment_size_range
+        If provided, only fragments with sizes in `[min, max)` are counted,
+        e.g., `(0, 147)` for nucleosome-free fragments. Ignored for single-end data.
+        This has no effect when `use_x=True`.
 
     Returns
     -------
@@ -515,7 +735,7 @@ def make_peak_matrix(
                 peaks = [line.strip() for line in f]
 
     if inplace:
-        internal.mk_peak_matrix(adata, peaks, chunk_size, use_x, None)
+        internal.mk_peak_matrix(adata, peaks, chunk_size, use_x, counting_strategy, fragment_size_range, None)
     else:
         if file is None:
             if adata.isbacked:
@@ -524,7 +744,7 @@ def make_peak_matrix(
                 out = AnnData(obs=adata.obs[:])
         else:
             out = internal.AnnData(filename=file, backend=backend, obs=adata.obs[:])
-        internal.mk_peak_matrix(adata, peaks, chunk_size, use_x, out)
+        internal.mk_peak_matrix(adata, peaks, chunk_size, use_x, counting_strategy, fragment_size_range, out)
         return out
 
 def make_gene_matrix(
@@ -537,6 +757,18 @@ def make_gene_matrix(
     chunk_size: int = 500,
     use_x: bool = False,
     id_type: Literal['gene', 'transcript'] = "gene",
+    method: Literal['count', 'score'] = "count",
+    upstream: int = 2000,
+    downstream: int = 0,
+    include_gene_body: bool = True,
+    decay: float = 5000,
+    offset: float = math.exp(-1),
+    extend_upstream: tuple[int, int] = (1000, 100000),
+    extend_downstream: tuple[int, int] = (1000, 100000),
+    use_gene_boundaries: bool = True,
+    gene_scale_factor: float | None = 5.0,
+    counting_strategy: Literal['insertion', 'fragment', 'paired-insertion'] = 'insertion',
+    fragment_size_range: tuple[int, int] | None = None,
 ) -> internal.AnnData:
     """Generate cell by gene activity matrix.
 
@@ -544,6 +776,12 @@ def make_gene_matrix(
     body regions. The result will be stored in a new file and a new AnnData object
     will be created.
 
+    When `method="score"`, gene activity scores are computed as in ArchR [Granja21]_:
+    insertions are weighted by `exp(-d / decay) + offset`, where `d` is the distance
+    to the gene body (or the TSS if `include_gene_body=False`). Insertions are only
+    assigned to genes whose domains, i.e., the gene body extended by `extend_upstream`
+    and `extend_downstream`, contain them.
+
     :func:`~snapatac2.pp.import_data` must be ran first in order to use this function.
 
     Parameters
@@ -566,6 +804,49 @@ def make_gene_matrix(
         Otherwise the `.obsm['insertion']` is used.
     id_type
         "gene" or "transcript".
+    method
+        "count": count insertions in promoters and gene bodies with equal weights.
+        "score": compute gene activity scores with exponential distance decay.
+        "score" can only be used with `id_type="gene"`.
+    upstream
+        The number of base pairs upstream of TSSs included in promoters.
+        Only used when `method="count"`.
+    downstream
+        The number of base pairs downstream of TSSs included in promoters.
+        Only used when `method="count"`.
+    include_gene_body
+        Whether to include gene bodies in promoters (`method="count"`), or to
+        measure distances from gene bodies rather than TSSs (`method="score"`).
+    decay
+        The decay length, in base pairs, of insertion weights.
+        Only used when `method="score"`.
+    offset
+        The constant added to insertion weights. Only used when `method="score"`.
+    extend_upstream
+        The minimum and maximum extensions of gene domains upstream of TSSs.
+        Only used when `method="score"`.
+    extend_downstream
+        The minimum and maximum extensions of gene domains downstream of gene ends.
+        Only used when `method="score"`.
+    use_gene_boundaries
+        Whether gene domains stop at the boundaries of neighbouring genes.
+        Minimum extensions are always applied. Only used when `method="score"`.
+    gene_scale_factor
+        If not None, weights of genes are inversely proportional to gene lengths,
+        linearly scaled to the range from 1 to `gene_scale_factor`.
+        Only used when `method="score"`.
+    counting_strategy
+        How paired-end fragments are counted. "insertion": the two Tn5 insertions
+        of a fragment are counted independently. "fragment": a fragment is counted
+        once for each feature it overlaps, as in Signac. "paired-insertion": a
+        fragment is counted once for each feature containing either of its
+        insertions, as in ArchR. Single-end reads are always counted by their
+        insertion sites. With `method="score"` and "fragment", only the midpoint
+        of each fragment is scored. This has no effect when `use_x=True`.
+    fragment_size_range
+        If provided, only fragments with sizes in `[min, max)` are counted,
+        e.g., `(0, 147)` for nucleosome-free fragments. Ignored for single-end data.
+        This has no effect when `use_x=True`.
 
     Returns
     -------
@@ -591,8 +872,13 @@ def make_gene_matrix(
     if isinstance(gene_anno, Genome):
         gene_anno = gene_anno.fetch_annotations()
 
+    args = (
+        method, upstream, downstream, include_gene_body, decay, offset,
+        extend_upstream, extend_downstream, use_gene_boundaries, gene_scale_factor,
+        counting_strategy, fragment_size_range,
+    )
     if inplace:
-        internal.mk_gene_matrix(adata, gene_anno, chunk_size, use_x, id_type, None)
+        internal.mk_gene_matrix(adata, gene_anno, chunk_size, use_x, id_type, *args, None)
     else:
         if file is None:
             if adata.isbacked:
@@ -601,7 +887,7 @@ def make_gene_matrix(
                 out = AnnData(obs=adata.obs[:])
         else:
             out = internal.AnnData(filename=file, backend=backend, obs=adata.obs[:])
-        internal.mk_gene_matrix(adata, gene_anno, chunk_size, use_x, id_type, out)
+        internal.mk_gene_matrix(adata, gene_anno, chunk_size, use_x, id_type, *args, out)
         return out
 
 def filter_cells(
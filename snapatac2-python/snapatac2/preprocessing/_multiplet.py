@@ -0,0 +1,99 @@
+""" Detection of barcode multiplets in droplet-based single-cell ATAC-seq data
+"""
+from __future__ import annotations
+
+import numpy as np
+import pandas as pd
+
+from .._utils import anndata_par
+import snapatac2._snapatac2 as internal
+
+def detect_multiplets(
+    adata: internal.AnnData | list[internal.AnnData],
+    min_shared: int = 5,
+    min_jaccard: float = 0.01,
+    max_barcodes_per_fragment: int = 10,
+    inplace: bool = True,
+    n_jobs: int = 8,
+) -> tuple[np.ndarray, np.ndarray, pd.DataFrame] | None:
+    """
+    Detect barcode multiplets.
+
+    A droplet may contain more than one gel bead, in which case the fragments
+    of a single cell are split across several barcodes. These barcode multiplets
+    share an unusual number of fragments with identical coordinates. This function
+    finds barcode pairs with at least `min_shared` shared fragments and a Jaccard
+    index of at least `min_jaccard`, and groups them. Similar to the "excluded barcodes"
+    of Cell Ranger ATAC, the barcode with the most fragments in each group is kept
+    as the representative, and the other barcodes are flagged.
+
+    :func:`~snapatac2.pp.import_data` must be ran first in order to use this function.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`.
+        Rows correspond to cells and columns to regions.
+        `adata` can also be a list of AnnData objects.
+        In this case, the function will be applied to each AnnData object in parallel.
+    min_shared
+        Minimum number of shared fragments for a barcode pair to be considered.
+    min_jaccard
+        Minimum Jaccard index of the fragment sets for a barcode pair to be called
+        a multiplet.
+    max_barcodes_per_fragment
+        Fragments shared by more than this number of barcodes, e.g., PCR hot spots,
+        are not used.
+    inplace
+        Whether update the AnnData object inplace
+    n_jobs
+        Number of jobs to run in parallel when `adata` is a list.
+
+    Returns
+    -------
+    tuple[np.ndarray, np.ndarray, pd.DataFrame] | None:
+        if ``inplace = True``, it updates adata with the following fields:
+            - ``adata.obs["multiplet_group"]``: the representative barcode of the
+              multiplet group. Barcodes that are not multiplets are their own representatives.
+              Merging barcodes by this column merges the multiplets.
+            - ``adata.obs["is_multiplet"]``: whether the barcode is a multiplet and
+              not the representative of its group, i.e., barcodes to be excluded.
+            - ``adata.uns["barcode_multiplets"]``: a dataframe of multiplet barcode pairs,
+              with the number of shared fragments and the Jaccard index.
+        Otherwise, these values are returned.
+
+    See Also
+    --------
+    amulet
+    """
+    if isinstance(adata, list):
+        result = anndata_par(
+            adata,
+            lambda x: detect_multiplets(x, min_shared, min_jaccard,
+                                        max_barcodes_per_fragment, inplace),
+            n_jobs=n_jobs,
+        )
+        if inplace:
+            return None
+        else:
+            return result
+
+    groups, pairs = internal.detect_multiplets(
+        adata, min_shared, min_jaccard, max_barcodes_per_fragment,
+    )
+    barcodes = np.array(adata.obs_names)
+    representative = np.array([i if g is None else g for i, g in enumerate(groups)])
+    multiplet_group = barcodes[representative]
+    is_multiplet = representative != np.arange(len(groups))
+    pairs = pd.DataFrame({
+        "barcode1": barcodes[[x[0] for x in pairs]],
+        "barcode2": barcodes[[x[1] for x in pairs]],
+        "num_shared": np.array([x[2] for x in pairs], dtype=np.uint64),
+        "jaccard": np.array([x[3] for x in pairs], dtype=np.float64),
+    })
+    if inplace:
+        adata.obs["multiplet_group"] = multiplet_group
+        adata.obs["is_multiplet"] = is_multiplet
+        adata.uns["barcode_multiplets"] = pairs
+    else:
+        return multiplet_group, is_multiplet, pairs
@@ -1,6 +1,7 @@
 """ Implementation of the scrublet algorithm for single-cell ATAC-seq data
 """
 from __future__ import annotations
+from typing_extensions import Literal
 
 import numpy as np
 import scipy.sparse as ss
@@ -23,6 +24,7 @@ def scrublet(
     inplace: bool = True,
     n_jobs: int = 8,
     verbose: bool = True,
+    backend: Literal['python', 'native'] = 'python',
 ) -> None:
     """
     Compute probability of being a doublet using the scrublet algorithm.
@@ -58,7 +60,8 @@ def scrublet(
         cells and simulated doublets. If `None`, this is 
         set to round(0.5 * sqrt(n_cells))
     use_approx_neighbors
-        Whether to use approximate search.
+        Whether to use approximate search. This has no effect when `backend="native"`,
+        which always uses approximate search.
     random_state
         Random state.
     inplace
@@ -67,6 +70,13 @@ def scrublet(
         Number of jobs to run in parallel.
     verbose
         Whether to print progress messages.
+    backend
+        If "native", doublet simulation, embedding and scoring are performed in Rust.
+        Simulated doublets are generated on the fly rather than stored, and `.X` is
+        read in chunks, so this is considerably faster and uses less memory than the
+        "python" backend for large datasets.
+        Note that the native backend computes the spectral embedding by randomized
+        subspace iteration, so its scores differ slightly from those of the "python" backend.
     
     Returns
     -------
@@ -81,7 +91,7 @@ def scrublet(
             lambda x: scrublet(x, features, n_comps, sim_doublet_ratio,
                                expected_doublet_rate, n_neighbors,
                                use_approx_neighbors, random_state,
-                               inplace, n_jobs, verbose=False),
+                               inplace, n_jobs, verbose=False, backend=backend),
             n_jobs=n_jobs,
         )
         if inplace:
@@ -89,29 +99,41 @@ def scrublet(
         else:
             return result
 
+    if backend not in ('python', 'native'):
+        raise ValueError("backend must be 'python' or 'native'")
+
     if isinstance(features, str):
         if features in adata.var:
             features = adata.var[features]
         else:
             raise NameError("Please call `select_features` first or explicitly set `features = None`")
 
-    if features is None:
-        count_matrix = adata.X[:]
+    if backend == 'native':
+        if features is not None: features = np.asarray(features)
+        doublet_scores_obs, doublet_scores_sim = internal.scrublet(
+            adata, features, n_comps, sim_doublet_ratio, expected_doublet_rate,
+            n_neighbors, random_state,
+        )
+        doublet_scores_obs = np.array(doublet_scores_obs)
+        doublet_scores_sim = np.array(doublet_scores_sim)
     else:
-        count_matrix = adata.X[:, features]
+        if features is None:
+            count_matrix = adata.X[:]
+        else:
+            count_matrix = adata.X[:, features]
 
-    if min(count_matrix.shape) == 0: raise NameError("Matrix is empty")
+        if min(count_matrix.shape) == 0: raise NameError("Matrix is empty")
 
-    if n_neighbors is None:
-        n_neighbors = int(round(0.5 * np.sqrt(count_matrix.shape[0])))
+        if n_neighbors is None:
+            n_neighbors = int(round(0.5 * np.sqrt(count_matrix.shape[0])))
 
-    doublet_scores_obs, doublet_scores_sim, _, _ = scrub_doublets_core(
-        count_matrix, n_neighbors, sim_doublet_ratio, expected_doublet_rate,
-        n_comps=n_comps,
-        use_approx_neighbors = use_approx_neighbors,
-        random_state=random_state,
-        verbose=verbose,
-    )
+        doublet_scores_obs, doublet_scores_sim, _, _ = scrub_doublets_core(
+            count_matrix, n_neighbors, sim_doublet_ratio, expected_doublet_rate,
+            n_comps=n_comps,
+            use_approx_neighbors = use_approx_neighbors,
+            random_state=random_state,
+            verbose=verbose,
+        )
     probs = get_doublet_probability(
         doublet_scores_sim, doublet_scores_obs, random_state,
     )
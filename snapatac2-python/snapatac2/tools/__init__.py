@@ -7,4 +7,5 @@ from ._diff import marker_regions, diff_test
 from ._network import *
 from ._motif import motif_enrichment
 from ._integration import transfer_labels
+from ._cnv import infer_cnv
 from ._misc import *
\ No newline at end of file
@@ -0,0 +1,114 @@
+from __future__ import annotations
+
+from pathlib import Path
+import numpy as np
+import pandas as pd
+import scipy.sparse as ss
+
+import snapatac2._snapatac2 as internal
+
+def infer_cnv(
+    adata: internal.AnnData | internal.AnnDataSet,
+    reference: str | list[str] | np.ndarray,
+    groupby: str | list[str] | None = None,
+    gc_fasta: Path | None = None,
+    centromeres: Path | None = None,
+    window_size: int = 1_000_000,
+    states: list[int] = [1, 2, 3],
+    transition_prob: float = 1e-4,
+    gc_bins: int = 10,
+    chunk_size: int = 2000,
+    inplace: bool = True,
+) -> tuple[pd.DataFrame, ss.csr_matrix, np.ndarray] | None:
+    """
+    Infer copy number variations from the tile matrix.
+
+    Aneuploidy shows up as broad shifts of accessibility along chromosomes.
+    This function aggregates the tile matrix, as produced by
+    :func:`~snapatac2.pp.add_tile_matrix`, into large windows, and compares
+    each profile with the expected profile of a reference group of normal cells.
+    The expected counts are further corrected for GC content if `gc_fasta` is
+    provided. Profiles are then segmented by a hidden Markov model whose states are
+    copy numbers, and the copy number of each chromosome arm is the most
+    frequent state of its windows.
+
+    Parameters
+    ----------
+    adata
+        The (annotated) data matrix of shape `n_obs` x `n_vars`, where `.X`
+        contains the tile matrix.
+    reference
+        The reference group of normal cells. It can be the name of a boolean column
+        in `.obs`, a boolean mask, or a list of barcodes.
+    groupby
+        If provided, cells are grouped, e.g., by clusters, and the summed profiles
+        of groups are segmented. This is more robust for sparse data.
+        Cells inherit the states of their groups.
+        If a `str`, groups are obtained from `.obs[groupby]`.
+        Otherwise, every cell is segmented separately.
+    gc_fasta
+        A FASTA file of the genome used to compute the GC content of windows.
+    centromeres
+        A BED file of centromeric regions used to split chromosomes into arms.
+        If not provided, or for chromosomes without centromeres, states are
+        reported for whole chromosomes.
+    window_size
+        Size of the windows into which bins are aggregated.
+    states
+        Copy numbers of the hidden states. Must contain the neutral state 2.
+    transition_prob
+        Probability of switching to a different state between adjacent windows.
+        Smaller values lead to fewer and longer segments.
+    gc_bins
+        Number of GC strata used for GC correction.
+    chunk_size
+        Number of cells processed at a time.
+    inplace
+        Whether to store the results in the AnnData object.
+
+    Returns
+    -------
+    tuple[pd.DataFrame, ss.csr_matrix, np.ndarray] | None
+        If `inplace = True`, it updates adata with the following fields:
+            - ``adata.obsm["cnv_arm"]``: copy-number states of chromosome arms,
+              whose names are stored in ``adata.uns["cnv_arms"]``.
+            - ``adata.obsm["cnv"]``: differences between the copy-number states and
+              the neutral state in each window. Window names are stored in
+              ``adata.uns["cnv_windows"]``.
+            - ``adata.obs["cnv_score"]``: fraction of windows with altered copy numbers.
+        Otherwise, return the arm-level states as a dataframe, the window-level
+        states and the scores.
+    """
+    if isinstance(reference, str):
+        reference = adata.obs[reference].to_numpy()
+    reference = np.asarray(reference)
+    if reference.dtype != bool:
+        reference = np.isin(np.asarray(adata.obs_names), reference)
+    if reference.size != adata.n_obs:
+        raise NameError("the length of `reference` should equal to the number of obervations")
+
+    if groupby is not None:
+        groups = adata.obs[groupby].to_numpy() if isinstance(groupby, str) else np.array(groupby)
+        if groups.size != adata.n_obs:
+            raise NameError("the length of `groupby` should equal to the number of obervations")
+        _, groups = np.unique(groups, return_inverse=True)
+        groups = [int(x) for x in groups]
+    else:
+        groups = None
+
+    arms, arm_states, windows, window_states, scores = internal.infer_cnv(
+        adata, [bool(x) for x in reference], groups,
+        None if gc_fasta is None else str(gc_fasta),
+        None if centromeres is None else str(centromeres),
+        window_size, list(states), transition_prob, gc_bins, chunk_size,
+    )
+    scores = np.array(scores)
+    if inplace:
+        adata.obsm["cnv_arm"] = arm_states
+        adata.uns["cnv_arms"] = np.array(arms)
+        adata.obsm["cnv"] = window_states
+        adata.uns["cnv_windows"] = np.array(windows)
+        adata.obs["cnv_score"] = scores
+    else:
+        arm_states = pd.DataFrame(arm_states, index=adata.obs_names, columns=arms)
+        return arm_states, window_states, scores
@@ -4,6 +4,7 @@ from typing_extensions import Literal
 import logging
 from pathlib import Path
 import numpy as np
+import scipy.sparse as sp
 import functools
 
 import snapatac2._snapatac2 as internal
@@ -11,7 +12,7 @@ from snapatac2._utils import is_anndata
 from snapatac2.tools import leiden
 from snapatac2.preprocessing import knn
 
-__all__ = ['aggregate_X', 'aggregate_cells']
+__all__ = ['aggregate_X', 'pseudobulk', 'aggregate_cells']
 
 def aggregate_X(
     adata: internal.AnnData | internal.AnnDataSet,
@@ -69,12 +70,24 @@ def aggregate_X(
         if groups.size != adata.n_obs:
             raise NameError("the length of `groupby` should equal to the number of obervations")
 
-        result = {x: np.zeros(adata.n_vars) for x in natsorted(np.unique(groups))}
-        for chunk, start, stop in adata.chunked_X(2000):
-            for i in range(start, stop):
-                result[groups[i]] += chunk[i-start, :]
-        for k in result.keys():
-            result[k] = norm(np.ravel(result[k]))
+        # The native aggregation streams sparse matrices only.
+        first_chunk, _, _ = next(adata.chunked_X(1))
+        if sp.issparse(first_chunk):
+            keys, _, _, _, counts = internal.pseudobulk(
+                adata, [str(x) for x in groups], None, None, True, 0,
+            )
+            rows = dict(zip(keys, range(len(keys))))
+            result = {
+                k: norm(np.ravel(counts[rows[k]].toarray()))
+                for k in natsorted(rows.keys())
+            }
+        else:
+            result = {x: np.zeros(adata.n_vars) for x in natsorted(np.unique(groups))}
+            for chunk, start, stop in adata.chunked_X(2000):
+                for i in range(start, stop):
+                    result[groups[i]] += chunk[i-start, :]
+            for k in result.keys():
+                result[k] = norm(np.ravel(result[k]))
 
         keys, values = zip(*result.items())
         if file is None:
@@ -85,6 +98,86 @@ def aggregate_X(
         out_adata.var_names = adata.var_names
         return out_adata
 
+def pseudobulk(
+    adata: internal.AnnData | internal.AnnDataSet,
+    groupby: str | list[str],
+    replicate: str | list[str] | None = None,
+    selections: list[str] | None = None,
+    use_x: bool = True,
+    bin_size: int = 500,
+    file: Path | None = None,
+) -> AnnData | internal.AnnData:
+    """
+    Sum the counts of cells in each group.
+
+    Pseudobulk profiles are computed by streaming `.X`, or the fragments stored
+    in `.obsm`, once, without loading the whole matrix into memory.
+    When `replicate` is provided, one profile is computed for each pair of group
+    and replicate, which can be used for pseudobulk differential testing.
+
+    Parameters
+    ----------
+    adata
+        The AnnData or AnnDataSet object.
+    groupby
+        Group the cells into different groups. If a `str`, groups are obtained
+        from `.obs[groupby]`.
+    replicate
+        Replicate labels of cells, e.g., samples or donors. If a `str`,
+        replicates are obtained from `.obs[replicate]`.
+    selections
+        Aggregate only the selected groups.
+    use_x
+        If True, sum the values in `.X`, which must be a sparse matrix.
+        Otherwise, count the TN5 insertions of fragments in consecutive bins
+        of size `bin_size`.
+    bin_size
+        The size of genomic bins. Only used when `use_x=False`.
+    file
+        If provided, the results will be saved to a new h5ad file.
+
+    Returns
+    -------
+    AnnData
+        An AnnData object whose rows are groups (or pairs of group and replicate)
+        and columns are features. `.obs['group']`, `.obs['replicate']` and
+        `.obs['n_cells']` store the group labels, the replicate labels and the
+        numbers of cells, respectively.
+
+    See Also
+    --------
+    aggregate_X
+    """
+    from anndata import AnnData
+
+    groups = adata.obs[groupby] if isinstance(groupby, str) else groupby
+    groups = [str(x) for x in groups]
+    if replicate is not None:
+        replicate = adata.obs[replicate] if isinstance(replicate, str) else replicate
+        replicate = [str(x) for x in replicate]
+    if selections is not None:
+        selections = set(selections)
+
+    groups, replicates, num_cells, features, counts = internal.pseudobulk(
+        adata, groups, replicate, selections, use_x, bin_size,
+    )
+    if replicates is None:
+        names = groups
+    else:
+        names = [f"{g}+{r}" for g, r in zip(groups, replicates)]
+
+    if file is None:
+        out = AnnData(X=counts)
+    else:
+        out = internal.AnnData(filename=file, X=counts)
+    out.obs_names = names
+    out.var_names = features
+    out.obs['group'] = groups
+    if replicates is not None:
+        out.obs['replicate'] = replicates
+    out.obs['n_cells'] = np.array(num_cells, dtype=np.uint64)
+    return out
+
 def aggregate_cells(
     adata: internal.AnnData | internal.AnnDataSet | np.ndarray,
     use_rep: str = 'X_spectral',
@@ -0,0 +1,68 @@
+use crate::utils::{AnnDataLike, open_file};
+use snapatac2_core::{
+    cnv::{infer_cnv as infer, CnvOptions},
+    hic::gc_content,
+    preprocessing::{count_data::GenomeBaseIndex, SnapData},
+    utils::open_file_for_read,
+};
+
+use std::ops::Deref;
+use anndata::{ArrayData, Backend};
+use anndata_hdf5::H5;
+use bed_utils::bed::{self, BEDLike};
+use numpy::PyArray2;
+use pyanndata::data::PyArrayData;
+use pyo3::prelude::*;
+use std::{collections::HashMap, io::BufReader, path::PathBuf};
+use anyhow::Result;
+
+#[pyfunction]
+pub(crate) fn infer_cnv<'py>(
+    py: Python<'py>,
+    anndata: AnnDataLike,
+    reference: Vec<bool>,
+    groups: Option<Vec<usize>>,
+    gc_fasta: Option<PathBuf>,
+    centromeres: Option<PathBuf>,
+    window_size: usize,
+    states: Vec<u8>,
+    transition_prob: f64,
+    gc_bins: usize,
+    chunk_size: usize,
+) -> Result<(Vec<String>, &'py PyArray2<u8>, Vec<String>, PyArrayData, Vec<f64>)> {
+    let opts = CnvOptions { window_size, states, transition_prob, gc_bins, chunk_size };
+
+    // Centromere positions are the midpoints of centromeric regions.
+    let mut centromere_pos = HashMap::new();
+    if let Some(file) = centromeres {
+        let mut regions: HashMap<String, (u64, u64)> = HashMap::new();
+        bed::io::Reader::new(open_file(file), None).into_records().try_for_each(|x: Result<bed::BED<3>, _>| {
+            let x = x?;
+            let r = regions.entry(x.chrom().to_string()).or_insert((x.start(), x.end()));
+            *r = (r.0.min(x.start()), r.1.max(x.end()));
+            anyhow::Ok(())
+        })?;
+        centromere_pos = regions.into_iter().map(|(k, (s, e))| (k, (s + e) / 2)).collect();
+    }
+
+    macro_rules! run {
+        ($data:expr) => {{
+            let gc = if let Some(fasta) = gc_fasta.as_ref() {
+                let windows = GenomeBaseIndex::new(&$data.read_chrom_sizes()?).with_step(window_size);
+                Some(gc_content(BufReader::new(open_file_for_read(fasta)?), &windows)?)
+            } else {
+                None
+            };
+            infer($data, &reference, groups.as_deref(), gc.as_deref(), &centromere_pos, &opts)
+        }};
+    }
+    let res = crate::with_anndata!(&anndata, run)?;
+    let windows = (0..res.windows.len()).map(|i| res.windows.get_region(i).pretty_show()).collect();
+    Ok((
+        res.arms,
+        PyArray2::from_owned_array(py, res.arm_states),
+        windows,
+        PyArrayData::from(ArrayData::from(res.window_states)),
+        res.scores,
+    ))
+}
@@ -1,12 +1,16 @@
 use crate::utils::AnnDataLike;
-use snapatac2_core::export::Exporter;
+use snapatac2_core::{
+    export::{Exporter, CountSource}, preprocessing::SnapData, utils::open_file_for_read,
+    hic::{BalanceMethod, LoopOptions, coverage_track, gc_content},
+};
 
 use std::ops::Deref;
-use anndata::Backend;
+use anndata::{ArrayData, Backend};
 use anndata_hdf5::H5;
 use pyo3::prelude::*;
-use std::{collections::{HashSet, HashMap}, path::PathBuf};
-use anyhow::Result;
+use pyanndata::data::PyArrayData;
+use std::{collections::{HashSet, HashMap}, io::BufReader, path::PathBuf};
+use anyhow::{bail, Result};
 
 #[pyfunction]
 pub fn export_fragments(
@@ -19,11 +23,13 @@ pub fn export_fragments(
     selections: Option<HashSet<&str>>,
     compression: Option<&str>,
     compression_level: Option<u32>,
+    fragment_size_range: Option<(u64, u64)>,
 ) -> Result<HashMap<String, PathBuf>> {
     macro_rules! run {
         ($data:expr) => {
             $data.export_fragments(
-                Some(&barcodes), &group_by, selections, dir, prefix, suffix, compression, compression_level
+                Some(&barcodes), &group_by, selections, dir, prefix, suffix, compression, compression_level,
+                fragment_size_range,
             )
         }
     }
@@ -39,11 +45,149 @@ pub fn export_bigwig(
     dir: PathBuf,
     prefix: &str,
     suffix: &str,
+    fragment_size_range: Option<(u64, u64)>,
 ) -> Result<HashMap<String, PathBuf>> {
     macro_rules! run {
         ($data:expr) => {
-            $data.export_bigwig(&group_by, selections, resolution, dir, prefix, suffix)
+            $data.export_bigwig(&group_by, selections, resolution, dir, prefix, suffix, fragment_size_range)
         }
     }
     crate::with_anndata!(&anndata, run)
-}
\ No newline at end of file
+}
+
+#[pyfunction]
+pub fn pseudobulk(
+    anndata: AnnDataLike,
+    group_by: Vec<&str>,
+    replicates: Option<Vec<&str>>,
+    selections: Option<HashSet<&str>>,
+    use_x: bool,
+    resolution: usize,
+) -> Result<(Vec<String>, Option<Vec<String>>, Vec<usize>, Vec<String>, PyArrayData)> {
+    let source = if use_x { CountSource::X } else { CountSource::Fragments(resolution) };
+    macro_rules! run {
+        ($data:expr) => {
+            $data.get_counts(&group_by, replicates.as_ref(), selections, source)
+        }
+    }
+    let res = crate::with_anndata!(&anndata, run)?;
+    Ok((
+        res.groups,
+        res.replicates,
+        res.num_cells,
+        res.features,
+        PyArrayData::from(ArrayData::from(res.counts)),
+    ))
+}
+
+#[pyfunction]
+pub fn export_cool(
+    anndata: AnnDataLike,
+    group_by: Vec<&str>,
+    selections: Option<HashSet<&str>>,
+    resolutions: Vec<usize>,
+    balance: Option<&str>,
+    dir: PathBuf,
+    prefix: &str,
+    suffix: &str,
+) -> Result<HashMap<String, PathBuf>> {
+    let balance = parse_balance_method(balance)?;
+    macro_rules! run {
+        ($data:expr) => {
+            $data.export_cool(&group_by, selections, &resolutions, balance, dir, prefix, suffix)
+        }
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+#[pyfunction]
+pub fn export_compartments(
+    anndata: AnnDataLike,
+    group_by: Vec<&str>,
+    selections: Option<HashSet<&str>>,
+    resolution: usize,
+    balance: Option<&str>,
+    gc_fasta: Option<PathBuf>,
+    atac: Option<AnnDataLike>,
+    dir: PathBuf,
+    prefix: &str,
+    suffix: &str,
+) -> Result<HashMap<String, PathBuf>> {
+    let balance = parse_balance_method(balance)?;
+    macro_rules! get_index {
+        ($data:expr) => {
+            $data.contact_count_iter(500)?.with_resolution(resolution).get_gindex()
+        }
+    }
+    let index = crate::with_anndata!(&anndata, get_index);
+
+    let track = if let Some(fasta) = gc_fasta {
+        Some(gc_content(BufReader::new(open_file_for_read(fasta)?), &index)?)
+    } else if let Some(atac) = atac {
+        macro_rules! get_track {
+            ($data:expr) => {
+                coverage_track($data.get_count_iter(500)?, &index)
+            }
+        }
+        Some(crate::with_anndata!(&atac, get_track))
+    } else {
+        None
+    };
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.export_compartments(
+                &group_by, selections, resolution, balance, track.as_deref(), dir, prefix, suffix,
+            )
+        }
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+#[pyfunction]
+pub fn export_loops(
+    anndata: AnnDataLike,
+    group_by: Vec<&str>,
+    selections: Option<HashSet<&str>>,
+    resolution: usize,
+    balance: Option<&str>,
+    peak_width: usize,
+    window_width: usize,
+    min_distance: u64,
+    max_distance: u64,
+    fdr: f64,
+    min_enrichment: f64,
+    min_enrichment_hv: f64,
+    cluster_radius: usize,
+    dir: PathBuf,
+    prefix: &str,
+    suffix: &str,
+) -> Result<HashMap<String, PathBuf>> {
+    let balance = parse_balance_method(balance)?;
+    let opts = LoopOptions {
+        peak_width,
+        window_width,
+        min_distance,
+        max_distance,
+        fdr,
+        min_enrichment,
+        min_enrichment_hv,
+        cluster_radius,
+        ..LoopOptions::default()
+    };
+    macro_rules! run {
+        ($data:expr) => {
+            $data.export_loops(&group_by, selections, resolution, balance, &opts, dir, prefix, suffix)
+        }
+    }
+    crate::with_anndata!(&anndata, run)
+}
+
+fn parse_balance_method(balance: Option<&str>) -> Result<Option<BalanceMethod>> {
+    match balance {
+        None => Ok(None),
+        Some("ice") => Ok(Some(BalanceMethod::ICE)),
+        Some("kr") => Ok(Some(BalanceMethod::KR)),
+        Some(x) => bail!("unknown balancing method: {}", x),
+    }
+}
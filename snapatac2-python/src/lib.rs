@@ -6,6 +6,7 @@ mod embedding;
 mod network;
 mod motif;
 mod knn;
+mod cnv;
 
 use pyo3::{prelude::*, PyResult, Python};
 use pyanndata;
@@ -42,15 +43,28 @@ fn _snapatac2(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(preprocessing::import_fragments, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::import_contacts, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::mk_tile_matrix, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::mk_tile_matrices, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::mk_partition_matrix, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::impute_contacts, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::normalize, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::mk_gene_matrix, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::mk_peak_matrix, m)?)?;
 
     m.add_function(wrap_pyfunction!(preprocessing::tss_enrichment, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::add_frip, m)?)?;
     m.add_function(wrap_pyfunction!(preprocessing::fragment_size_distribution, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::fragment_size_qc, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::contact_qc, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::amulet, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::scrublet, m)?)?;
+    m.add_function(wrap_pyfunction!(preprocessing::detect_multiplets, m)?)?;
 
     m.add_function(wrap_pyfunction!(export::export_fragments, m)?)?;
     m.add_function(wrap_pyfunction!(export::export_bigwig, m)?)?;
+    m.add_function(wrap_pyfunction!(export::pseudobulk, m)?)?;
+    m.add_function(wrap_pyfunction!(export::export_cool, m)?)?;
+    m.add_function(wrap_pyfunction!(export::export_compartments, m)?)?;
+    m.add_function(wrap_pyfunction!(export::export_loops, m)?)?;
 
     m.add_function(wrap_pyfunction!(call_peaks::export_tags, m)?)?;
     m.add_function(wrap_pyfunction!(call_peaks::create_fwtrack_obj, m)?)?;
@@ -63,6 +77,8 @@ fn _snapatac2(_py: Python, m: &PyModule) -> PyResult<()> {
 
     m.add_function(wrap_pyfunction!(network::link_region_to_gene, m)?)?;
 
+    m.add_function(wrap_pyfunction!(cnv::infer_cnv, m)?)?;
+
     m.add_function(wrap_pyfunction!(utils::jaccard_similarity, m)?)?;
     m.add_function(wrap_pyfunction!(utils::cosine_similarity, m)?)?;
     m.add_function(wrap_pyfunction!(utils::pearson, m)?)?;
@@ -3,16 +3,19 @@ use crate::utils::*;
 use anndata::Backend;
 use anndata_hdf5::H5;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
-use std::{str::FromStr, collections::BTreeMap, ops::Deref, collections::HashSet};
+use std::path::{Path, PathBuf};
+use std::{str::FromStr, collections::BTreeMap, ops::Deref, collections::HashSet, sync::{Arc, Mutex}};
 use pyo3::prelude::*;
-use bed_utils::{bed, bed::GenomicRange};
-use pyanndata::PyAnnData;
-use anyhow::Result;
+use bed_utils::{bed, bed::{BEDLike, GenomicRange}};
+use anndata::{AnnDataOp, ArrayData};
+use pyanndata::{AnnData, PyAnnData, data::PyArrayData};
+use anyhow::{bail, Context, Result};
 
 use snapatac2_core::{
-    preprocessing::{Fragment, Contact, FlagStat, SnapData},
+    preprocessing::{Fragment, Contact, FlagStat, SnapData, PairsReader, TssProfileMode, CountingStrategy,
+        Normalization, TfIdfMethod, TfIdfOptions, qc, doublet, multiplet},
     preprocessing,
+    hic::{impute_contacts, ImputeOptions},
 };
 
 #[pyclass]
@@ -138,16 +141,48 @@ fn shift_fragment(fragment: &mut Fragment, shift_left: i64, shift_right: i64) {
 pub(crate) fn import_contacts(
     anndata: AnnDataLike,
     contact_file: PathBuf,
-    chrom_size: BTreeMap<&str, u64>,
+    chrom_size: Option<BTreeMap<&str, u64>>,
+    max_distance: Option<u64>,
     fragment_is_sorted_by_name: bool,
     chunk_size: usize,
     tempdir: Option<PathBuf>,
 ) -> Result<()>
 {
-    let chrom_sizes = chrom_size.into_iter().map(|(chr, s)| GenomicRange::new(chr, 0, s)).collect();
+    let is_pairs = is_pairs_file(&contact_file)?;
+    let (chrom_sizes, contacts): (Vec<(String, u64)>, Box<dyn Iterator<Item = Result<Contact>>>) = if is_pairs {
+        let reader = PairsReader::new(BufReader::new(open_file(&contact_file)), None)?;
+        let chrom_sizes = match chrom_size {
+            Some(x) => x.into_iter().map(|(chr, s)| (chr.to_string(), s)).collect(),
+            None => reader.header().chrom_sizes.clone().into_iter().collect(),
+        };
+        (chrom_sizes, Box::new(reader.into_contacts(100000)))
+    } else {
+        let chrom_sizes = match chrom_size {
+            Some(x) => x.into_iter().map(|(chr, s)| (chr.to_string(), s)).collect(),
+            None => bail!("chromosome sizes must be provided"),
+        };
+        let contacts = BufReader::new(open_file(&contact_file)).lines().map(|x| {
+            let x = x.context("failed to read the contact file")?;
+            Contact::from_str(&x).with_context(|| format!("invalid record in contact file: {}", x))
+        });
+        (chrom_sizes, Box::new(contacts))
+    };
+    if chrom_sizes.is_empty() {
+        bail!("chromosome sizes must be provided, as the .pairs file has no '#chromsize' headers");
+    }
+    let chrom_sizes: bed::tree::GenomeRegions<GenomicRange> = chrom_sizes.into_iter()
+        .map(|(chr, s)| GenomicRange::new(chr, 0, s)).collect();
 
-    let contacts = BufReader::new(open_file(&contact_file)).lines()
-        .map(|x| Contact::from_str(&x.unwrap()).unwrap());
+    // Stop at the first invalid record and report it once the contacts are consumed.
+    let error = Arc::new(Mutex::new(None));
+    let error_ = error.clone();
+    let contacts = contacts.map_while(move |x| match x {
+        Ok(x) => Some(x),
+        Err(e) => {
+            *error_.lock().unwrap() = Some(e);
+            None
+        },
+    });
     let sorted_contacts: Box<dyn Iterator<Item = Contact>> = if !fragment_is_sorted_by_name {
         let tmp = if let Some(dir) = tempdir {
             tempfile::Builder::new().tempdir_in(dir)
@@ -163,26 +198,55 @@ pub(crate) fn import_contacts(
     } else {
         Box::new(contacts)
     };
+    if let Some(e) = error.lock().unwrap().take() {
+        return Err(e);
+    }
 
     macro_rules! run {
         ($data:expr) => {
-            preprocessing::import_contacts($data, sorted_contacts, &chrom_sizes, chunk_size)?
+            preprocessing::import_contacts($data, sorted_contacts, &chrom_sizes, max_distance, chunk_size)?
         };
     }
 
     crate::with_anndata!(&anndata, run);
+    if let Some(e) = error.lock().unwrap().take() {
+        return Err(e);
+    }
     Ok(())
 } 
 
 
 
+/// Detect `.pairs` files by their suffix or by the `## pairs format` header line.
+fn is_pairs_file(file: &Path) -> Result<bool> {
+    let name = file.file_name().and_then(|x| x.to_str()).unwrap_or_default();
+    if name.ends_with(".pairs") || name.ends_with(".pairs.gz") {
+        return Ok(true);
+    }
+    let mut line = String::new();
+    BufReader::new(open_file(file)).read_line(&mut line)?;
+    Ok(line.starts_with("## pairs format"))
+}
+
+fn counting_strategy(x: &str) -> Result<CountingStrategy> {
+    match x {
+        "insertion" => Ok(CountingStrategy::Insertion),
+        "fragment" => Ok(CountingStrategy::Fragment),
+        "paired-insertion" => Ok(CountingStrategy::PairedInsertion),
+        _ => bail!("counting_strategy must be 'insertion', 'fragment' or 'paired-insertion'"),
+    }
+}
+
 #[pyfunction]
 pub(crate) fn mk_tile_matrix(
     anndata: AnnDataLike, bin_size: usize, chunk_size: usize, 
+    strategy: &str,
+    fragment_size_range: Option<(u64, u64)>,
     exclude_chroms: Option<Vec<&str>>,
     out: Option<AnnDataLike>
 ) -> Result<()>
 {
+    let strategy = counting_strategy(strategy)?;
     macro_rules! run {
         ($data:expr) => {
             if let Some(out) = out {
@@ -192,6 +256,8 @@ pub(crate) fn mk_tile_matrix(
                             $data,
                             bin_size,
                             chunk_size,
+                            strategy,
+                            fragment_size_range,
                             exclude_chroms.as_ref().map(|x| x.as_slice()),
                             Some($out_data)
                         )?
@@ -203,6 +269,8 @@ pub(crate) fn mk_tile_matrix(
                     $data,
                     bin_size,
                     chunk_size,
+                    strategy,
+                    fragment_size_range,
                     exclude_chroms.as_ref().map(|x| x.as_slice()),
                     None::<&PyAnnData>
                 )?;
@@ -214,15 +282,169 @@ pub(crate) fn mk_tile_matrix(
     Ok(())
 }
 
+#[pyfunction]
+pub(crate) fn mk_tile_matrices(
+    anndata: AnnDataLike, bin_sizes: Vec<usize>, chunk_size: usize,
+    strategy: &str,
+    fragment_size_range: Option<(u64, u64)>,
+    exclude_chroms: Option<Vec<&str>>,
+    outs: Vec<AnnData>,
+) -> Result<()>
+{
+    let strategy = counting_strategy(strategy)?;
+    let outs = outs.iter().map(|x| match x.backend().as_str() {
+        H5::NAME => Ok(x.inner_ref::<H5>()),
+        x => bail!("Unsupported backend: {}", x),
+    }).collect::<Result<Vec<_>>>()?;
+    let outs: Vec<_> = outs.iter().map(|x| x.deref()).collect();
+    macro_rules! run {
+        ($data:expr) => {
+            preprocessing::create_tile_matrices(
+                $data,
+                &bin_sizes,
+                chunk_size,
+                strategy,
+                fragment_size_range,
+                exclude_chroms.as_ref().map(|x| x.as_slice()),
+                &outs,
+            )?
+        };
+    }
+
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
+
+#[pyfunction]
+pub(crate) fn mk_partition_matrix(
+    anndata: AnnDataLike,
+    bin_file: PathBuf,
+    chunk_size: usize,
+    strategy: &str,
+    fragment_size_range: Option<(u64, u64)>,
+    out: Option<AnnDataLike>,
+) -> Result<()>
+{
+    let strategy = counting_strategy(strategy)?;
+    let bins = bed::io::Reader::new(open_file(bin_file), None).into_records()
+        .map(|x: Result<bed::BED<3>, _>| x.map(|x| GenomicRange::new(x.chrom(), x.start(), x.end())))
+        .collect::<Result<Vec<_>, _>>()?;
+    macro_rules! run {
+        ($data:expr) => {
+            if let Some(out) = out {
+                macro_rules! run2 {
+                    ($out_data:expr) => {
+                        preprocessing::create_partition_matrix(
+                            $data, bins, chunk_size, strategy, fragment_size_range, Some($out_data)
+                        )?
+                    };
+                }
+                crate::with_anndata!(&out, run2);
+            } else {
+                preprocessing::create_partition_matrix(
+                    $data, bins, chunk_size, strategy, fragment_size_range, None::<&PyAnnData>
+                )?;
+            }
+        };
+    }
+
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
+
+#[pyfunction]
+pub(crate) fn impute_contacts(
+    anndata: AnnDataLike,
+    resolution: usize,
+    pad: usize,
+    restart_prob: f64,
+    max_distance: Option<u64>,
+    top_percent: Option<f64>,
+    key: Option<&str>,
+    chunk_size: usize,
+    out: Option<AnnDataLike>,
+) -> Result<()>
+{
+    let opts = ImputeOptions { pad, restart_prob, max_distance, top_percent, ..Default::default() };
+    macro_rules! run {
+        ($data:expr) => {
+            if let Some(out) = out {
+                macro_rules! run2 {
+                    ($out_data:expr) => {
+                        impute_contacts($data, resolution, &opts, key, chunk_size, Some($out_data))?
+                    };
+                }
+                crate::with_anndata!(&out, run2);
+            } else {
+                impute_contacts($data, resolution, &opts, key, chunk_size, None::<&PyAnnData>)?;
+            }
+        };
+    }
+
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
+
+#[pyfunction]
+pub(crate) fn normalize(
+    anndata: AnnDataLike,
+    method: &str,
+    tfidf_method: &str,
+    scale_factor: f64,
+    binarize: bool,
+    log1p: bool,
+    chunk_size: usize,
+    layer: Option<&str>,
+    out: Option<AnnDataLike>,
+) -> Result<()>
+{
+    let method = match method {
+        "tfidf" => {
+            let method = match tfidf_method {
+                "log_tfidf" => TfIdfMethod::LogTfIdf,
+                "tf_logidf" => TfIdfMethod::TfLogIdf,
+                "logtf_logidf" => TfIdfMethod::LogTfLogIdf,
+                "idf" => TfIdfMethod::Idf,
+                _ => bail!("tfidf_method must be 'log_tfidf', 'tf_logidf', 'logtf_logidf' or 'idf'"),
+            };
+            Normalization::TfIdf(TfIdfOptions { method, scale_factor, binarize })
+        },
+        "cpm" => Normalization::Cpm { scale_factor, log1p },
+        "log1p" => Normalization::Log1p,
+        "binarize" => Normalization::Binarize,
+        _ => bail!("method must be 'tfidf', 'cpm', 'log1p' or 'binarize'"),
+    };
+    macro_rules! run {
+        ($data:expr) => {
+            if let Some(out) = out {
+                macro_rules! run2 {
+                    ($out_data:expr) => {
+                        preprocessing::normalize($data, &method, chunk_size, layer, Some($out_data))?
+                    };
+                }
+                crate::with_anndata!(&out, run2);
+            } else {
+                preprocessing::normalize($data, &method, chunk_size, layer, None::<&PyAnnData>)?;
+            }
+        };
+    }
+
+    crate::with_anndata!(&anndata, run);
+    Ok(())
+}
+
 #[pyfunction]
 pub(crate) fn mk_peak_matrix(
     anndata: AnnDataLike,
     peaks: &PyAny,
     chunk_size: usize,
     use_x: bool,
+    strategy: &str,
+    fragment_size_range: Option<(u64, u64)>,
     out: Option<AnnDataLike>,
 ) -> Result<()>
 {
+    let strategy = counting_strategy(strategy)?;
     let peaks = peaks.iter()?
         .map(|x| GenomicRange::from_str(x.unwrap().extract().unwrap()).unwrap());
 
@@ -231,12 +453,12 @@ pub(crate) fn mk_peak_matrix(
             if let Some(out) = out {
                 macro_rules! run2 {
                     ($out_data:expr) => {
-                        preprocessing::create_peak_matrix($data, peaks, chunk_size, Some($out_data), use_x)?
+                        preprocessing::create_peak_matrix($data, peaks, chunk_size, strategy, fragment_size_range, Some($out_data), use_x)?
                     };
                 }
                 crate::with_anndata!(&out, run2);
             } else {
-                preprocessing::create_peak_matrix($data, peaks, chunk_size, None::<&PyAnnData>, use_x)?;
+                preprocessing::create_peak_matrix($data, peaks, chunk_size, strategy, fragment_size_range, None::<&PyAnnData>, use_x)?;
             }
         }
     }
@@ -251,21 +473,47 @@ pub(crate) fn mk_gene_matrix(
     chunk_size: usize,
     use_x: bool,
     id_type: &str,
+    method: &str,
+    upstream: u64,
+    downstream: u64,
+    include_gene_body: bool,
+    decay: f64,
+    offset: f64,
+    extend_upstream: (u64, u64),
+    extend_downstream: (u64, u64),
+    use_gene_boundaries: bool,
+    gene_scale_factor: Option<f64>,
+    strategy: &str,
+    fragment_size_range: Option<(u64, u64)>,
     out: Option<AnnDataLike>,
 ) -> Result<()>
 {
+    let strategy = counting_strategy(strategy)?;
     let transcripts = read_transcripts(gff_file);
+    let quantification = match method {
+        "count" => preprocessing::GeneQuantification::Count { upstream, downstream, include_gene_body },
+        "score" => preprocessing::GeneQuantification::Score(preprocessing::GeneScoreOptions {
+            decay,
+            offset,
+            upstream: extend_upstream,
+            downstream: extend_downstream,
+            use_gene_boundaries,
+            include_gene_body,
+            gene_scale_factor,
+        }),
+        _ => bail!("method must be 'count' or 'score'"),
+    };
     macro_rules! run {
         ($data:expr) => {
             if let Some(out) = out {
                 macro_rules! run2 {
                     ($out_data:expr) => {
-                        preprocessing::create_gene_matrix($data, transcripts, id_type, chunk_size, Some($out_data), use_x)?
+                        preprocessing::create_gene_matrix($data, transcripts, id_type, quantification, chunk_size, strategy, fragment_size_range, Some($out_data), use_x)?
                     };
                 }
                 crate::with_anndata!(&out, run2);
             } else {
-                preprocessing::create_gene_matrix($data, transcripts, id_type, chunk_size, None::<&PyAnnData>, use_x)?;
+                preprocessing::create_gene_matrix($data, transcripts, id_type, quantification, chunk_size, strategy, fragment_size_range, None::<&PyAnnData>, use_x)?;
             }
         }
     }
@@ -273,22 +521,120 @@ pub(crate) fn mk_gene_matrix(
     Ok(())
 }
 
+#[pyfunction]
+pub(crate) fn amulet(
+    anndata: AnnDataLike,
+    blacklist: Option<PathBuf>,
+    exclude_chroms: Vec<String>,
+    max_overlap: usize,
+) -> Result<(Vec<u64>, Vec<f64>, Vec<f64>)>
+{
+    let blacklist: Option<bed::tree::BedTree<()>> = blacklist.map(|f|
+        bed::io::Reader::new(open_file(f), None).into_records()
+            .map(|x: Result<bed::BED<3>, _>| Ok((x?, ())))
+            .collect::<Result<_>>()
+    ).transpose()?;
+    let opts = doublet::AmuletOptions {
+        max_overlap,
+        exclude_chroms: exclude_chroms.into_iter().collect(),
+    };
+
+    macro_rules! run {
+        ($data:expr) => {
+            doublet::amulet($data.get_count_iter(2000)?, blacklist.as_ref(), &opts)
+        }
+    }
+    let res = crate::with_anndata!(&anndata, run)?;
+    Ok((res.num_overlaps, res.pvalue, res.qvalue))
+}
+
+#[pyfunction]
+pub(crate) fn scrublet(
+    anndata: AnnDataLike,
+    features: &PyAny,
+    n_comps: usize,
+    sim_doublet_ratio: f64,
+    expected_doublet_rate: f64,
+    n_neighbors: Option<usize>,
+    random_state: u64,
+) -> Result<(Vec<f64>, Vec<f64>)>
+{
+    let opts = doublet::ScrubletOptions {
+        n_comps,
+        sim_doublet_ratio,
+        expected_doublet_rate,
+        n_neighbors,
+        random_state,
+        ..Default::default()
+    };
+
+    macro_rules! run {
+        ($data:expr) => {{
+            let slice = pyanndata::data::to_select_elem(features, $data.n_vars())?;
+            doublet::scrublet(&doublet::AnnDataRows::new($data, slice), &opts)
+        }}
+    }
+    let res = crate::with_anndata!(&anndata, run)?;
+    Ok((res.scores, res.sim_scores))
+}
+
+#[pyfunction]
+pub(crate) fn detect_multiplets(
+    anndata: AnnDataLike,
+    min_shared: usize,
+    min_jaccard: f64,
+    max_barcodes_per_fragment: usize,
+) -> Result<(Vec<Option<usize>>, Vec<(usize, usize, usize, f64)>)>
+{
+    let opts = multiplet::MultipletOptions { min_shared, min_jaccard, max_barcodes_per_fragment };
+
+    macro_rules! run {
+        ($data:expr) => {
+            anyhow::Ok(multiplet::detect_multiplets($data.get_count_iter(2000)?, &opts))
+        }
+    }
+    let res = crate::with_anndata!(&anndata, run)?;
+    let pairs = res.pairs.into_iter().map(|x| (x.barcode1, x.barcode2, x.num_shared, x.jaccard)).collect();
+    Ok((res.groups, pairs))
+}
+
 /// QC metrics
 
 #[pyfunction]
 pub(crate) fn tss_enrichment(
     anndata: AnnDataLike,
     gtf_file: PathBuf,
-) -> Result<Vec<f64>>
+    method: &str,
+    window: u64,
+    flank: u64,
+    smooth: usize,
+    pseudocount: f64,
+    profile: bool,
+    group_by: Option<Vec<&str>>,
+) -> Result<(Vec<f64>, Option<PyArrayData>, Option<Vec<String>>)>
 {
-    let promoters = preprocessing::make_promoter_map(preprocessing::read_tss(open_file(gtf_file)));
+    let method = match method {
+        "encode" => qc::TssEnrichmentMethod::Encode,
+        "signac" => qc::TssEnrichmentMethod::Signac,
+        x => bail!("unknown TSS enrichment method: {}", x),
+    };
+    let opts = qc::TssEnrichmentOptions { method, window, flank, smooth, pseudocount };
+    let promoters = preprocessing::make_promoter_map(preprocessing::read_tss(open_file(gtf_file)), window);
+    let mode = if !profile {
+        None
+    } else if let Some(group_by) = group_by.as_ref() {
+        Some(TssProfileMode::Group(group_by))
+    } else {
+        Some(TssProfileMode::Cell)
+    };
 
     macro_rules! run {
         ($data:expr) => {
-            $data.tss_enrichment(&promoters)
+            $data.tss_enrichment(&promoters, &opts, mode)
         }
     }
-    crate::with_anndata!(&anndata, run)
+    let res = crate::with_anndata!(&anndata, run)?;
+    Ok((res.scores, res.profile.map(|x| PyArrayData::from(ArrayData::from(x))), res.groups))
 }
 
 #[pyfunction]
@@ -315,6 +661,63 @@ pub(crate) fn add_frip(
     )
 }
 
+#[pyfunction]
+pub(crate) fn contact_qc(
+    anndata: AnnDataLike,
+    mitochondrial_dna: Vec<String>,
+    short_range: u64,
+    long_range: u64,
+    min_distance: u64,
+    max_distance: u64,
+    bins_per_decade: usize,
+) -> Result<(BTreeMap<String, Vec<f64>>, Vec<Vec<f64>>, Vec<u64>)>
+{
+    let mito_dna: HashSet<String> = mitochondrial_dna.into_iter().collect();
+    let bins = qc::log_distance_bins(min_distance, max_distance, bins_per_decade);
+
+    macro_rules! run {
+        ($data:expr) => {
+            $data.contact_qc(&mito_dna, short_range, long_range, &bins)
+        }
+    }
+
+    let res = crate::with_anndata!(&anndata, run)?;
+    let mut columns = BTreeMap::new();
+    columns.insert("n_contact".to_string(), res.iter().map(|x| x.num_contacts as f64).collect());
+    columns.insert("n_cis".to_string(), res.iter().map(|x| x.num_cis as f64).collect());
+    columns.insert("n_trans".to_string(), res.iter().map(|x| x.num_trans as f64).collect());
+    columns.insert(
+        "cis_trans_ratio".to_string(),
+        res.iter().map(|x| x.cis_trans_ratio()).collect(),
+    );
+    columns.insert("frac_mito".to_string(), res.iter().map(|x| x.frac_mitochondrial).collect());
+    columns.insert("frac_short_cis".to_string(), res.iter().map(|x| x.frac_short_cis).collect());
+    columns.insert("frac_long_cis".to_string(), res.iter().map(|x| x.frac_long_cis).collect());
+    let decay = res.into_iter().map(|x| x.decay).collect();
+    Ok((columns, decay, bins))
+}
+
+#[pyfunction]
+pub(crate) fn fragment_size_qc(
+    anndata: AnnDataLike,
+    max_recorded_size: usize,
+    bin_size: usize,
+) -> Result<(Vec<f64>, Vec<f64>, PyArrayData)>
+{
+    macro_rules! run {
+        ($data:expr) => {
+            $data.fragment_size_qc(max_recorded_size, bin_size)
+        }
+    }
+
+    let (qc, histograms) = crate::with_anndata!(&anndata, run)?;
+    Ok((
+        qc.iter().map(|x| x.nucleosome_signal).collect(),
+        qc.iter().map(|x| x.periodicity).collect(),
+        PyArrayData::from(ArrayData::from(histograms)),
+    ))
+}
+
 #[pyfunction]
 pub(crate) fn fragment_size_distribution(
     anndata: AnnDataLike,
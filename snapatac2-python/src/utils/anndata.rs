@@ -184,6 +184,7 @@ impl<'py> SnapData for PyAnnData<'py> {
     {
         Ok(ContactMap::new(
             self.read_chrom_sizes()?,
+            self.read_contact_layout()?,
             self.obsm().get_item_iter("contact", chunk_size).expect("'contact' not found in obsm"),
         ))
     }